@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Every deserializer must turn arbitrary bytes into a value or a TeleportError, never a panic
+fuzz_target!(|data: &[u8]| {
+    let _ = teleporter::parse_any(data);
+});
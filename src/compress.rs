@@ -0,0 +1,133 @@
+use crate::errors::TeleportError;
+use std::io::Read;
+
+/// Chunk payload is sent as-is
+const RAW: u8 = 0x00;
+/// Chunk payload is a zstd frame
+const ZSTD: u8 = 0x01;
+
+/// Number of chunks sampled before deciding whether compression is worthwhile
+pub const SAMPLE_CHUNKS: usize = 4;
+/// Compressed/raw size ratio above which compression is disabled
+pub const MAX_RATIO: f64 = 0.9;
+
+const LEVEL: i32 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compressor {
+    pub enabled: bool,
+    pub sample_chunks: usize,
+    pub max_ratio: f64,
+    chunks: usize,
+    raw: u64,
+    packed: u64,
+}
+
+impl Compressor {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            sample_chunks: SAMPLE_CHUNKS,
+            max_ratio: MAX_RATIO,
+            chunks: 0,
+            raw: 0,
+            packed: 0,
+        }
+    }
+
+    /// Wrap a chunk of file data, compressing it while compression is still paying off
+    pub fn pack(&mut self, input: &[u8]) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::with_capacity(input.len() + 1);
+
+        if self.enabled {
+            let packed = zstd::bulk::compress(input, LEVEL)?;
+            self.update(input.len(), packed.len());
+
+            if packed.len() < input.len() {
+                out.push(ZSTD);
+                out.extend_from_slice(&packed);
+                return Ok(out);
+            }
+        }
+
+        out.push(RAW);
+        out.extend_from_slice(input);
+
+        Ok(out)
+    }
+
+    fn update(&mut self, raw: usize, packed: usize) {
+        self.chunks += 1;
+        self.raw += raw as u64;
+        self.packed += packed as u64;
+
+        // Stop compressing data that does not shrink, e.g. already-compressed files
+        if self.chunks >= self.sample_chunks
+            && self.raw > 0
+            && self.packed as f64 / self.raw as f64 > self.max_ratio
+        {
+            self.enabled = false;
+        }
+    }
+}
+
+/// Unwrap a chunk of file data, refusing to decompress more than `limit` bytes
+pub fn unpack(input: &[u8], limit: u64) -> Result<Vec<u8>, TeleportError> {
+    let (kind, data) = match input.split_first() {
+        Some(s) => s,
+        None => return Err(TeleportError::InvalidCompression),
+    };
+
+    let out = match *kind {
+        RAW => data.to_vec(),
+        ZSTD => {
+            // Read one byte past the limit so oversized chunks are detected
+            let mut out = Vec::<u8>::new();
+            let decoder = zstd::stream::read::Decoder::new(data)?;
+            decoder.take(limit + 1).read_to_end(&mut out)?;
+            out
+        }
+        _ => return Err(TeleportError::InvalidCompression),
+    };
+
+    if out.len() as u64 > limit {
+        return Err(TeleportError::InvalidCompression);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let data = vec![7u8; 4096];
+        let mut c = Compressor::new(true);
+        let packed = c.pack(&data).expect("Test should never fail");
+        assert_eq!(packed[0], ZSTD);
+        assert!(packed.len() < data.len());
+
+        let out = unpack(&packed, data.len() as u64).expect("Test should never fail");
+        assert_eq!(out, data);
+        assert!(unpack(&packed, data.len() as u64 - 1).is_err());
+    }
+
+    #[test]
+    fn test_compress_disables_on_random_data() {
+        let mut rng = StdRng::from_entropy();
+        let mut data = vec![0u8; 4096];
+        let mut c = Compressor::new(true);
+
+        for _ in 0..SAMPLE_CHUNKS {
+            rng.fill(&mut data[..]);
+            let packed = c.pack(&data).expect("Test should never fail");
+            assert_eq!(packed[0], RAW);
+            assert_eq!(&packed[1..], &data[..]);
+        }
+
+        assert!(!c.enabled);
+    }
+}
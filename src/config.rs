@@ -0,0 +1,201 @@
+use crate::errors::TeleportError;
+use crate::ListenOpt;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, Parser};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// The config file listen defaults are read from: `explicit`, or the default one if it exists
+pub fn listen_config(explicit: Option<&Path>) -> Option<PathBuf> {
+    if let Some(p) = explicit {
+        return Some(p.to_path_buf());
+    }
+
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(d) => PathBuf::from(d),
+        None if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("teleporter").join("listen.toml")).filter(|p| p.is_file())
+}
+
+/// Arguments for every option in the listen config file not already given by a flag or variable
+pub fn listen_args(opt: &ListenOpt, matches: &ArgMatches) -> Result<Vec<String>, TeleportError> {
+    let path = match listen_config(opt.config()) {
+        Some(p) => p,
+        None => return Ok(Vec::new()),
+    };
+    let given = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let text = fs::read_to_string(&path)?;
+    parse_listen(&text, given)
+        .and_then(validate)
+        .map_err(|e| TeleportError::Config(format!("{}: {e}", path.display())))
+}
+
+/// Parse the file's values exactly like flags, so bad values are blamed on the file
+fn validate(args: Vec<String>) -> Result<Vec<String>, String> {
+    let argv = std::iter::once("listen").chain(args.iter().map(|a| a.as_str()));
+    match ListenOpt::try_parse_from(argv) {
+        Ok(_) => Ok(args),
+        Err(e) => {
+            let msg = e.to_string();
+            let line = msg.lines().next().unwrap_or_default();
+            Err(line.trim_start_matches("error: ").to_string())
+        }
+    }
+}
+
+/// Turn a TOML table into `listen` arguments, skipping the options `given` on the command line
+fn parse_listen(text: &str, given: impl Fn(&str) -> bool) -> Result<Vec<String>, String> {
+    let table = match text.parse::<Value>().map_err(|e| e.to_string())? {
+        Value::Table(t) => t,
+        _ => return Err("expected a table of options".to_string()),
+    };
+
+    // Keys are the long option names, with dashes or underscores
+    let cmd = ListenOpt::command();
+    let known: Vec<&str> = cmd
+        .get_arguments()
+        .filter_map(|a| a.get_long())
+        .filter(|l| *l != "config" && *l != "help")
+        .collect();
+    let unknown: Vec<&str> = table
+        .keys()
+        .map(|k| k.as_str())
+        .filter(|k| !known.contains(&k.replace('_', "-").as_str()))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("unknown keys: {}", unknown.join(", ")));
+    }
+
+    let mut args = Vec::new();
+    for (key, value) in &table {
+        if given(&key.replace('-', "_")) {
+            continue;
+        }
+
+        let flag = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            Value::Array(a) => a.iter().collect(),
+            v => vec![v],
+        };
+        for v in values {
+            match v {
+                Value::Boolean(true) => args.push(flag.clone()),
+                Value::Boolean(false) => (),
+                Value::String(s) => args.push(format!("{flag}={s}")),
+                Value::Integer(i) => args.push(format!("{flag}={i}")),
+                _ => return Err(format!("unsupported value for {key}")),
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        port = 9100
+        output_dir = "/srv/incoming"
+        allowed-users = ["alice", "bob"]
+        max_bandwidth = "10M"
+        allow_plaintext = false
+        keep_partial = true
+    "#;
+
+    fn parse(args: &[String]) -> ListenOpt {
+        ListenOpt::parse_from(std::iter::once("listen").chain(args.iter().map(|a| a.as_str())))
+    }
+
+    #[test]
+    fn test_parse_listen() {
+        let args = parse_listen(CONFIG, |_| false).expect("Test should never fail");
+        let flags = ListenOpt::parse_from([
+            "listen",
+            "-p",
+            "9100",
+            "-o",
+            "/srv/incoming",
+            "-a",
+            "alice",
+            "bob",
+            "--max-bandwidth",
+            "10M",
+            "--keep-partial",
+        ]);
+        assert_eq!(parse(&args), flags);
+    }
+
+    #[test]
+    fn test_listen_args_flags_win() {
+        let dir = env::temp_dir().join(format!("teleporter-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let path = dir.join("listen.toml");
+        fs::write(&path, CONFIG).expect("Test should never fail");
+
+        let argv: Vec<String> = [
+            "listen",
+            "-p",
+            "9200",
+            "--allowed-users",
+            "carol",
+            "--config",
+            path.to_str().expect("Test should never fail"),
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        let matches = ListenOpt::command().get_matches_from(&argv);
+        let opt = ListenOpt::parse_from(&argv);
+        let mut args = listen_args(&opt, &matches).expect("Test should never fail");
+        args.extend(argv[1..].iter().cloned());
+
+        let flags = ListenOpt::parse_from([
+            "listen",
+            "-p",
+            "9200",
+            "-o",
+            "/srv/incoming",
+            "-a",
+            "carol",
+            "--max-bandwidth",
+            "10M",
+            "--keep-partial",
+            "--config",
+            path.to_str().expect("Test should never fail"),
+        ]);
+        assert_eq!(parse(&args), flags);
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_parse_listen_errors() {
+        let e = parse_listen("port = 9100\nprot = 1\nbogus = true", |_| false)
+            .expect_err("Test should fail");
+        assert_eq!(e, "unknown keys: bogus, prot");
+        assert!(parse_listen("config = \"other.toml\"", |_| false).is_err());
+        assert!(parse_listen("port = 1.5", |_| false).is_err());
+        assert!(parse_listen("port = ", |_| false).is_err());
+
+        let args = parse_listen("port = \"none\"", |_| false).expect("Test should never fail");
+        assert!(validate(args).is_err());
+    }
+
+    #[test]
+    fn test_missing_config() {
+        let argv = ["listen", "--config", "/nonexistent/teleporter.toml"];
+        let matches = ListenOpt::command().get_matches_from(argv);
+        assert!(listen_args(&ListenOpt::parse_from(argv), &matches).is_err());
+    }
+}
@@ -1,36 +1,204 @@
 use crate::errors::TeleportError;
 use crate::teleport::TeleportEnc;
-use aes_gcm::aead::Aead;
+use aes_gcm::aead::{Aead, Payload};
 use aes_gcm::{Aes256Gcm, KeyInit};
 use generic_array::GenericArray;
+use hkdf::Hkdf;
 use rand::rngs::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
 
-pub fn genkey(ctx: &mut TeleportEnc) -> EphemeralSecret {
-    let secret = EphemeralSecret::new(OsRng);
+/// Generate an ephemeral keypair from the OS RNG and store its public key in `ctx`
+pub fn genkey(ctx: &mut TeleportEnc) -> Result<StaticSecret, TeleportError> {
+    genkey_from(ctx, &mut OsRng)
+}
+
+/// Generate an ephemeral keypair from `rng`, failing instead of panicking if it has no bytes
+pub fn genkey_from<R: RngCore + CryptoRng>(
+    ctx: &mut TeleportEnc,
+    rng: &mut R,
+) -> Result<StaticSecret, TeleportError> {
+    let secret = gensecret_from(rng)?;
     ctx.public = PublicKey::from(&secret).to_bytes();
 
-    secret
+    Ok(secret)
+}
+
+/// Generate a secret key from the OS RNG, e.g. the server identity key
+pub fn gensecret() -> Result<StaticSecret, TeleportError> {
+    gensecret_from(&mut OsRng)
+}
+
+/// Generate a secret key from `rng`
+pub fn gensecret_from<R: RngCore + CryptoRng>(rng: &mut R) -> Result<StaticSecret, TeleportError> {
+    let mut bytes = [0; 32];
+    random_bytes_from(rng, &mut bytes)?;
+
+    Ok(StaticSecret::from(bytes))
 }
 
-pub fn decrypt(key: &[u8; 32], nonce: Vec<u8>, data: Vec<u8>) -> Result<Vec<u8>, TeleportError> {
+/// Fill `buf` from the OS RNG
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), TeleportError> {
+    random_bytes_from(&mut OsRng, buf)
+}
+
+/// Fill `buf` from `rng`, failing instead of panicking if it has no bytes
+pub fn random_bytes_from<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    buf: &mut [u8],
+) -> Result<(), TeleportError> {
+    rng.try_fill_bytes(buf)
+        .map_err(|_| TeleportError::EncryptionFailure)
+}
+
+/// Fixed HKDF info string binding derived keys to this protocol
+const KDF_INFO: &[u8] = b"teleporter aes-256-gcm key";
+
+/// Derive the symmetric encryption key from the raw ECDH shared secret(s)
+pub fn derive_key(salt: Option<&[u8]>, shared: &[u8]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(salt, shared);
+    let mut key: [u8; 32] = [0; 32];
+    hk.expand(KDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    key
+}
+
+/// Bytes of the SHA-256 digest shown in a key fingerprint
+const FINGERPRINT_LEN: usize = 15;
+
+/// Short human-readable fingerprint of a public key, base32 of its SHA-256 in groups of 4
+pub fn fingerprint(public: &[u8; 32]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let digest = Sha256::digest(public);
+
+    let mut out = String::new();
+    let mut bits: u32 = 0;
+    let mut len = 0;
+    for byte in &digest[..FINGERPRINT_LEN] {
+        bits = (bits << 8) | *byte as u32;
+        len += 8;
+        while len >= 5 {
+            len -= 5;
+            if !out.is_empty() && out.len() % 5 == 4 {
+                out.push('-');
+            }
+            out.push(ALPHABET[(bits >> len) as usize & 0x1f] as char);
+        }
+    }
+
+    out
+}
+
+/// Compare a fingerprint given by the user, ignoring case and grouping
+pub fn fingerprint_matches(fingerprint: &str, pin: &str) -> bool {
+    let normalize = |s: &str| -> String {
+        s.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect()
+    };
+
+    ct_eq(normalize(fingerprint).as_bytes(), normalize(pin).as_bytes())
+}
+
+/// Length of the AES-GCM authentication tag appended to every ciphertext
+pub const TAG_LEN: usize = 16;
+
+pub fn decrypt(
+    key: &[u8; 32],
+    nonce: Vec<u8>,
+    aad: &[u8],
+    data: Vec<u8>,
+) -> Result<Vec<u8>, TeleportError> {
     let key = GenericArray::from_slice(key);
     let cipher = Aes256Gcm::new(key);
     let gen_nonce = GenericArray::from_slice(&nonce);
+    let payload = Payload {
+        msg: data.as_ref(),
+        aad,
+    };
 
-    let plaintext = cipher
-        .decrypt(gen_nonce, data.as_ref())
-        .expect("Decrypt failed");
-
-    Ok(plaintext.to_vec())
+    match cipher.decrypt(gen_nonce, payload) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(TeleportError::DecryptionFailure),
+    }
 }
 
-pub fn encrypt(key: &[u8; 32], nonce: Vec<u8>, input: Vec<u8>) -> Result<Vec<u8>, TeleportError> {
+pub fn encrypt(
+    key: &[u8; 32],
+    nonce: Vec<u8>,
+    aad: &[u8],
+    input: Vec<u8>,
+) -> Result<Vec<u8>, TeleportError> {
     let key = GenericArray::from_slice(key);
     let cipher = Aes256Gcm::new(key);
     let gen_nonce = GenericArray::from_slice(&nonce);
-    match cipher.encrypt(gen_nonce, input.as_ref()) {
+    let payload = Payload {
+        msg: input.as_ref(),
+        aad,
+    };
+
+    match cipher.encrypt(gen_nonce, payload) {
         Ok(s) => Ok(s),
         Err(_) => Err(TeleportError::EncryptionFailure),
     }
 }
+
+/// Curve25519 u-coordinates of low-order points (and their non-canonical encodings below 2^255)
+pub const LOW_ORDER: [[u8; 32]; 7] = [
+    [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
+/// Check whether a peer public key would force a predictable shared secret
+pub fn is_low_order(key: &[u8; 32]) -> bool {
+    // The high bit is ignored by x25519, so compare without it
+    let mut masked = *key;
+    masked[31] &= 0x7f;
+
+    LOW_ORDER.iter().any(|p| ct_eq(p, &masked))
+}
+
+/// Compare two byte slices in constant time
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -3,6 +3,7 @@ use std::num::{ParseIntError, TryFromIntError};
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
+use crate::teleport::TeleportStatus;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -25,9 +26,19 @@ pub enum TeleportError {
     #[error("Error with destination address")]
     InvalidDest,
 
+    #[error("Could not connect to any address of the destination: {0}")]
+    ConnectFailed(String),
+
     #[error("Invalid Protocol header received")]
     InvalidProtocol,
 
+    #[error(
+        "Unsupported wire protocol version {0} (this build speaks {} to {})",
+        crate::MIN_PROTOCOL_VERSION,
+        crate::PROTOCOL_VERSION
+    )]
+    UnsupportedProtocol(u8),
+
     #[error("Invalid file name")]
     InvalidFileName,
 
@@ -40,6 +51,9 @@ pub enum TeleportError {
     #[error("Data is not the expected length")]
     InvalidLength,
 
+    #[error("Chunk data does not match its checksum")]
+    ChunkChecksum,
+
     #[error("Not enough data for public key")]
     InvalidPubKey,
 
@@ -52,6 +66,45 @@ pub enum TeleportError {
     #[error("Encryption failed")]
     EncryptionFailure,
 
+    #[error("Decryption failed: packet could not be authenticated")]
+    DecryptionFailure,
+
+    #[error("Nonce counter exhausted for this session")]
+    NonceExhausted,
+
+    #[error("Cannot decompress chunk data")]
+    InvalidCompression,
+
+    #[error("The server refused the transfer: {0}")]
+    Refused(TeleportStatus),
+
+    #[error("No file was received")]
+    NoTransfer,
+
+    #[error("The file written to stdout is incomplete")]
+    StdoutIncomplete,
+
+    #[error("The server does not support strong hashing")]
+    StrongHashUnsupported,
+
+    #[error("The server does not accept data of unknown size")]
+    UnknownSizeUnsupported,
+
+    #[error("The server key fingerprint does not match the pinned fingerprint")]
+    KeyMismatch,
+
+    #[error("No files match the pattern: {0}")]
+    NoMatch(String),
+
+    #[error("--passphrase cannot be combined with --plaintext")]
+    PassphrasePlaintext,
+
+    #[error("Invalid config file {0}")]
+    Config(String),
+
+    #[error("Invalid glob pattern: {0}")]
+    InvalidPattern(#[from] glob::PatternError),
+
     // added by lee
     #[error("Invalid user name")]
     InvalidUserName,
@@ -0,0 +1,37 @@
+use log::debug;
+use serde_json::Value;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Print events as one JSON object per line on stdout
+pub fn enable_json() {
+    JSON.store(true, Ordering::SeqCst);
+}
+
+/// True once JSON events were enabled
+pub fn json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// Print `fields` tagged with the `event` name, if JSON events are enabled
+pub fn emit(event: &str, mut fields: Value) {
+    if !json() {
+        return;
+    }
+
+    if let Value::Object(map) = &mut fields {
+        map.insert("event".to_string(), Value::from(event));
+    }
+
+    // Write the whole line at once so events from several threads never interleave
+    let mut stdout = io::stdout().lock();
+    if writeln!(stdout, "{fields}")
+        .and_then(|_| stdout.flush())
+        .is_err()
+    {
+        debug!("Could not print {event} event");
+    }
+}
@@ -1,52 +1,158 @@
 use clap::Parser;
-use std::path::PathBuf;
+use manifest::Manifest;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+pub mod config;
 pub mod errors;
+pub mod events;
+pub mod list;
 pub mod listen;
+pub mod ping;
+pub mod progress;
 pub mod scan;
 pub mod send;
 
+mod compress;
 mod crypto;
+mod manifest;
 mod teleport;
 mod utils;
+mod xattr;
 
-pub const PROTOCOL: u64 = 0x54524f50454c4554;
+pub use teleport::{parse_any, Frame, TeleportStatus};
+
+pub const PROTOCOL: u64 = 0x33545250454c4554;
+/// Wire format version sent after the protocol magic in every packet
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Oldest wire format version this build can still parse
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Statistics about a completed file transfer
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferStats {
+    /// Name of the file on the server
+    pub filename: String,
+    /// Size of the file in bytes
+    pub bytes: u64,
+    /// Time taken to transfer the file
+    pub duration: Duration,
+    /// Transfer speed in megabits per second
+    pub mbps: f64,
+    /// Hash of the whole file, as verified by the server
+    pub hash: u64,
+    /// The server already had an identical file, so no file data was sent
+    pub skipped: bool,
+    /// Only the chunks that differ from the server's copy were sent
+    pub used_delta: bool,
+    /// The transfer was encrypted
+    pub used_encryption: bool,
+}
+
+/// Totals since a server started, updated as connections are handled
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Files received and verified
+    pub files: u64,
+    /// Size of the files received in bytes, including data the server already had
+    pub bytes: u64,
+    /// Accepted transfers that did not complete, e.g. dropped connections or hash mismatches
+    pub failed: u64,
+    /// How often each status other than Proceed or Pong was sent to a client
+    pub refused: BTreeMap<TeleportStatus, u64>,
+}
+
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files ({}) received, {} failed",
+            self.files,
+            utils::format_size(self.bytes),
+            self.failed
+        )?;
+        for (status, count) in &self.refused {
+            write!(f, ", {count} {status:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
 pub struct SendOpt {
-    /// List of filepaths to files that will be teleported
+    /// List of filepaths to files that will be teleported, glob patterns such as "*.log" are expanded
     #[arg(short, long, num_args = ..)]
     input: Vec<PathBuf>,
 
+    /// Read the file data from stdin instead of --input, sending it to the server as --name
+    #[arg(long, requires = "name", conflicts_with_all = ["input", "resume", "compress", "verify_only", "dry_run"])]
+    stdin: bool,
+
+    /// Name to give the data read from --stdin on the server
+    #[arg(long, requires = "stdin")]
+    name: Option<String>,
+
     /// Destination teleporter host
     #[arg(short, long, default_value = "localhost")]
     dest: String,
 
     /// Destination teleporter port
-    #[arg(short, long, default_value = "9001")]
+    #[arg(short, long, env = "TELEPORT_PORT", default_value = "9001")]
     port: u16,
 
+    /// Only connect to IPv4 addresses of the destination
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only connect to IPv6 addresses of the destination
+    #[arg(short = '6', long)]
+    ipv6: bool,
+
     /// Overwrite remote file
     #[arg(short, long)]
     overwrite: bool,
 
-    /// Recurse into directories on send
+    /// Recurse into directories on send, keeping each file's path below the directory
     #[arg(short, long)]
     recursive: bool,
 
-    /// Encrypt the file transfer using ECDH key-exchange and random keys
-    #[arg(short, long)]
+    /// Follow symlinks to files and directories instead of skipping them
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Skip files and directories found by --recursive whose relative path or name matches this glob pattern, may be repeated
+    #[arg(long, value_parser = parse_pattern)]
+    exclude: Vec<glob::Pattern>,
+
+    /// Send without encryption, only accepted by servers started with --allow-plaintext
+    #[arg(long)]
+    plaintext: bool,
+
+    /// Deprecated, transfers are encrypted unless --plaintext is given
+    #[arg(short, long, hide = true)]
     encrypt: bool,
 
+    /// Checksum each chunk of a --plaintext transfer so the server can detect corruption before writing it
+    #[arg(long, requires = "plaintext")]
+    chunk_checksums: bool,
+
     /// Disable delta transfer (overwrite will transfer entire file)
     #[arg(short, long)]
     no_delta: bool,
 
     /// Keep path info (recreate directory path on remote server)
-    #[arg(short, long)]
+    #[arg(short, long, visible_alias = "relative")]
     keep_path: bool,
 
+    /// Send only the file name, even for files found by --recursive (files with the same name collide on the server)
+    #[arg(long, conflicts_with = "keep_path")]
+    flat: bool,
+
     /// Backup the destination file to a ".bak" extension if it exists and is being overwritten (consecutive runs will replace the *.bak file)
     #[arg(short, long)]
     backup: bool,
@@ -55,8 +161,104 @@ pub struct SendOpt {
     #[arg(short, long)]
     filename_append: bool,
 
+    /// Compress file data with zstd (disabled automatically if the file does not shrink)
+    #[arg(short, long)]
+    compress: bool,
+
+    /// Resume an interrupted transfer from the partial file left on the remote server
+    #[arg(long)]
+    resume: bool,
+
+    /// Give received files the same uid and gid they have here, if the server is privileged to (unix only)
+    #[arg(long)]
+    preserve_owner: bool,
+
+    /// Send the extended attributes of each file for the server to reapply (Linux and macOS only)
+    #[arg(long)]
+    preserve_xattrs: bool,
+
+    /// Username to identify as on the remote server
     #[arg(short, long)]
     username: String,
+
+    /// Shared secret token to authenticate the username with the server
+    #[arg(short, long)]
+    token: Option<String>,
+
+    /// Bytes of file data per chunk when not sending a delta [default: 4096]
+    #[arg(long, value_parser = parse_chunk_size)]
+    chunk_size: Option<usize>,
+
+    /// Limit upload bandwidth in bytes/sec, e.g. 512K or 10M
+    #[arg(long = "limit", value_parser = utils::parse_size)]
+    rate_limit: Option<u64>,
+
+    /// Hash with BLAKE3 instead of xxh3, so delta matching cannot be fooled by a malicious peer
+    #[arg(long)]
+    strong_hash: bool,
+
+    /// Write the hash and remote path of each file sent to this file, as `hash  path` lines (BLAKE3 with --strong-hash)
+    #[arg(long, conflicts_with_all = ["stdin", "verify_only", "dry_run"])]
+    checksum_manifest: Option<PathBuf>,
+
+    /// Send all files over a single connection when the server supports it
+    #[arg(long)]
+    batch: bool,
+
+    /// Send each file over this many parallel connections when the server supports it
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..=64))]
+    streams: u16,
+
+    /// Retry this many times if connecting or sending fails, resuming the file if --resume is given
+    #[arg(long, default_value = "0")]
+    retries: u32,
+
+    /// Seconds to wait before the first retry, doubling after every failed attempt
+    #[arg(long, default_value = "1", value_parser = parse_seconds)]
+    retry_delay: Duration,
+
+    /// Abort unless the server's key fingerprint matches this one
+    #[arg(long)]
+    pin: Option<String>,
+
+    /// Pre-shared passphrase mixed into the encryption key, must match the server's
+    #[arg(long, env = "TELEPORT_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// Leave Nagle's algorithm on, batching small packets at the cost of handshake latency
+    #[arg(long)]
+    nagle: bool,
+
+    /// Ping the server after this many seconds without sending file data, so NAT and firewalls keep the connection open
+    #[arg(long, default_value = "30", value_parser = parse_seconds)]
+    keepalive: Duration,
+
+    /// Only report how many chunks of each file differ from the server's copy, without sending data
+    #[arg(long, conflicts_with = "no_delta")]
+    verify_only: bool,
+
+    /// Print the files that would be sent and their sizes without sending any data, asking the server which chunks differ if it is reachable
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Smallest and largest accepted --chunk-size values
+const MIN_CHUNK_SIZE: usize = 512;
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+fn parse_pattern(s: &str) -> Result<glob::Pattern, String> {
+    glob::Pattern::new(s).map_err(|e| format!("{e}"))
+}
+
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    let size: usize = s.parse().map_err(|e| format!("{e}"))?;
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+        return Err(format!(
+            "chunk size must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes"
+        ));
+    }
+
+    Ok(size)
 }
 
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
@@ -65,13 +267,149 @@ pub struct ListenOpt {
     #[arg(long)]
     allow_dangerous_filepath: bool,
 
-    /// Require encryption for incoming connections to the server
-    #[arg(short, long)]
+    /// Accept unencrypted connections, e.g. from constrained devices [WARNING: filenames and data are sent in the clear]
+    #[arg(long)]
+    allow_plaintext: bool,
+
+    /// Deprecated, encryption is required unless --allow-plaintext is given
+    #[arg(short, long, hide = true)]
     must_encrypt: bool,
 
     /// Port to listen on
-    #[arg(short, long, default_value = "9001")]
+    #[arg(short, long, env = "TELEPORT_PORT", default_value = "9001")]
     port: u16,
+
+    /// Directory received files are written below
+    #[arg(short, long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Address to listen on [default: all interfaces]
+    #[arg(short, long, env = "TELEPORT_BIND")]
+    bind: Option<IpAddr>,
+
+    /// Only accept transfers from these usernames
+    #[arg(short, long, num_args = 1..)]
+    allowed_users: Option<Vec<String>>,
+
+    /// Shared secret token clients must send along with their username
+    #[arg(short, long)]
+    token: Option<String>,
+
+    /// Keep the partial output of aborted transfers instead of deleting it
+    #[arg(long)]
+    keep_partial: bool,
+
+    /// Write through symlinks found at the destination path instead of refusing the file
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Close connections that stall for this many seconds
+    #[arg(long, value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Maximum number of connections handled at once, extra clients are told the server is busy
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Allow clients to list the files below the directory the server writes to
+    #[arg(long)]
+    allow_listing: bool,
+
+    /// Refuse files that would leave less than this much free disk space, e.g. 512M or 2G
+    #[arg(long, default_value = "64M", value_parser = utils::parse_size)]
+    min_free: u64,
+
+    /// Refuse any single file larger than this, e.g. 100M or 4G
+    #[arg(long, value_parser = utils::parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Keep the server identity key in this file, so its fingerprint survives restarts
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// Pre-shared passphrase clients must mix into the encryption key, plaintext clients are refused
+    #[arg(long, env = "TELEPORT_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// Leave Nagle's algorithm on, batching small packets at the cost of handshake latency
+    #[arg(long)]
+    nagle: bool,
+
+    /// Number of backups kept when clients overwrite with --backup, older ones are renamed to ".bak.1", ".bak.2", ...
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    backup_count: u16,
+
+    /// Write the data of a single received file to stdout instead of the output directory, then exit
+    #[arg(long)]
+    to_stdout: bool,
+
+    /// Log the files, bytes and refusals received since start every this many seconds
+    #[arg(long, value_parser = parse_seconds)]
+    stats_interval: Option<Duration>,
+
+    /// Limit the download bandwidth of each connection in bytes/sec, e.g. 512K or 10M
+    #[arg(long, value_parser = utils::parse_size)]
+    max_bandwidth: Option<u64>,
+
+    /// Limit the combined download bandwidth of all connections in bytes/sec, e.g. 512K or 10M
+    #[arg(long, value_parser = utils::parse_size)]
+    max_total_bandwidth: Option<u64>,
+
+    /// Only log warnings, errors and received files, e.g. when running under a service manager
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Number of chunks the delta of an overwritten file aims for, more finds smaller changes but costs a larger handshake
+    #[arg(long, default_value = "2048", value_parser = clap::value_parser!(u16).range(1..))]
+    delta_chunks: u16,
+
+    /// Use chunks of exactly this many bytes for deltas instead, e.g. for reproducible deltas
+    #[arg(long, value_parser = parse_chunk_size, conflicts_with = "delta_chunks")]
+    delta_chunk_size: Option<usize>,
+
+    /// Permission bits cleared from the mode clients send, setuid, setgid and sticky bits are always cleared
+    #[arg(long, default_value = "022", value_parser = parse_umask)]
+    umask: u32,
+
+    /// Check each received file listed in this manifest of `hash  path` lines against its hash
+    #[arg(long, value_parser = manifest::load)]
+    verify_manifest: Option<Manifest>,
+
+    /// Read default options from this TOML file [default: ~/.config/teleporter/listen.toml if it exists]
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+impl ListenOpt {
+    /// True if received data is written to stdout, so log messages must go elsewhere
+    pub fn to_stdout(&self) -> bool {
+        self.to_stdout
+    }
+
+    /// The config file given with --config
+    pub fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+}
+
+/// Parse an octal permission mask like 022 or 0o077
+fn parse_umask(s: &str) -> Result<u32, String> {
+    let digits = s.trim_start_matches("0o");
+    let mask = u32::from_str_radix(digits, 8).map_err(|_| format!("invalid octal mask: {s}"))?;
+    if mask > 0o777 {
+        return Err(format!("mask {s} has bits beyond 0777"));
+    }
+
+    Ok(mask)
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs: u64 = s.parse().map_err(|e| format!("{e}"))?;
+    if secs == 0 {
+        return Err("must be at least 1 second".to_string());
+    }
+
+    Ok(Duration::from_secs(secs))
 }
 
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
@@ -80,3 +418,48 @@ pub struct ScanOpt {
     #[arg(short, long, default_value = "9001")]
     port: u16,
 }
+
+#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+pub struct PingOpt {
+    /// Teleporter host to ping
+    #[arg(short, long, default_value = "localhost")]
+    dest: String,
+
+    /// Teleporter port to ping
+    #[arg(short, long, default_value = "9001")]
+    port: u16,
+}
+
+#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+pub struct ListOpt {
+    /// Directory on the server to list [default: the directory the server writes to]
+    dir: Option<String>,
+
+    /// Teleporter host to query
+    #[arg(short, long, default_value = "localhost")]
+    dest: String,
+
+    /// Teleporter port to query
+    #[arg(short, long, env = "TELEPORT_PORT", default_value = "9001")]
+    port: u16,
+
+    /// Query without encryption, only accepted by servers started with --allow-plaintext
+    #[arg(long)]
+    plaintext: bool,
+
+    /// Username to identify as on the remote server
+    #[arg(short, long)]
+    username: String,
+
+    /// Shared secret token to authenticate the username with the server
+    #[arg(short, long)]
+    token: Option<String>,
+
+    /// Abort unless the server's key fingerprint matches this one
+    #[arg(long)]
+    pin: Option<String>,
+
+    /// Pre-shared passphrase mixed into the encryption key, must match the server's
+    #[arg(long, env = "TELEPORT_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+}
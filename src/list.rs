@@ -0,0 +1,88 @@
+use crate::errors::TeleportError;
+use crate::send;
+use crate::teleport::{TeleportAction, TeleportFeatures, TeleportStatus};
+use crate::teleport::{TeleportInit, TeleportInitAck, TeleportList};
+use crate::utils;
+use crate::{ListOpt, PROTOCOL_VERSION};
+use clap::parser::ValueSource;
+use log::{error, info};
+use std::net::TcpStream;
+
+/// Ask a teleporter server which files it already has
+pub fn run(opt: ListOpt) -> Result<(), TeleportError> {
+    run_with_passphrase_source(opt, Some(ValueSource::CommandLine))
+}
+
+/// Like `run`, querying in plaintext with --plaintext even if the environment sets a passphrase
+pub fn run_with_passphrase_source(
+    mut opt: ListOpt,
+    source: Option<ValueSource>,
+) -> Result<(), TeleportError> {
+    if opt.plaintext && opt.passphrase.is_some() {
+        match source {
+            Some(ValueSource::CommandLine) => return Err(TeleportError::PassphrasePlaintext),
+            _ => opt.passphrase = None,
+        }
+    }
+    let mut stream = TcpStream::connect((opt.dest.as_str(), opt.port))?;
+
+    let mut version = PROTOCOL_VERSION;
+    let enc = match opt.plaintext {
+        true => None,
+        false => Some(send::key_exchange(
+            &mut stream,
+            &mut version,
+            opt.pin.as_deref(),
+            opt.passphrase.as_deref(),
+        )?),
+    };
+
+    // The request reuses the TeleportInit header, with the directory as the filename
+    let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+    header.filename = opt.dir.unwrap_or_default().into_bytes();
+    header.username = opt.username.into_bytes();
+    if let Some(token) = opt.token {
+        header.token = token.into_bytes();
+    }
+    utils::send_packet(
+        &mut stream,
+        TeleportAction::List,
+        version,
+        &enc,
+        header.serialize()?,
+    )?;
+
+    // Requests refused before the listing are answered with a TeleportInitAck
+    let packet = utils::recv_packet(&mut stream, version, &enc)?;
+    let status: TeleportStatus = match TeleportAction::try_from(packet.action)? {
+        TeleportAction::ListAck => {
+            let mut list = TeleportList::new(TeleportStatus::Proceed);
+            list.deserialize(&packet.data)?;
+            if list.status == TeleportStatus::Proceed as u8 {
+                for entry in &list.entries {
+                    info!(
+                        "{:>12} {}",
+                        entry.filesize,
+                        String::from_utf8_lossy(&entry.filename)
+                    );
+                }
+                info!("{} files", list.entries.len());
+                return Ok(());
+            }
+            list.status.try_into()?
+        }
+        _ => {
+            let mut ack = TeleportInitAck::default();
+            ack.deserialize(&packet.data)?;
+            ack.status.try_into()?
+        }
+    };
+
+    match status {
+        TeleportStatus::NoPermission => error!("The server does not allow listing files"),
+        TeleportStatus::BadFileName => error!("The directory does not exist on the server"),
+        s => error!("The server responded: {s}"),
+    }
+
+    Err(TeleportError::Refused(status))
+}
@@ -1,334 +1,4009 @@
 use crate::errors::TeleportError;
+use crate::manifest::Manifest;
+use crate::progress::ProgressReporter;
+use crate::teleport::{FileType, HashAlgorithm, TeleportHeader, TeleportList, TeleportListEntry};
 use crate::teleport::{TeleportAction, TeleportEnc, TeleportFeatures, TeleportStatus};
 use crate::teleport::{TeleportData, TeleportDelta, TeleportInit, TeleportInitAck};
-use crate::ListenOpt;
-use crate::VERSION;
-use crate::{crypto, utils};
+use crate::teleport::{DELTA_CHUNKS, STREAM_ID_LEN};
+use crate::utils::TokenBucket;
+use crate::{compress, crypto, events, manifest, utils, xattr};
+use crate::{ListenOpt, ServerStats, TransferStats};
+use crate::{PROTOCOL_VERSION, VERSION};
+use filetime::FileTime;
+use is_terminal::IsTerminal;
+use log::{debug, error, info, warn};
 use semver::Version;
+use serde_json::json;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use x25519_dalek::StaticSecret;
+
+/// How often the listener checks for new connections and the shutdown flag
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+/// How long shutdown waits for active transfers to clean up
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(5);
+/// How often the idle status line is redrawn on a terminal
+const HEARTBEAT: Duration = Duration::from_secs(1);
+/// How many transfers the status line names before summing up the rest
+const LIST_SHOWN: usize = 3;
+
+/// Transfers in progress by filename, with the bytes received so far and the file size if known
+type RecvList = Mutex<BTreeMap<String, (u64, Option<u64>)>>;
 
 /// Server function sets up a listening socket for any incoming connnections
 pub fn run(opt: ListenOpt) -> Result<(), TeleportError> {
-    // Bind to all interfaces on specified Port
-    let listener = match TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, opt.port))) {
+    run_with_stats(opt, Arc::new(Mutex::new(ServerStats::default())))
+}
+
+/// Like `run`, keeping the totals in `stats` where another thread can read them
+pub fn run_with_stats(opt: ListenOpt, stats: Arc<Mutex<ServerStats>>) -> Result<(), TeleportError> {
+    if let Err(e) = fs::create_dir_all(&opt.output_dir) {
+        error!(
+            "Cannot create output directory: {}",
+            opt.output_dir.display()
+        );
+        return Err(TeleportError::Io(e));
+    }
+
+    let identity = load_identity(opt.key_file.as_deref())?;
+
+    // Bind to the requested address, or all interfaces on specified Port. Only use a separate IPv4
+    // listener if the system cannot accept both families on one socket
+    let bound = match opt.bind {
+        Some(ip) => bind(SocketAddr::from((ip, opt.port))),
+        None => bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, opt.port)))
+            .or_else(|_| bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, opt.port)))),
+    };
+    let listener = match bound {
         Ok(l) => l,
-        Err(_) => match TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, opt.port))) {
-            Ok(l) => l,
-            Err(s) => {
-                println!(
-                    "Cannot bind to port: {}. Is Teleporter already running?",
-                    &opt.port
-                );
-                return Err(TeleportError::Io(s));
-            }
-        },
+        Err(s) => {
+            error!(
+                "Cannot bind to port: {}. Is Teleporter already running?",
+                &opt.port
+            );
+            return Err(TeleportError::Io(s));
+        }
     };
 
     // Print welcome banner
-    println!(
-        "Teleporter Server {} listening for connections on 0.0.0.0:{}",
-        VERSION, &opt.port
+    let addr = match opt.bind {
+        Some(ip) => SocketAddr::from((ip, opt.port)).to_string(),
+        None => format!("0.0.0.0:{}", opt.port),
+    };
+    let public = x25519_dalek::PublicKey::from(&identity).to_bytes();
+    if !opt.quiet {
+        info!(
+            "Teleporter Server {} listening for connections on {}",
+            VERSION, addr
+        );
+        info!("Server key fingerprint: {}", crypto::fingerprint(&public));
+    }
+    events::emit(
+        "listening",
+        json!({ "address": addr, "fingerprint": crypto::fingerprint(&public) }),
     );
 
     // Print warning banner for dangerous options
     if opt.allow_dangerous_filepath {
-        println!("Warning: `--allow-dangerous-filepath` is ENABLED. This is a potentially dangerous option, use at your own risk!");
+        warn!("Warning: `--allow-dangerous-filepath` is ENABLED. This is a potentially dangerous option, use at your own risk!");
+    }
+    if opt.allow_plaintext {
+        warn!("Warning: `--allow-plaintext` is ENABLED. Unencrypted filenames and file data can be read by anyone on the network!");
+    }
+    if opt.must_encrypt {
+        warn!("`--must-encrypt` is deprecated, encryption is required unless --allow-plaintext is given");
+    }
+
+    if let Some(m) = &opt.verify_manifest {
+        if !opt.quiet {
+            info!(
+                "Checking received files against a manifest of {} files",
+                m.count()
+            );
+        }
+    }
+
+    // Stop accepting connections and wind down transfers on Ctrl-C
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&shutdown);
+    if let Err(e) = ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst)) {
+        warn!("Could not install the Ctrl-C handler: {e}");
+    }
+
+    serve(listener, opt, Arc::new(identity), shutdown, stats)
+}
+
+/// Bind a listener that a restarted server can take over while old connections are in TIME_WAIT
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Accept IPv4 clients on the IPv6 wildcard too, whatever the system default is
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    // Windows allows the rebind anyway, and there SO_REUSEADDR would let others steal a port in use
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+
+    Ok(socket.into())
+}
+
+/// Load the server identity key from `path`, creating it if missing, or make a fresh one
+fn load_identity(path: Option<&Path>) -> Result<StaticSecret, TeleportError> {
+    let path = match path {
+        Some(p) => p,
+        None => return crypto::gensecret(),
+    };
+
+    if path.exists() {
+        let key: [u8; 32] = match fs::read(path)?.try_into() {
+            Ok(k) => k,
+            Err(_) => {
+                error!("Invalid identity key file: {}", path.display());
+                return Err(TeleportError::InvalidPubKey);
+            }
+        };
+        return Ok(StaticSecret::from(key));
+    }
+
+    // Only the owner may read the secret key
+    let identity = crypto::gensecret()?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(&identity.to_bytes())?;
+    info!("Created identity key file: {}", path.display());
+
+    Ok(identity)
+}
+
+/// Accept connections on the listener and handle each one in its own thread
+fn serve(
+    listener: TcpListener,
+    opt: ListenOpt,
+    identity: Arc<StaticSecret>,
+    shutdown: Arc<AtomicBool>,
+    stats: Arc<Mutex<ServerStats>>,
+) -> Result<(), TeleportError> {
+    let recv_list = Arc::new(RecvList::default());
+    let aborted = Arc::new(Mutex::new(Vec::<String>::new()));
+    let active = Arc::new(AtomicUsize::new(0));
+    let transfers = Arc::new(Transfers::default());
+    let total_rate = opt
+        .max_total_bandwidth
+        .map(|r| Arc::new(Mutex::new(TokenBucket::new(r))));
+
+    // Keep the idle status line current on a terminal, it would only clutter logs and pipes
+    if !events::json() && !opt.to_stdout && !opt.quiet && io::stdout().is_terminal() {
+        let recv_list = Arc::clone(&recv_list);
+        let stats = Arc::clone(&stats);
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || heartbeat(&recv_list, &stats, &shutdown));
+    }
+
+    // Log the totals regularly for long running servers
+    if let Some(interval) = opt.stats_interval {
+        let stats = Arc::clone(&stats);
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || report_stats(interval, &stats, &shutdown));
     }
 
-    let recv_list = Arc::new(Mutex::new(Vec::<String>::new()));
+    // Poll for connections so the shutdown flag is noticed
+    listener.set_nonblocking(true)?;
 
     // Listen for incoming connections
-    for stream in listener.incoming() {
+    while !shutdown.load(Ordering::SeqCst) {
         let args = opt.clone();
-        let s = match stream {
-            Ok(s) => s,
+        let mut s = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
             _ => continue,
         };
+        s.set_nonblocking(false)?;
+
+        // Politely turn away connections over the limit
+        let conn = Connection::new(&active);
+        if let Some(max) = opt.max_connections {
+            if conn.count > max {
+                warn!("Connection limit of {max} reached, refusing connection");
+                let resp = TeleportInitAck::new(TeleportStatus::ServerBusy);
+                if let Err(e) = send_ack(resp, &mut s, PROTOCOL_VERSION, &None, &stats) {
+                    debug!("Could not send busy status: {e:?}");
+                }
+                continue;
+            }
+        }
+
+        // Received data goes to stdout, so take one transfer at a time and stop after the first
+        if opt.to_stdout {
+            let _conn = conn;
+            match handle_connection(
+                s,
+                &recv_list,
+                args,
+                &identity,
+                &shutdown,
+                &aborted,
+                &stats,
+                None,
+                total_rate.as_deref(),
+                None,
+            ) {
+                Ok(Some(_)) => break,
+                Ok(None) => continue,
+                // Every failure after the transfer was accepted comes back as StdoutIncomplete,
+                // and nothing may be appended to the partial data already written
+                Err(TeleportError::StdoutIncomplete) => {
+                    return Err(TeleportError::StdoutIncomplete)
+                }
+                Err(e) if is_timeout(&e) => warn!("Connection timed out, closing it"),
+                Err(e) => error!("Error: {e:?}"),
+            }
+            continue;
+        }
 
         // Receive connections in recv function
         let recv_list_clone = Arc::clone(&recv_list);
+        let shutdown_clone = Arc::clone(&shutdown);
+        let aborted_clone = Arc::clone(&aborted);
+        let identity_clone = Arc::clone(&identity);
+        let transfers_clone = Arc::clone(&transfers);
+        let stats_clone = Arc::clone(&stats);
+        let total_rate_clone = total_rate.clone();
+        let quiet = opt.quiet;
         thread::spawn(move || {
-            if let Err(e) = handle_connection(s, &recv_list_clone, args) {
-                println!("Error: {e:?}");
+            let _conn = conn;
+            match handle_connection(
+                s,
+                &recv_list_clone,
+                args,
+                &identity_clone,
+                &shutdown_clone,
+                &aborted_clone,
+                &stats_clone,
+                Some(&transfers_clone),
+                total_rate_clone.as_deref(),
+                None,
+            ) {
+                Err(e) if is_timeout(&e) => warn!("Connection timed out, closing it"),
+                Err(e) => {
+                    events::emit("error", json!({ "message": e.to_string() }));
+                    error!("Error: {e:?}");
+                }
+                Ok(_) => (),
             }
             let recv_list = recv_list_clone
                 .lock()
                 .expect("Fatal error locking recv_list_clone");
-            print_list(&recv_list);
+            if !quiet {
+                print_list(&recv_list);
+            }
         });
     }
 
+    // Give active transfers a chance to notice the flag and clean up
+    if !opt.quiet {
+        info!(
+            "\nShutting down, waiting for {} connection(s)",
+            active.load(Ordering::SeqCst)
+        );
+    }
+    let start = Instant::now();
+    while active.load(Ordering::SeqCst) > 0 && start.elapsed() < SHUTDOWN_WAIT {
+        thread::sleep(ACCEPT_POLL);
+    }
+
+    let aborted = aborted.lock().expect("Fatal error locking aborted");
+    if aborted.is_empty() {
+        if !opt.quiet {
+            info!("No transfers were aborted");
+        }
+    } else {
+        warn!("Aborted {} transfer(s): {:?}", aborted.len(), aborted);
+    }
+    print_stats(&stats);
+
     Ok(())
 }
 
+/// Options for receiving a single file with `receive_file`
+#[derive(Clone, Default)]
+pub struct ReceiveOptions<'a> {
+    /// Allow absolute and relative file paths for transfers [WARNING: potentially dangerous option, use at your own risk!]
+    pub allow_dangerous_filepath: bool,
+    /// Directory the file is written below, the current directory if empty
+    pub output_dir: PathBuf,
+    /// Accept an unencrypted transfer
+    pub allow_plaintext: bool,
+    /// Only accept transfers from these usernames
+    pub allowed_users: Option<Vec<String>>,
+    /// Shared secret token the client must send along with its username
+    pub token: Option<String>,
+    /// Keep the partial output of an aborted transfer instead of deleting it
+    pub keep_partial: bool,
+    /// Write through symlinks found at the destination path
+    pub follow_symlinks: bool,
+    /// Give up if the connection stalls for this long
+    pub timeout: Option<Duration>,
+    /// Bytes of disk space to always leave free
+    pub min_free: u64,
+    /// Refuse files larger than this many bytes
+    pub max_file_size: Option<u64>,
+    /// Pre-shared passphrase the client must mix into the encryption key
+    pub passphrase: Option<String>,
+    /// Secret key identifying the server to encrypting clients, a fresh one is made if None
+    pub identity: Option<[u8; 32]>,
+    /// Number of backups kept of an overwritten file when the client asks for one, 0 is treated as 1
+    pub backup_count: u16,
+    /// Read file data no faster than this many bytes/sec
+    pub max_bandwidth: Option<u64>,
+    /// Permission bits cleared from the mode the client sends, 022 if None
+    pub umask: Option<u32>,
+    /// Chunk size of the delta sent for an overwritten file, picked from its size if None
+    pub delta_chunk_size: Option<usize>,
+    /// Receives progress updates as file data is received
+    pub progress: Option<&'a dyn ProgressReporter>,
+}
+
+impl ReceiveOptions<'_> {
+    /// The equivalent command line options for a single connection
+    fn to_opt(&self) -> ListenOpt {
+        ListenOpt {
+            allow_dangerous_filepath: self.allow_dangerous_filepath,
+            allow_plaintext: self.allow_plaintext,
+            must_encrypt: false,
+            port: 0,
+            output_dir: match self.output_dir.as_os_str().is_empty() {
+                true => PathBuf::from("."),
+                false => self.output_dir.clone(),
+            },
+            bind: None,
+            allowed_users: self.allowed_users.clone(),
+            token: self.token.clone(),
+            keep_partial: self.keep_partial,
+            follow_symlinks: self.follow_symlinks,
+            timeout: self.timeout,
+            max_connections: None,
+            allow_listing: false,
+            min_free: self.min_free,
+            max_file_size: self.max_file_size,
+            key_file: None,
+            passphrase: self.passphrase.clone(),
+            nagle: false,
+            backup_count: self.backup_count.max(1),
+            to_stdout: false,
+            stats_interval: None,
+            max_bandwidth: self.max_bandwidth,
+            max_total_bandwidth: None,
+            quiet: false,
+            umask: self.umask.unwrap_or(0o022),
+            delta_chunks: DELTA_CHUNKS,
+            delta_chunk_size: self.delta_chunk_size,
+            verify_manifest: None,
+            config: None,
+        }
+    }
+}
+
+/// Receive a single file from an already accepted stream into the output directory
+pub fn receive_file(
+    stream: TcpStream,
+    opts: ReceiveOptions,
+) -> Result<TransferStats, TeleportError> {
+    let recv_list = Arc::new(RecvList::default());
+    let aborted = Mutex::new(Vec::<String>::new());
+    let shutdown = AtomicBool::new(false);
+    let stats = Mutex::new(ServerStats::default());
+    let identity = match opts.identity {
+        Some(key) => StaticSecret::from(key),
+        None => crypto::gensecret()?,
+    };
+
+    handle_connection(
+        stream,
+        &recv_list,
+        opts.to_opt(),
+        &identity,
+        &shutdown,
+        &aborted,
+        &stats,
+        None,
+        None,
+        opts.progress,
+    )?
+    .ok_or(TeleportError::NoTransfer)
+}
+
+/// Counts a connection as active until it is dropped
+struct Connection {
+    active: Arc<AtomicUsize>,
+    count: usize,
+}
+
+impl Connection {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        let count = active.fetch_add(1, Ordering::SeqCst) + 1;
+        Connection {
+            active: Arc::clone(active),
+            count,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 fn send_ack(
     ack: TeleportInitAck,
     stream: &mut TcpStream,
+    version: u8,
     enc: &Option<TeleportEnc>,
+    stats: &Mutex<ServerStats>,
 ) -> Result<(), TeleportError> {
+    // Count every refusal or failure by the status the client is told
+    let status = TeleportStatus::try_from(ack.status)?;
+    if status != TeleportStatus::Proceed && status != TeleportStatus::Pong {
+        *stats
+            .lock()
+            .expect("Fatal error locking stats")
+            .refused
+            .entry(status)
+            .or_insert(0) += 1;
+    }
+
+    if events::json() {
+        let peer = stream.peer_addr().map(|a| a.to_string()).ok();
+        events::emit(
+            "ack",
+            json!({ "peer": peer, "status": format!("{status:?}") }),
+        );
+    }
+
     // Encode and send response
-    utils::send_packet(stream, TeleportAction::InitAck, enc, ack.serialize()?)
+    utils::send_packet(
+        stream,
+        TeleportAction::InitAck,
+        version,
+        enc,
+        ack.serialize()?,
+    )
 }
 
-fn print_list(list: &MutexGuard<Vec<String>>) {
-    if list.len() == 0 {
-        print!("\rListening...");
-    } else {
-        print!("\rReceiving: {list:?}");
+/// Redraw the status line with the uptime and files received, or the progress of each transfer, until shutdown
+fn heartbeat(recv_list: &RecvList, stats: &Mutex<ServerStats>, shutdown: &AtomicBool) {
+    let start = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(HEARTBEAT);
+
+        // Holding the lock keeps this from interleaving with transfers starting or ending
+        let list = recv_list.lock().expect("Fatal error locking recv_list");
+        match list.is_empty() {
+            true => {
+                let files = stats.lock().expect("Fatal error locking stats").files;
+                print!("\r{}", idle_line(start.elapsed(), files));
+                io::stdout().flush().expect("Fatal error flushing stdout");
+            }
+            false => print_list(&list),
+        }
+    }
+}
+
+/// Add an accepted transfer to the totals, `done` is None if it did not complete
+fn record_transfer(stats: &Mutex<ServerStats>, done: Option<&TransferStats>) {
+    let mut stats = stats.lock().expect("Fatal error locking stats");
+    match done {
+        Some(d) => {
+            stats.files += 1;
+            stats.bytes += d.bytes;
+        }
+        None => stats.failed += 1,
+    }
+}
+
+/// Log the totals every `interval` until shutdown
+fn report_stats(interval: Duration, stats: &Mutex<ServerStats>, shutdown: &AtomicBool) {
+    let mut last = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(ACCEPT_POLL);
+        if last.elapsed() >= interval {
+            print_stats(stats);
+            last = Instant::now();
+        }
+    }
+}
+
+/// Log the totals and emit them as a stats event
+fn print_stats(stats: &Mutex<ServerStats>) {
+    let stats = stats.lock().expect("Fatal error locking stats").clone();
+    info!("\rStats: {stats}");
+    let refused: serde_json::Map<String, serde_json::Value> = stats
+        .refused
+        .iter()
+        .map(|(status, count)| (format!("{status:?}"), json!(count)))
+        .collect();
+    events::emit(
+        "stats",
+        json!({
+            "files": stats.files,
+            "bytes": stats.bytes,
+            "failed": stats.failed,
+            "refused": refused,
+        }),
+    );
+}
+
+/// The idle status line, padded to hide the end of a longer line drawn before it
+fn idle_line(uptime: Duration, files: u64) -> String {
+    let line = format!(
+        "Listening... (up {}, {} file{} received)",
+        utils::format_clock(uptime.as_secs()),
+        files,
+        if files == 1 { "" } else { "s" }
+    );
+    format!("{line:<60}")
+}
+
+/// The status line naming each transfer with its progress, padded like the idle line
+fn list_line(list: &BTreeMap<String, (u64, Option<u64>)>) -> String {
+    if list.is_empty() {
+        return format!("{:<60}", "Listening...");
+    }
+
+    let mut files: Vec<String> = list
+        .iter()
+        .take(LIST_SHOWN)
+        .map(|(name, (received, total))| match total {
+            Some(0) => format!("{name} 100%"),
+            Some(t) => format!("{name} {}%", (*received as f64 / *t as f64 * 100.0) as u64),
+            None => format!("{name} {}", utils::format_size(*received)),
+        })
+        .collect();
+    if list.len() > LIST_SHOWN {
+        files.push(format!("+{} more", list.len() - LIST_SHOWN));
     }
+
+    format!("{:<60}", format!("Receiving: {}", files.join(", ")))
+}
+
+fn print_list(list: &MutexGuard<BTreeMap<String, (u64, Option<u64>)>>) {
+    // The status line would garble the JSON events on stdout
+    if events::json() {
+        return;
+    }
+
+    print!("\r{}", list_line(list));
     io::stdout().flush().expect("Fatal error flushing stdout");
 }
 
-fn rm_filename_from_list(filename: &str, list: &Arc<Mutex<Vec<String>>>) {
+/// Record the bytes of `filename` received so far for the status line
+fn update_list(filename: &str, received: u64, list: &RecvList) {
     let mut recv_data = list.lock().expect("Fatal error locking file list");
-    recv_data.retain(|x| x != filename);
+    if let Some(entry) = recv_data.get_mut(filename) {
+        entry.0 = received;
+    }
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
-    recv_list: &Arc<Mutex<Vec<String>>>,
-    opt: ListenOpt,
-) -> Result<(), TeleportError> {
-    let start_time = Instant::now();
-    let ip = stream.peer_addr()?;
+/// Log whether a received file has the hash the manifest lists for the name it was `sent` as
+fn check_manifest(manifest: &Manifest, sent: &str, file: &File, filename: &str) {
+    let expected = match manifest.get(sent) {
+        Some(h) => h,
+        None => {
+            warn!(" => Not in the manifest: {}", sent);
+            return;
+        }
+    };
+    match manifest::matches(file, expected) {
+        Ok(true) => info!(" => Matches the manifest: {}", filename),
+        Ok(false) => {
+            error!(" => Error: does not match the manifest: {}", filename);
+            events::emit(
+                "error",
+                json!({ "filename": filename, "message": "manifest mismatch" }),
+            );
+        }
+        Err(e) => warn!(
+            " => Could not check {} against the manifest: {:?}",
+            filename, e
+        ),
+    }
+}
 
-    let mut enc: Option<TeleportEnc> = None;
+/// Reply to a List request with the files found under `dir`
+fn send_list(
+    stream: &mut TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    dir: &str,
+    opt: &ListenOpt,
+) -> Result<(), TeleportError> {
+    let root = match dir.is_empty() {
+        true => Path::new("."),
+        false => Path::new(dir),
+    };
 
-    // Receive header first
-    let mut packet = utils::recv_packet(&mut stream, &None)?;
-    if packet.action == TeleportAction::Ping as u8 {
-        let mut ping = TeleportInit::default();
-        ping.deserialize(&packet.data)?;
-        if !TeleportFeatures::Ping.check_u32(ping.features) {
-            return Ok(());
-        }
-        println!(
-            "\rPing received from Teleporter v{} at {}",
-            ping.version, ip
-        );
-        let pong = TeleportInitAck::new(TeleportStatus::Pong);
-        return utils::send_packet(
-            &mut stream,
-            TeleportAction::PingAck,
-            &None,
-            pong.serialize()?,
-        );
-    } else if packet.action == TeleportAction::Ecdh as u8 {
-        let mut ctx = TeleportEnc::new();
-        let privkey = crypto::genkey(&mut ctx);
-        ctx.deserialize(&packet.data)?;
-        ctx.calc_secret(privkey);
-        utils::send_packet(&mut stream, TeleportAction::EcdhAck, &None, ctx.serialize())?;
-        enc = Some(ctx);
-        packet = utils::recv_packet(&mut stream, &enc)?;
-    } else if opt.must_encrypt {
-        let resp = TeleportInitAck::new(TeleportStatus::RequiresEncryption);
-        return send_ack(resp, &mut stream, &enc);
-    }
-
-    let mut header = TeleportInit::new(TeleportFeatures::NewFile);
-    header.deserialize(&packet.data)?;
-
-    if packet.action != TeleportAction::Init as u8 {
-        let resp = TeleportInitAck::new(TeleportStatus::EncryptionError);
-        return send_ack(resp, &mut stream, &enc);
-    }
-
-    let username = String::from_utf8(header.username)?;
-    println!("username: {}", &username);
-    let mut filename: String = String::from_utf8(header.filename)?;
-    let features: u32 = header.features;
-
-    let version = Version::parse(VERSION).expect("Fatal version error");
-    let compatible = header.version.is_compatible(&version);
-
-    if !compatible {
-        println!(
-            "Error: Version mismatch from: {:?}! Us:{} Client:{}",
-            ip, VERSION, header.version
-        );
-        let resp = TeleportInitAck::new(TeleportStatus::WrongVersion);
-        return send_ack(resp, &mut stream, &enc);
+    let mut list = TeleportList::new(TeleportStatus::Proceed);
+    if !opt.allow_listing {
+        warn!(" => Refusing to list files, listing is not allowed");
+        list.status = TeleportStatus::NoPermission as u8;
+    } else if !root.is_dir() {
+        list.status = TeleportStatus::BadFileName as u8;
+    } else {
+        list_files(root, &opt.output_dir, &mut list.entries)?;
+        list.entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+        info!(" => Listed {} files in {:?}", list.entries.len(), root);
     }
 
-    if !opt.allow_dangerous_filepath {
-        if filename.starts_with('/') {
-            // Remove any preceeding '/'
-            filename.remove(0);
+    utils::send_packet(
+        stream,
+        TeleportAction::ListAck,
+        version,
+        enc,
+        list.serialize()?,
+    )
+}
+
+/// Collect the regular files below `dir` named relative to `root`, skipping symlinks and unfinished transfers
+fn list_files(
+    dir: &Path,
+    root: &Path,
+    entries: &mut Vec<TeleportListEntry>,
+) -> Result<(), TeleportError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            list_files(&path, root, entries)?;
+            continue;
+        }
+
+        let name = path.strip_prefix(root).unwrap_or(&path).to_string_lossy();
+        if !meta.is_file() || name.ends_with(".teleport-tmp") {
+            continue;
         }
 
-        // Prohibit directory traversal
-        filename = filename.replace("../", "");
+        entries.push(TeleportListEntry {
+            filesize: meta.len(),
+            filename_len: u16::try_from(name.len())?,
+            filename: name.as_bytes().to_vec(),
+        });
     }
 
-    if TeleportFeatures::Rename.check_u32(features) {
-        let mut num = 1;
-        let mut dest = filename.clone();
-        while Path::new(&dest).exists() {
-            dest = filename.clone() + "." + &num.to_string();
-            num += 1;
+    Ok(())
+}
+
+fn rm_filename_from_list(filename: &str, list: &RecvList) {
+    let mut recv_data = list.lock().expect("Fatal error locking file list");
+    recv_data.remove(filename);
+}
+
+fn authorized(opt: &ListenOpt, username: &str, token: &[u8]) -> bool {
+    if let Some(users) = &opt.allowed_users {
+        if !users.iter().any(|u| u == username) {
+            return false;
         }
-        filename = dest;
     }
 
-    // Test if overwrite is false and file exists
-    if !TeleportFeatures::Overwrite.check_u32(features) && Path::new(&filename).exists() {
-        println!(" => Refusing to overwrite file: {}", &filename);
-        let resp = TeleportInitAck::new(TeleportStatus::NoOverwrite);
-        return send_ack(resp, &mut stream, &enc);
+    match &opt.token {
+        Some(t) => crypto::ct_eq(t.as_bytes(), token),
+        None => true,
     }
+}
 
-    // Create recursive dirs
-    let path = match Path::new(&filename).parent() {
-        Some(p) => p,
-        None => {
-            println!(
-                "Error: unable to parse the path and filename: {}",
-                &filename
-            );
-            let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
-            return send_ack(resp, &mut stream, &enc);
+/// Check if an error came from a stalled connection hitting the socket timeout
+fn is_timeout(e: &TeleportError) -> bool {
+    match e {
+        TeleportError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+/// Resolve `filename` relative to `root`, or None if it would end up outside of `root`
+fn confine(root: &Path, filename: &str) -> Option<String> {
+    let mut clean = PathBuf::new();
+    for c in Path::new(filename).components() {
+        match c {
+            Component::Normal(part) => clean.push(part),
+            // Absolute paths are kept below the root
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
         }
-    };
+    }
+
+    // Symlinks already below the root must not lead out of it
+    let root = root.canonicalize().ok()?;
+    let mut existing = root.join(&clean);
+    while fs::symlink_metadata(&existing).is_err() {
+        existing.pop();
+    }
+    if !existing.canonicalize().ok()?.starts_with(&root) {
+        return None;
+    }
+
+    Some(clean.to_string_lossy().to_string())
+}
+
+/// Device names Windows reserves in every directory, with or without an extension
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check that every component of `filename` can be created on a Windows filesystem
+fn windows_safe_name(filename: &str) -> bool {
+    filename
+        .split(['/', '\\'])
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .all(|c| {
+            let stem = c.split('.').next().unwrap_or(c).trim_end();
+            !c.chars()
+                .any(|ch| ch.is_control() || "<>:\"|?*".contains(ch))
+                && !c.ends_with(['.', ' '])
+                && !WINDOWS_RESERVED
+                    .iter()
+                    .any(|r| r.eq_ignore_ascii_case(stem))
+        })
+}
 
-    if fs::create_dir_all(path).is_err() {
-        println!("Error: unable to create directories: {}", &path.display());
-        let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
-        return send_ack(resp, &mut stream, &enc);
+/// Sibling temp file a transfer is written into, e.g. "dir/.file.teleport-tmp"
+fn temp_name(filename: &str) -> String {
+    let path = Path::new(filename);
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return filename.to_owned() + ".teleport-tmp",
     };
 
-    // Open file for writing
-    let mut file = match OpenOptions::new().read(true).write(true).open(&filename) {
-        Ok(f) => {
-            if TeleportFeatures::Backup.check_u32(features) {
-                let dest = filename.clone() + ".bak";
-                fs::copy(&filename, &dest)?;
+    let tmp = format!(".{name}.teleport-tmp");
+    match path.parent() {
+        Some(p) => p.join(tmp).to_string_lossy().to_string(),
+        None => tmp,
+    }
+}
+
+/// Removes the transfer's temp file unless the transfer finished or the file should be kept
+struct TempFile {
+    path: String,
+    keep: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep && Path::new(&self.path).exists() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!(" => Could not remove temp file {}: {}", &self.path, e);
             }
-            f
         }
-        Err(_) => match File::create(&filename) {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Error: unable to create file: {}", &filename);
-                let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
-                return send_ack(resp, &mut stream, &enc);
+    }
+}
+
+/// Byte ranges of a transfer that hold final data, so chunks may arrive in any order
+#[derive(Debug, Default)]
+struct WrittenRanges {
+    // Start offset of each disjoint range mapped to its end offset
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl WrittenRanges {
+    /// Mark [start, end) as written, merging it with any overlapping or adjacent ranges
+    fn insert(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back() {
+            if e >= start {
+                start = s;
+                end = end.max(e);
             }
-        },
-    };
-    let meta = file.metadata()?;
-    let mut perms = meta.permissions();
-    perms.set_mode(header.chmod);
-    if fs::set_permissions(&filename, perms).is_err() {
-        println!("Could not set file permissions");
-        let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
-        return send_ack(resp, &mut stream, &enc);
-    };
+        }
 
-    // Send ready for data ACK
-    let mut resp = TeleportInitAck::new(TeleportStatus::Proceed);
-    TeleportFeatures::NewFile.add(&mut resp.features)?;
+        let absorbed: Vec<u64> = self.ranges.range(start..=end).map(|(&s, _)| s).collect();
+        for s in absorbed {
+            if let Some(e) = self.ranges.remove(&s) {
+                end = end.max(e);
+            }
+        }
+        self.ranges.insert(start, end);
+    }
 
-    // Add file to list
-    let mut recv_data = recv_list.lock().expect("Fatal error locking recv_list");
-    recv_data.push(filename.clone());
-    print_list(&recv_data);
-    drop(recv_data);
-
-    // If overwrite and file exists, build TeleportDelta
-    file.set_len(header.filesize)?;
-    if meta.len() > 0 {
-        TeleportFeatures::Overwrite.add(&mut resp.features)?;
-        if TeleportFeatures::Delta.check_u32(features) {
-            TeleportFeatures::Delta.add(&mut resp.features)?;
-            resp.delta = match TeleportDelta::delta_hash(&file) {
-                Ok(d) => Some(d),
-                _ => None,
-            };
+    /// Mark everything written in `other` as written
+    fn extend(&mut self, other: &WrittenRanges) {
+        for (&start, &end) in &other.ranges {
+            self.insert(start, end);
         }
     }
 
-    match send_ack(resp, &mut stream, &enc) {
-        Ok(_) => (),
-        Err(e) => {
-            println!(
-                "Connection closed (reason: {:?}). Aborted {} transfer.",
-                e, &filename
-            );
-            rm_filename_from_list(&filename, recv_list);
-            return Ok(());
+    /// Forget everything written at or past offset
+    fn truncate(&mut self, offset: u64) {
+        self.ranges.split_off(&offset);
+        if let Some(end) = self.ranges.values_mut().next_back() {
+            *end = (*end).min(offset);
         }
     }
 
-    // Receive file data
-    let mut received: u64 = 0;
-    loop {
-        // Read from network connection
-        let packet = match utils::recv_packet(&mut stream, &enc) {
-            Ok(s) => s,
-            Err(e) => {
-                println!(
-                    "Connection closed (reason: {:?}). Aborted {} transfer.",
-                    e, &filename
-                );
-                break;
-            }
-        };
-        let mut chunk = TeleportData::new();
-        chunk.deserialize(&packet.data)?;
+    /// Number of bytes written
+    fn total(&self) -> u64 {
+        self.ranges.iter().map(|(s, e)| e - s).sum()
+    }
 
-        if chunk.data_len == 0 {
-            if received == header.filesize
-                || (header.filesize == chunk.offset && chunk.data_len == 0)
-            {
-                let duration = start_time.elapsed();
-                let speed =
-                    (header.filesize as f64 * 8.0) / duration.as_secs() as f64 / 1024.0 / 1024.0;
-                println!(
-                    " => Received file: {} (from: {} v{}) ({:.2?} @ {:.3} Mbps)",
-                    &filename, ip, &header.version, duration, speed
-                );
-            } else {
-                println!(" => Error receiving: {}", &filename);
-            }
-            break;
+    /// True once every byte of [0, size) has been written
+    fn covers(&self, size: u64) -> bool {
+        size == 0 || matches!(self.ranges.get(&0), Some(&end) if end >= size)
+    }
+}
+
+/// Transfers that extra connections can join by id to send chunks in parallel
+type Transfers = Mutex<HashMap<[u8; STREAM_ID_LEN], Arc<SharedTransfer>>>;
+
+/// A file being received over the extra connections joined to the transfer
+struct SharedTransfer {
+    filename: String,
+    // Only written at explicit offsets, so handles to it never disturb each other
+    file: File,
+    filesize: u64,
+    compress: bool,
+    state: Mutex<SharedState>,
+    // Signalled whenever a joined connection finishes
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct SharedState {
+    written: WrittenRanges,
+    chunks: u64,
+    streams: usize,
+}
+
+/// Lets extra connections join a transfer until it is finished or dropped
+struct ParallelTransfer<'a> {
+    transfers: &'a Transfers,
+    id: [u8; STREAM_ID_LEN],
+    shared: Arc<SharedTransfer>,
+}
+
+impl<'a> ParallelTransfer<'a> {
+    fn new(transfers: &'a Transfers, id: [u8; STREAM_ID_LEN], shared: SharedTransfer) -> Self {
+        let shared = Arc::new(shared);
+        transfers
+            .lock()
+            .expect("Fatal error locking transfers")
+            .insert(id, Arc::clone(&shared));
+
+        ParallelTransfer {
+            transfers,
+            id,
+            shared,
         }
+    }
 
-        // Seek to offset
-        file.seek(SeekFrom::Start(chunk.offset))?;
+    /// True if the joined connections wrote more chunks since the last call
+    fn progressed(&self, seen: &mut u64) -> bool {
+        let state = self.shared.state.lock().expect("Fatal error locking state");
+        let moved = state.streams > 0 && state.chunks != *seen;
+        *seen = state.chunks;
 
-        // Write received data to file
-        let wrote = file.write(&chunk.data)?;
+        moved
+    }
 
-        if chunk.data_len as usize != wrote {
-            println!(
-                "Error writing to file: {} (read: {}, wrote: {}). Out of space?",
-                &filename, chunk.data_len, wrote
-            );
-            break;
+    /// Stop accepting connections and wait for the joined ones to finish
+    fn finish(&self, shutdown: &AtomicBool) -> MutexGuard<'_, SharedState> {
+        self.transfers
+            .lock()
+            .expect("Fatal error locking transfers")
+            .remove(&self.id);
+
+        let mut state = self.shared.state.lock().expect("Fatal error locking state");
+        while state.streams > 0 && !shutdown.load(Ordering::SeqCst) {
+            state = self
+                .shared
+                .finished
+                .wait_timeout(state, ACCEPT_POLL)
+                .expect("Fatal error locking state")
+                .0;
         }
 
-        received = chunk.offset;
-        received += chunk.data_len as u64;
+        state
+    }
+}
 
-        if received > header.filesize {
-            println!(
-                "Error: Received {} greater than filesize!",
-                received - header.filesize
-            );
-            break;
+impl Drop for ParallelTransfer<'_> {
+    fn drop(&mut self) {
+        self.transfers
+            .lock()
+            .expect("Fatal error locking transfers")
+            .remove(&self.id);
+    }
+}
+
+/// Counts a connection as joined to a transfer until it is dropped
+struct JoinedStream(Arc<SharedTransfer>);
+
+impl Drop for JoinedStream {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().expect("Fatal error locking state");
+        state.streams -= 1;
+        self.0.finished.notify_all();
+    }
+}
+
+/// Limits how fast data is read from a connection, and from all connections together
+struct Throttle<'a> {
+    conn: Option<TokenBucket>,
+    total: Option<&'a Mutex<TokenBucket>>,
+}
+
+impl<'a> Throttle<'a> {
+    fn new(rate: Option<u64>, total: Option<&'a Mutex<TokenBucket>>) -> Self {
+        Throttle {
+            conn: rate.map(TokenBucket::new),
+            total,
+        }
+    }
+
+    /// Wait until `len` more bytes fit within both limits, slowing the sender down through TCP
+    fn take(&mut self, len: usize) {
+        if let Some(b) = self.conn.as_mut() {
+            b.take(len);
+        }
+        // Sleeping with the lock held makes the other connections wait their turn
+        if let Some(t) = self.total {
+            t.lock().expect("Fatal error locking total_rate").take(len);
         }
     }
+}
 
-    rm_filename_from_list(&filename, recv_list);
+/// Receive chunks over an extra connection until it sends its final marker
+#[allow(clippy::too_many_arguments)]
+fn receive_joined(
+    mut stream: TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    id: &[u8],
+    transfers: Option<&Transfers>,
+    shutdown: &AtomicBool,
+    stats: &Mutex<ServerStats>,
+    throttle: &mut Throttle,
+) -> Result<Option<TransferStats>, TeleportError> {
+    // Count the connection while the transfer is looked up, so finishing cannot miss it
+    let joined = transfers.and_then(|t| {
+        let id = <[u8; STREAM_ID_LEN]>::try_from(id).ok()?;
+        let shared = Arc::clone(t.lock().expect("Fatal error locking transfers").get(&id)?);
+        shared
+            .state
+            .lock()
+            .expect("Fatal error locking state")
+            .streams += 1;
+        Some(JoinedStream(shared))
+    });
+    let shared = match &joined {
+        Some(j) => Arc::clone(&j.0),
+        None => {
+            warn!(" => Refused a connection joining an unknown transfer");
+            let resp = TeleportInitAck::new(TeleportStatus::UnknownTransfer);
+            return send_ack(resp, &mut stream, version, enc, stats).map(|_| None);
+        }
+    };
+    send_ack(
+        TeleportInitAck::new(TeleportStatus::Proceed),
+        &mut stream,
+        version,
+        enc,
+        stats,
+    )?;
 
-    Ok(())
+    while !shutdown.load(Ordering::SeqCst) {
+        let packet = utils::recv_packet(&mut stream, version, enc)?;
+        if answer_keepalive(&mut stream, version, enc, &packet)? {
+            continue;
+        }
+        throttle.take(packet.data.len());
+        let mut chunk = TeleportData::new();
+        chunk.deserialize(&packet.data)?;
+        if chunk.is_final() {
+            break;
+        }
+
+        if chunk.data_len > 0 {
+            if shared.compress {
+                chunk.data =
+                    compress::unpack(&chunk.data, shared.filesize.saturating_sub(chunk.offset))?;
+                chunk.data_len = u32::try_from(chunk.data.len())?;
+            }
+
+            if chunk.offset.saturating_add(chunk.data_len as u64) > shared.filesize {
+                error!(
+                    "Error: Received data past the end of {}! Aborted transfer.",
+                    &shared.filename
+                );
+                break;
+            }
+
+            utils::write_at(&shared.file, &chunk.data, chunk.offset)?;
+        }
+
+        let mut state = shared.state.lock().expect("Fatal error locking state");
+        state
+            .written
+            .insert(chunk.offset, chunk.offset + chunk.data_len as u64);
+        state.chunks += 1;
+    }
+
+    Ok(None)
+}
+
+/// Answer a keepalive ping the client sent between data chunks, true if `packet` was one
+fn answer_keepalive(
+    stream: &mut TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    packet: &TeleportHeader,
+) -> Result<bool, TeleportError> {
+    if packet.action != TeleportAction::Ping as u8 {
+        return Ok(false);
+    }
+
+    debug!("Keepalive ping received");
+    utils::send_packet(stream, TeleportAction::PingAck, version, enc, Vec::new())?;
+
+    Ok(true)
+}
+
+/// Claim `filename`, or the first free one of "filename.1", "filename.2", ..., by creating it
+fn reserve_name(filename: &str) -> io::Result<String> {
+    let mut num = 0;
+    loop {
+        let dest = match num {
+            0 => filename.to_string(),
+            n => format!("{filename}.{n}"),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&dest) {
+            Ok(_) => return Ok(dest),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => num += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Options to open a file the server writes, refusing to open it through a symlink unless allowed
+fn write_options(follow_symlinks: bool) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true);
+    #[cfg(unix)]
+    if !follow_symlinks {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    // Only unix can refuse symlinks at open time, the up front check still applies elsewhere
+    #[cfg(not(unix))]
+    let _ = follow_symlinks;
+
+    options
+}
+
+/// Backup names of `filename` from newest to oldest: ".bak", then ".bak.1" up to ".bak.{count - 1}"
+fn backup_names(filename: &str, count: u16) -> Vec<String> {
+    (0..count.max(1))
+        .map(|n| match n {
+            0 => format!("{filename}.bak"),
+            n => format!("{filename}.bak.{n}"),
+        })
+        .collect()
+}
+
+/// Copy `filename` to its newest backup, shifting older backups down and dropping the oldest
+fn rotate_backups(filename: &str, count: u16) -> Result<(), TeleportError> {
+    let names = backup_names(filename, count);
+    for pair in names.windows(2).rev() {
+        match fs::rename(&pair[0], &pair[1]) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    fs::copy(filename, &names[0])?;
+
+    Ok(())
+}
+
+fn finish_transfer(
+    filename: &str,
+    target: &str,
+    features: u32,
+    backup_count: u16,
+) -> Result<(), TeleportError> {
+    if TeleportFeatures::Backup.check_u32(features) && Path::new(filename).exists() {
+        rotate_backups(filename, backup_count)?;
+    }
+
+    // Move the completed file into place over the destination
+    fs::rename(target, filename)?;
+
+    Ok(())
+}
+
+/// Wait for the next file of a batch, None once the client is done with the connection
+fn next_in_batch(
+    stream: &mut TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+) -> Result<Option<TeleportHeader>, TeleportError> {
+    let packet = match utils::recv_packet(stream, version, enc) {
+        Ok(p) => p,
+        Err(e) => {
+            debug!("Batch connection closed (reason: {:?})", e);
+            return Ok(None);
+        }
+    };
+    if packet.action == TeleportAction::Done as u8 {
+        return Ok(None);
+    }
+
+    Ok(Some(packet))
+}
+
+/// Reapply the extended attributes sent by the client, skipping any that grant privileges
+fn apply_xattrs(path: &Path, xattrs: &[xattr::Xattr]) {
+    for (name, value) in xattrs {
+        let shown = String::from_utf8_lossy(name);
+        if xattr::refused(name) {
+            warn!(
+                " => Refusing extended attribute {}: {}",
+                shown,
+                path.display()
+            );
+            continue;
+        }
+        if let Err(e) = xattr::write(path, name, value) {
+            warn!(
+                " => Could not set extended attribute {} on {}: {}",
+                shown,
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Refuse a packet whose action makes no sense at this point of the session
+fn unexpected_action(
+    stream: &mut TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    action: u8,
+    ip: SocketAddr,
+    stats: &Mutex<ServerStats>,
+) -> Result<Option<TransferStats>, TeleportError> {
+    warn!(" => Unexpected action {:#04x} from {:?}", action, ip);
+    let resp = TeleportInitAck::new(TeleportStatus::UnknownAction);
+    send_ack(resp, stream, version, enc, stats).map(|_| None)
+}
+
+/// The permissions `chmod` from the client becomes: no special bits, nothing in `umask`, and
+/// always readable by the server's own user, who can also add entries to directories
+fn clamp_mode(chmod: u32, umask: u32, dir: bool, filename: &str) -> u32 {
+    let owner = if dir { 0o700 } else { 0o400 };
+    let mode = (chmod & 0o777 & !umask) | owner;
+    if chmod & 0o7000 != 0 {
+        warn!(
+            " => Dropped setuid/setgid/sticky bits from mode {:o}: {}",
+            chmod, filename
+        );
+    } else if mode != chmod {
+        debug!(" => Mode {:o} clamped to {:o}: {}", chmod, mode, filename);
+    }
+
+    mode
+}
+
+/// Chunk size of the delta of a `file_size` file: --delta-chunk-size, unless that makes more chunks
+/// than a TeleportDelta holds, or the smallest power of two making at most --delta-chunks
+fn delta_chunk_size(opt: &ListenOpt, file_size: u64) -> usize {
+    let smallest = TeleportDelta::chunk_size_for(file_size, u16::MAX - 1);
+    match opt.delta_chunk_size {
+        Some(size) => size.max(smallest).min(u32::MAX as usize),
+        None => TeleportDelta::chunk_size_for(file_size, opt.delta_chunks),
+    }
+}
+
+/// The data a COPY chunk names in the original file, at most `limit` bytes of it
+fn copy_chunk(
+    original: Option<&File>,
+    chunk: &TeleportData,
+    limit: u32,
+) -> Result<Vec<u8>, TeleportError> {
+    let (source, len) = chunk.copy_source()?;
+    let file = match original {
+        Some(f) if len <= limit => f,
+        _ => return Err(TeleportError::InvalidDelta),
+    };
+
+    let mut data = vec![0; len as usize];
+    if utils::read_at(file, &mut data, source)? != data.len() {
+        return Err(TeleportError::InvalidDelta);
+    }
+
+    Ok(data)
+}
+
+/// Create the directory `filename` with the `chmod` permissions, keeping one that already exists
+fn create_dir(filename: &str, chmod: u32) -> TeleportStatus {
+    let path = Path::new(filename);
+    if matches!(fs::symlink_metadata(path), Ok(m) if !m.is_dir()) {
+        warn!(
+            " => Refusing to replace a file with a directory: {}",
+            filename
+        );
+        return TeleportStatus::NoOverwrite;
+    }
+    if let Err(e) = fs::create_dir_all(path) {
+        error!("Error: unable to create directories: {} ({})", filename, e);
+        return TeleportStatus::NoPermission;
+    }
+
+    let mut perms = match fs::metadata(path) {
+        Ok(m) => m.permissions(),
+        Err(_) => return TeleportStatus::NoPermission,
+    };
+    utils::set_file_mode(&mut perms, chmod);
+    if fs::set_permissions(path, perms).is_err() {
+        error!("Could not set directory permissions");
+        return TeleportStatus::NoPermission;
+    }
+
+    info!(" => Created directory: {}", filename);
+    TeleportStatus::Proceed
+}
+
+/// Create the symlink `filename` pointing to `target`, which must stay below the output
+/// directory unless dangerous file paths are allowed
+fn create_symlink(
+    filename: &str,
+    name: &str,
+    target: &[u8],
+    features: u32,
+    opt: &ListenOpt,
+) -> TeleportStatus {
+    let target = match std::str::from_utf8(target) {
+        Ok(t) if !t.is_empty() => t,
+        _ => {
+            warn!(" => Refusing symlink with an invalid target: {}", filename);
+            return TeleportStatus::BadFileName;
+        }
+    };
+    if !opt.allow_dangerous_filepath && !link_inside(name, target) {
+        warn!(
+            " => Refusing symlink leading outside the server directory: {} -> {}",
+            filename, target
+        );
+        return TeleportStatus::BadFileName;
+    }
+
+    let path = Path::new(filename);
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.is_dir() || !TeleportFeatures::Overwrite.check_u32(features) {
+            warn!(" => Refusing to overwrite file: {}", filename);
+            return TeleportStatus::NoOverwrite;
+        }
+        if let Err(e) = fs::remove_file(path) {
+            error!("Error: unable to replace {} ({})", filename, e);
+            return TeleportStatus::NoPermission;
+        }
+    }
+    if let Some(parent) = path.parent() {
+        if fs::create_dir_all(parent).is_err() {
+            error!("Error: unable to create directories: {}", parent.display());
+            return TeleportStatus::NoPermission;
+        }
+    }
+
+    #[cfg(unix)]
+    let made = std::os::unix::fs::symlink(target, path);
+    #[cfg(windows)]
+    let made = std::os::windows::fs::symlink_file(target, path);
+    if let Err(e) = made {
+        error!("Error: unable to create symlink: {} ({})", filename, e);
+        return TeleportStatus::NoPermission;
+    }
+
+    info!(" => Created symlink: {} -> {}", filename, target);
+    TeleportStatus::Proceed
+}
+
+/// True if a symlink at `name`, relative to the root, pointing to `target` stays below the root
+fn link_inside(name: &str, target: &str) -> bool {
+    let mut depth = Path::new(name).components().count().saturating_sub(1);
+    for c in Path::new(target).components() {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Write the data of an accepted transfer to stdout in order instead of into a file
+#[allow(clippy::too_many_arguments)]
+fn receive_stdout(
+    stream: &mut TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    header: &TeleportInit,
+    features: u32,
+    filename: &str,
+    opt: &ListenOpt,
+    shutdown: &AtomicBool,
+    stats: &Mutex<ServerStats>,
+    throttle: &mut Throttle,
+    ip: SocketAddr,
+) -> Result<Option<TransferStats>, TeleportError> {
+    let start_time = Instant::now();
+    let unknown_size = TeleportFeatures::UnknownSize.check_u32(features);
+    let compress = TeleportFeatures::Compress.check_u32(features);
+    let algo = HashAlgorithm::from_features(features);
+
+    // Nothing exists to compare against, back up or resume, only agree to what shapes the data
+    let mut resp = TeleportInitAck::new(TeleportStatus::Proceed);
+    TeleportFeatures::NewFile.add(&mut resp.features)?;
+    if compress {
+        TeleportFeatures::Compress.add(&mut resp.features)?;
+    }
+    if algo == HashAlgorithm::Blake3 {
+        TeleportFeatures::StrongHash.add(&mut resp.features)?;
+    }
+    if unknown_size {
+        TeleportFeatures::UnknownSize.add(&mut resp.features)?;
+    }
+    if TeleportFeatures::ChunkChecksum.check_u32(features) && enc.is_none() {
+        TeleportFeatures::ChunkChecksum.add(&mut resp.features)?;
+    }
+    send_ack(resp, stream, version, enc, stats)?;
+    if !opt.quiet {
+        info!("Receiving: {} to stdout (from: {})", filename, ip);
+    }
+
+    // Whatever was written already cannot be taken back, so any failure is reported as such
+    let mut receive = || -> Result<Option<TransferStats>, TeleportError> {
+        let limit = match unknown_size {
+            true => opt.max_file_size.unwrap_or(u64::MAX),
+            false => header.filesize,
+        };
+        let mut hasher = algo.hasher();
+        let mut stdout = io::stdout().lock();
+        let mut pos: u64 = 0;
+        let mut chunks: u64 = 0;
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                warn!(" => Server shutting down. Aborted {} transfer.", filename);
+                return Err(TeleportError::StdoutIncomplete);
+            }
+
+            let packet = match utils::recv_packet(stream, version, enc) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!(
+                        "Connection closed (reason: {:?}). Aborted {} transfer.",
+                        e, filename
+                    );
+                    return Err(TeleportError::StdoutIncomplete);
+                }
+            };
+            if answer_keepalive(stream, version, enc, &packet)? {
+                continue;
+            }
+            throttle.take(packet.data.len());
+            let mut chunk = TeleportData::new();
+            match chunk.deserialize(&packet.data) {
+                Ok(()) => {}
+                Err(TeleportError::ChunkChecksum) => {
+                    error!(" => Error: chunk checksum mismatch on the data for stdout");
+                    send_ack(
+                        TeleportInitAck::new(TeleportStatus::HashMismatch),
+                        stream,
+                        version,
+                        enc,
+                        stats,
+                    )?;
+                    return Err(TeleportError::StdoutIncomplete);
+                }
+                Err(e) => return Err(e),
+            }
+
+            if chunk.is_final() {
+                let (filesize, whole_hash) = match unknown_size {
+                    true => match <[u8; 8]>::try_from(chunk.data.as_slice()) {
+                        Ok(h) => (chunk.offset, u64::from_le_bytes(h)),
+                        Err(_) => {
+                            error!("Error: Missing hash at the end of {}!", filename);
+                            return Err(TeleportError::StdoutIncomplete);
+                        }
+                    },
+                    false => (header.filesize, header.whole_hash),
+                };
+                if pos != filesize || chunk.seq != chunks {
+                    error!(
+                        " => Error receiving: {} ({} of {} chunks, {} of {} bytes missing)",
+                        filename,
+                        chunks,
+                        chunk.seq,
+                        filesize.saturating_sub(pos),
+                        filesize
+                    );
+                    send_ack(
+                        TeleportInitAck::new(TeleportStatus::Incomplete),
+                        stream,
+                        version,
+                        enc,
+                        stats,
+                    )?;
+                    return Err(TeleportError::StdoutIncomplete);
+                }
+                stdout.flush()?;
+
+                if hasher.finish() != whole_hash {
+                    error!(" => Error: hash mismatch on the data written to stdout");
+                    send_ack(
+                        TeleportInitAck::new(TeleportStatus::HashMismatch),
+                        stream,
+                        version,
+                        enc,
+                        stats,
+                    )?;
+                    return Err(TeleportError::StdoutIncomplete);
+                }
+                send_ack(
+                    TeleportInitAck::new(TeleportStatus::Proceed),
+                    stream,
+                    version,
+                    enc,
+                    stats,
+                )?;
+
+                let duration = start_time.elapsed();
+                let done = TransferStats {
+                    filename: filename.to_string(),
+                    bytes: filesize,
+                    duration,
+                    mbps: utils::mbps(filesize, duration),
+                    hash: whole_hash,
+                    skipped: false,
+                    used_delta: false,
+                    used_encryption: enc.is_some(),
+                };
+                info!(
+                    " => Received file: {} to stdout (from: {} v{}) ({:.2?} @ {:.3} Mbps)",
+                    filename, ip, &header.version, done.duration, done.mbps
+                );
+                return Ok(Some(done));
+            }
+            chunks += 1;
+
+            if chunk.data_len == 0 {
+                continue;
+            }
+            if compress {
+                chunk.data = compress::unpack(&chunk.data, limit.saturating_sub(chunk.offset))?;
+            }
+
+            // Stdout cannot seek, so the client must send the file in order and stop at its end
+            if chunk.offset != pos || pos.saturating_add(chunk.data.len() as u64) > limit {
+                error!(
+                    "Error: Received data out of order or past the end of {}! Aborted transfer.",
+                    filename
+                );
+                return Err(TeleportError::StdoutIncomplete);
+            }
+            stdout.write_all(&chunk.data)?;
+            hasher.write(&chunk.data);
+            pos += chunk.data.len() as u64;
+        }
+    };
+    match receive() {
+        Err(TeleportError::StdoutIncomplete) => Err(TeleportError::StdoutIncomplete),
+        Err(e) => {
+            error!(
+                "Error receiving {} to stdout (reason: {:?}). Aborted transfer.",
+                filename, e
+            );
+            Err(TeleportError::StdoutIncomplete)
+        }
+        done => done,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_connection(
+    mut stream: TcpStream,
+    recv_list: &Arc<RecvList>,
+    opt: ListenOpt,
+    identity: &StaticSecret,
+    shutdown: &AtomicBool,
+    aborted: &Mutex<Vec<String>>,
+    stats: &Mutex<ServerStats>,
+    transfers: Option<&Transfers>,
+    total_rate: Option<&Mutex<TokenBucket>>,
+    progress: Option<&dyn ProgressReporter>,
+) -> Result<Option<TransferStats>, TeleportError> {
+    let ip = stream.peer_addr()?;
+    events::emit("connect", json!({ "peer": ip.to_string() }));
+
+    // Do not let a stalled client hold this thread forever
+    stream.set_read_timeout(opt.timeout)?;
+    stream.set_write_timeout(opt.timeout)?;
+
+    // Send small control packets right away instead of waiting to fill a segment
+    stream.set_nodelay(!opt.nagle)?;
+
+    let mut enc: Option<TeleportEnc> = None;
+    let mut throttle = Throttle::new(opt.max_bandwidth, total_rate);
+
+    // Receive header first, from a client of any newer version, and answer in the newest
+    // version both speak for the rest of the connection
+    let mut packet = utils::recv_packet(&mut stream, u8::MAX, &None)?;
+    let version = utils::negotiate(packet.version);
+    let first = match TeleportAction::try_from(packet.action) {
+        Ok(a) => a,
+        Err(_) => return unexpected_action(&mut stream, version, &None, packet.action, ip, stats),
+    };
+    match first {
+        TeleportAction::Ping => {
+            let mut ping = TeleportInit::default();
+            ping.deserialize(&packet.data)?;
+            if !TeleportFeatures::Ping.check_u32(ping.features) {
+                return Ok(None);
+            }
+            if !opt.quiet {
+                info!(
+                    "\rPing received from Teleporter v{} at {}",
+                    ping.version, ip
+                );
+            }
+            let pong = TeleportInitAck::new(TeleportStatus::Pong);
+            return utils::send_packet(
+                &mut stream,
+                TeleportAction::PingAck,
+                version,
+                &None,
+                pong.serialize()?,
+            )
+            .map(|_| None);
+        }
+        TeleportAction::Ecdh => {
+            let key = TeleportEnc::new()
+                .and_then(|mut ctx| crypto::genkey(&mut ctx).map(|privkey| (ctx, privkey)));
+            let (mut ctx, privkey) = match key {
+                Ok(k) => k,
+                Err(e) => {
+                    error!(" => Could not generate a session key for {:?}: {}", ip, e);
+                    let resp = TeleportInitAck::new(TeleportStatus::EncryptionError);
+                    return send_ack(resp, &mut stream, version, &None, stats).map(|_| None);
+                }
+            };
+            ctx.deserialize(&packet.data)?;
+            ctx.calc_secret(&privkey);
+            ctx.bind_identity(identity);
+            if let Some(p) = &opt.passphrase {
+                ctx.bind_passphrase(p.as_bytes());
+            }
+            debug!("Server key fingerprint: {}", ctx.fingerprint());
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::EcdhAck,
+                version,
+                &None,
+                ctx.serialize(),
+            )?;
+            enc = Some(ctx);
+            packet = match utils::recv_packet(&mut stream, version, &enc) {
+                Ok(p) => p,
+                Err(TeleportError::DecryptionFailure) => {
+                    // The peer derived a different key, most likely from a different passphrase
+                    warn!(
+                        " => Could not decrypt the request from {:?}, wrong passphrase?",
+                        ip
+                    );
+                    let resp = TeleportInitAck::new(TeleportStatus::EncryptionError);
+                    return send_ack(resp, &mut stream, version, &None, stats).map(|_| None);
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        TeleportAction::Init | TeleportAction::List | TeleportAction::Join
+            if !opt.allow_plaintext || opt.passphrase.is_some() =>
+        {
+            let resp = TeleportInitAck::new(TeleportStatus::RequiresEncryption);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        }
+        TeleportAction::Init | TeleportAction::List | TeleportAction::Join => {}
+        _ => return unexpected_action(&mut stream, version, &None, packet.action, ip, stats),
+    }
+
+    // Extra connections only carry chunks for a transfer offered on another one
+    if packet.action == TeleportAction::Join as u8 {
+        return receive_joined(
+            stream,
+            version,
+            &enc,
+            &packet.data,
+            transfers,
+            shutdown,
+            stats,
+            &mut throttle,
+        );
+    }
+
+    // Receive files until the client is done, only one unless it asked for a batch
+    loop {
+        let start_time = Instant::now();
+        let action = match TeleportAction::try_from(packet.action) {
+            Ok(a @ (TeleportAction::Init | TeleportAction::List)) => a,
+            _ => return unexpected_action(&mut stream, version, &enc, packet.action, ip, stats),
+        };
+
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.deserialize(&packet.data)?;
+
+        let username = String::from_utf8_lossy(&header.username).to_string();
+        debug!("username: {}", &username);
+        let mut filename: String = match String::from_utf8(header.filename.clone()) {
+            Ok(f) => f,
+            Err(_) => {
+                error!(
+                    "Error: filename is not valid UTF-8: {}",
+                    String::from_utf8_lossy(&header.filename)
+                );
+                let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+                return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+            }
+        };
+        let mut features: u32 = header.features;
+
+        // Data of unknown size is streamed in order over this connection only, and is never
+        // compared against, resumed from or decompressed into an unbounded buffer
+        let unknown_size = TeleportFeatures::UnknownSize.check_u32(features);
+        if unknown_size {
+            for f in [
+                TeleportFeatures::Delta,
+                TeleportFeatures::Resume,
+                TeleportFeatures::Compress,
+                TeleportFeatures::Streams,
+            ] {
+                features &= !(f as u32);
+            }
+        }
+
+        let ours = Version::parse(VERSION).expect("Fatal version error");
+        let compatible = header.version.is_compatible(&ours);
+
+        if !compatible {
+            error!(
+                "Error: Version mismatch from: {:?}! Us:{} (wire v{}) Client:{} (wire v{})",
+                ip, VERSION, PROTOCOL_VERSION, header.version, packet.version
+            );
+            let resp = TeleportInitAck::new(TeleportStatus::WrongVersion);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        }
+
+        if !authorized(&opt, &username, &header.token) {
+            warn!(" => Rejected unknown user: {} (from: {:?})", &username, ip);
+            let resp = TeleportInitAck::new(TeleportStatus::UnknownUser);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        }
+
+        if let Some(max) = opt.max_file_size {
+            if header.filesize > max {
+                warn!(
+                    " => Refusing file larger than {} bytes: {} ({} bytes)",
+                    max, &filename, header.filesize
+                );
+                let resp = TeleportInitAck::new(TeleportStatus::TooLarge);
+                return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+            }
+        }
+
+        // Received data goes to stdout, none of the file handling below applies
+        if opt.to_stdout && action == TeleportAction::Init {
+            let result = receive_stdout(
+                &mut stream,
+                version,
+                &enc,
+                &header,
+                features,
+                &filename,
+                &opt,
+                shutdown,
+                stats,
+                &mut throttle,
+                ip,
+            );
+            record_transfer(stats, result.as_ref().ok().and_then(|r| r.as_ref()));
+            return result;
+        }
+
+        if !opt.allow_dangerous_filepath {
+            filename = match confine(&opt.output_dir, &filename) {
+                Some(f) => f,
+                None => {
+                    warn!(
+                        " => Refusing filename outside the server directory: {}",
+                        &filename
+                    );
+                    let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+                    return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+                }
+            };
+        }
+
+        // Windows cannot store reserved device names or some characters, refuse them up front
+        if cfg!(windows) && !opt.allow_dangerous_filepath && !windows_safe_name(&filename) {
+            warn!(" => Refusing filename Windows cannot store: {}", &filename);
+            let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        }
+
+        // Everything is written below the output directory
+        let name = filename.clone();
+        let dest = opt.output_dir.join(&filename);
+        filename = dest
+            .strip_prefix(".")
+            .unwrap_or(&dest)
+            .to_string_lossy()
+            .to_string();
+
+        // Answer directory listings instead of receiving a file
+        if action == TeleportAction::List {
+            return send_list(&mut stream, version, &enc, &filename, &opt).map(|_| None);
+        }
+
+        // Directories and symlinks carry no data, create them and answer right away
+        let created = match FileType::try_from(header.file_type)? {
+            FileType::Regular => None,
+            FileType::Dir => {
+                let chmod = clamp_mode(header.chmod, opt.umask, true, &filename);
+                Some(create_dir(&filename, chmod))
+            }
+            FileType::Symlink => Some(create_symlink(
+                &filename,
+                &name,
+                &header.link_target,
+                features,
+                &opt,
+            )),
+        };
+        if let Some(status) = created {
+            let batch = TeleportFeatures::Batch.check_u32(features);
+            let mut resp = TeleportInitAck::new(status);
+            TeleportFeatures::NewFile.add(&mut resp.features)?;
+            TeleportFeatures::FileType.add(&mut resp.features)?;
+            if batch {
+                TeleportFeatures::Batch.add(&mut resp.features)?;
+            }
+            send_ack(resp, &mut stream, version, &enc, stats)?;
+
+            let outcome = (status == TeleportStatus::Proceed).then(|| TransferStats {
+                filename: filename.clone(),
+                bytes: 0,
+                duration: start_time.elapsed(),
+                mbps: 0.0,
+                hash: 0,
+                skipped: false,
+                used_delta: false,
+                used_encryption: enc.is_some(),
+            });
+            if !batch || outcome.is_none() {
+                return Ok(outcome);
+            }
+            packet = match next_in_batch(&mut stream, version, &enc)? {
+                Some(p) => p,
+                None => return Ok(outcome),
+            };
+            continue;
+        }
+
+        // Test if overwrite is false and file exists, a renamed file gets a free name below
+        let rename = TeleportFeatures::Rename.check_u32(features);
+        if !rename
+            && !TeleportFeatures::Overwrite.check_u32(features)
+            && Path::new(&filename).exists()
+        {
+            warn!(" => Refusing to overwrite file: {}", &filename);
+            let resp = TeleportInitAck::new(TeleportStatus::NoOverwrite);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        }
+
+        // Create recursive dirs
+        let path = match Path::new(&filename).parent() {
+            Some(p) => p.to_path_buf(),
+            None => {
+                error!(
+                    "Error: unable to parse the path and filename: {}",
+                    &filename
+                );
+                let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+                return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+            }
+        };
+
+        if fs::create_dir_all(&path).is_err() {
+            error!("Error: unable to create directories: {}", &path.display());
+            let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        };
+
+        // Claim the new name right away so concurrent transfers of the same file never pick the
+        // same one, the empty placeholder is removed again if this transfer does not finish
+        let mut reserved = None;
+        if rename {
+            filename = match reserve_name(&filename) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Error: unable to create file: {} ({})", &filename, e);
+                    let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+                    return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+                }
+            };
+            reserved = Some(TempFile {
+                path: filename.clone(),
+                keep: false,
+            });
+        }
+
+        // Write into a ".part" file if the client may resume the transfer later, otherwise
+        // into a temp file that only replaces the destination once the transfer is verified
+        let resume = TeleportFeatures::Resume.check_u32(features);
+        let target = if resume {
+            filename.clone() + ".part"
+        } else {
+            temp_name(&filename)
+        };
+        // Never write through a symlink planted at the destination, it could point anywhere
+        if !opt.follow_symlinks {
+            let mut paths = vec![filename.clone(), target.clone()];
+            if TeleportFeatures::Backup.check_u32(features) {
+                paths.extend(backup_names(&filename, opt.backup_count));
+            }
+            if let Some(link) = paths.iter().find(|p| Path::new(p).is_symlink()) {
+                warn!(" => Refusing to write through symlink: {}", link);
+                let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+                return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+            }
+        }
+
+        // Refuse files that would not fit, always leaving the configured margin free
+        let partial = match resume {
+            true => fs::metadata(&target).map(|m| m.len()).unwrap_or(0),
+            false => 0,
+        };
+        let needed = header
+            .filesize
+            .saturating_sub(partial)
+            .saturating_add(opt.min_free);
+        let dir = match path.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => &path,
+        };
+        match fs2::available_space(dir) {
+            Ok(free) if free < needed => {
+                warn!(
+                    " => Not enough space for: {} (needs {} bytes, {} free)",
+                    &filename, needed, free
+                );
+                let resp = TeleportInitAck::new(TeleportStatus::NoSpace);
+                return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+            }
+            Err(e) => debug!("Unable to check free space in {}: {}", dir.display(), e),
+            _ => (),
+        }
+
+        let _temp = TempFile {
+            path: target.clone(),
+            keep: resume || opt.keep_partial,
+        };
+
+        // Start from a copy of the existing file so delta transfers can patch it
+        if !resume && Path::new(&filename).exists() {
+            if let Err(e) = fs::copy(&filename, &target) {
+                error!("Error: unable to copy {} to temp file: {}", &filename, e);
+                let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+                return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+            }
+        }
+
+        // Open file for writing
+        let file = match write_options(opt.follow_symlinks).open(&target) {
+            Ok(f) => f,
+            Err(_) => match write_options(opt.follow_symlinks)
+                .create(true)
+                .truncate(true)
+                .open(&target)
+            {
+                Ok(f) => f,
+                Err(_) => {
+                    error!("Error: unable to create file: {}", &filename);
+                    let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+                    return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+                }
+            },
+        };
+        let meta = file.metadata()?;
+        let mut perms = meta.permissions();
+        let chmod = clamp_mode(header.chmod, opt.umask, false, &filename);
+        utils::set_file_mode(&mut perms, chmod);
+        if fs::set_permissions(&target, perms).is_err() {
+            error!("Could not set file permissions");
+            let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+            return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+        };
+
+        // Send ready for data ACK
+        let mut resp = TeleportInitAck::new(TeleportStatus::Proceed);
+        TeleportFeatures::NewFile.add(&mut resp.features)?;
+
+        // Agree to decompress if the client asked for compression
+        let compress = TeleportFeatures::Compress.check_u32(features);
+        if compress {
+            TeleportFeatures::Compress.add(&mut resp.features)?;
+        }
+
+        // Hash with BLAKE3 if the client asked for it
+        let algo = HashAlgorithm::from_features(features);
+        if algo == HashAlgorithm::Blake3 {
+            TeleportFeatures::StrongHash.add(&mut resp.features)?;
+        }
+
+        // Agree to receive data whose size and hash only arrive with the final chunk
+        if unknown_size {
+            TeleportFeatures::UnknownSize.add(&mut resp.features)?;
+        }
+
+        // Verify each chunk's checksum if asked to, encrypted chunks are already authenticated
+        if TeleportFeatures::ChunkChecksum.check_u32(features) && enc.is_none() {
+            TeleportFeatures::ChunkChecksum.add(&mut resp.features)?;
+        }
+
+        // Keep the connection open for the next file if the client asked for it
+        let batch = TeleportFeatures::Batch.check_u32(features);
+        if batch {
+            TeleportFeatures::Batch.add(&mut resp.features)?;
+        }
+
+        // Add file to list
+        let mut recv_data = recv_list.lock().expect("Fatal error locking recv_list");
+        let total = match unknown_size {
+            true => None,
+            false => Some(header.filesize),
+        };
+        recv_data.insert(filename.clone(), (0, total));
+        if !opt.quiet {
+            print_list(&recv_data);
+        }
+        drop(recv_data);
+
+        let mut resume_offset: u64 = 0;
+        let mut original: Option<File> = None;
+        if resume {
+            // A partial file larger than the new file cannot be resumed
+            if meta.len() > header.filesize {
+                file.set_len(0)?;
+            }
+
+            // Report the partial file length and hash so the client can validate it
+            TeleportFeatures::Resume.add(&mut resp.features)?;
+            resume_offset = file.metadata()?.len();
+            resp.resume_offset = Some(resume_offset);
+            resp.resume_hash = Some(TeleportDelta::delta_hash(&file, algo)?.hash);
+        } else {
+            // If overwrite and file exists, build TeleportDelta. An empty file has no chunks to
+            // compare, the client just sends the completion marker
+            file.set_len(header.filesize)?;
+            if meta.len() > 0 {
+                TeleportFeatures::Overwrite.add(&mut resp.features)?;
+                if TeleportFeatures::Delta.check_u32(features) && header.filesize > 0 {
+                    TeleportFeatures::Delta.add(&mut resp.features)?;
+                    match TeleportFeatures::RollingDelta.check_u32(features) {
+                        // A rolling delta describes the untouched file the copied chunks come from
+                        true => {
+                            let source = File::open(&filename)?;
+                            let len = source.metadata()?.len();
+                            let chunk = delta_chunk_size(&opt, len);
+                            resp.delta =
+                                TeleportDelta::delta_hash_chunks(&source, len, chunk, algo)
+                                    .and_then(|d| d.with_weak_hashes(&source))
+                                    .ok();
+                            if resp.delta.is_some() {
+                                TeleportFeatures::RollingDelta.add(&mut resp.features)?;
+                                original = Some(source);
+                            }
+                        }
+                        false => {
+                            let chunk = delta_chunk_size(&opt, header.filesize);
+                            resp.delta = TeleportDelta::delta_hash_chunks(
+                                &file,
+                                header.filesize,
+                                chunk,
+                                algo,
+                            )
+                            .ok();
+                        }
+                    }
+                }
+            }
+        }
+
+        // Hand out an id extra connections can join the transfer with to send chunks in parallel,
+        // unless the chunks of a rolling delta are copied from the original file
+        let parallel = match transfers {
+            Some(t) if TeleportFeatures::Streams.check_u32(features) && original.is_none() => {
+                let mut id = [0u8; STREAM_ID_LEN];
+                if let Err(e) = crypto::random_bytes(&mut id) {
+                    error!(" => Could not generate a stream id for {:?}: {}", ip, e);
+                    let resp = TeleportInitAck::new(TeleportStatus::EncryptionError);
+                    return send_ack(resp, &mut stream, version, &enc, stats).map(|_| None);
+                }
+                TeleportFeatures::Streams.add(&mut resp.features)?;
+                resp.stream_id = Some(id);
+                let shared = SharedTransfer {
+                    filename: filename.clone(),
+                    file: file.try_clone()?,
+                    filesize: header.filesize,
+                    compress,
+                    state: Mutex::new(SharedState::default()),
+                    finished: Condvar::new(),
+                };
+                Some(ParallelTransfer::new(t, id, shared))
+            }
+            _ => None,
+        };
+
+        let used_delta = resp.delta.is_some();
+        let copy_limit = resp.delta.as_ref().map_or(0, |d| d.chunk_size);
+
+        // Data the client will not resend is already in place: the resumed prefix, or the
+        // existing copy a delta is patched onto
+        let mut written = WrittenRanges::default();
+        if resume {
+            written.insert(0, resume_offset);
+        } else if used_delta {
+            written.insert(0, meta.len().min(header.filesize));
+        }
+        update_list(&filename, written.total(), recv_list);
+        match send_ack(resp, &mut stream, version, &enc, stats) {
+            Ok(_) => (),
+            Err(e) => {
+                warn!(
+                    "Connection closed (reason: {:?}). Aborted {} transfer.",
+                    e, &filename
+                );
+                rm_filename_from_list(&filename, recv_list);
+                return Ok(None);
+            }
+        }
+
+        // Receive file data, a stream of unknown size up to any --max-file-size quota
+        let limit = match unknown_size {
+            true => opt.max_file_size.unwrap_or(u64::MAX),
+            false => header.filesize,
+        };
+        let mut received: u64 = 0;
+        let mut chunks: u64 = 0;
+        let mut joined_chunks: u64 = 0;
+        let mut outcome: Option<TransferStats> = None;
+        let mut failure = "transfer aborted";
+        // Chunks usually arrive in order, so only seek when they do not follow the last write
+        let mut writer = BufWriter::with_capacity(utils::FILE_BUFFER, file);
+        let mut pos: Option<u64> = None;
+        loop {
+            // Stop receiving if the server is shutting down, the temp file is cleaned up on exit
+            if shutdown.load(Ordering::SeqCst) {
+                warn!(" => Server shutting down. Aborted {} transfer.", &filename);
+                aborted
+                    .lock()
+                    .expect("Fatal error locking aborted")
+                    .push(filename.clone());
+                break;
+            }
+
+            // Read from network connection
+            let packet = match utils::recv_packet(&mut stream, version, &enc) {
+                Ok(s) => s,
+                // This connection stays quiet while joined ones send the chunks
+                Err(e)
+                    if is_timeout(&e)
+                        && matches!(&parallel, Some(p) if p.progressed(&mut joined_chunks)) =>
+                {
+                    continue;
+                }
+                Err(e) if is_timeout(&e) => {
+                    warn!(" => Connection timed out. Aborted {} transfer.", &filename);
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "Connection closed (reason: {:?}). Aborted {} transfer.",
+                        e, &filename
+                    );
+                    break;
+                }
+            };
+            match answer_keepalive(&mut stream, version, &enc, &packet) {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(e) => {
+                    warn!(
+                        "Connection closed (reason: {:?}). Aborted {} transfer.",
+                        e, &filename
+                    );
+                    break;
+                }
+            }
+            throttle.take(packet.data.len());
+            let mut chunk = TeleportData::new();
+            match chunk.deserialize(&packet.data) {
+                Ok(()) => {}
+                Err(TeleportError::ChunkChecksum) => {
+                    error!(
+                        " => Error: chunk checksum mismatch at offset {}. Aborted {} transfer.",
+                        chunk.offset, &filename
+                    );
+                    failure = "chunk checksum mismatch";
+                    let resp = TeleportInitAck::new(TeleportStatus::HashMismatch);
+                    if let Err(e) = send_ack(resp, &mut stream, version, &enc, stats) {
+                        warn!("Connection closed (reason: {:?}).", e);
+                    }
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "Invalid data received (reason: {:?}). Aborted {} transfer.",
+                        e, &filename
+                    );
+                    break;
+                }
+            }
+
+            if chunk.is_final() {
+                // Count what the joined connections wrote once they are all done
+                if let Some(p) = &parallel {
+                    let state = p.finish(shutdown);
+                    written.extend(&state.written);
+                    chunks += state.chunks;
+                }
+
+                // The final chunk of a stream carries its size and whole file hash
+                if unknown_size {
+                    header.filesize = chunk.offset;
+                    header.whole_hash = match <[u8; 8]>::try_from(chunk.data.as_slice()) {
+                        Ok(h) => u64::from_le_bytes(h),
+                        Err(_) => {
+                            error!("Error: Missing hash at the end of {}!", &filename);
+                            break;
+                        }
+                    };
+                }
+
+                if written.covers(header.filesize) && chunk.seq == chunks {
+                    if let Err(e) = writer.flush() {
+                        error!(
+                            "Error writing to file: {} (reason: {}). Out of space?",
+                            &filename, e
+                        );
+                        break;
+                    }
+
+                    // Verify the received file against the hash sent by the client
+                    let file = writer.get_ref();
+                    file.set_len(header.filesize)?;
+                    let hash = TeleportDelta::stream_hash(file, algo)?;
+                    let status = if hash != header.whole_hash {
+                        error!(" => Error: hash mismatch, removing: {}", &filename);
+                        failure = "hash mismatch";
+                        fs::remove_file(&target)?;
+                        TeleportStatus::HashMismatch
+                    } else {
+                        finish_transfer(&filename, &target, features, opt.backup_count)?;
+                        if let Some(r) = &mut reserved {
+                            r.keep = true;
+                        }
+                        if TeleportFeatures::PreserveTimes.check_u32(features) {
+                            let mtime = FileTime::from_unix_time(header.mtime as i64, 0);
+                            if let Err(e) = filetime::set_file_mtime(&filename, mtime) {
+                                warn!(
+                                    " => Could not set modification time on {}: {}",
+                                    &filename, e
+                                );
+                            }
+                        }
+                        if TeleportFeatures::PreserveOwner.check_u32(features) {
+                            let path = Path::new(&filename);
+                            if let Err(e) = utils::set_owner(path, header.uid, header.gid) {
+                                warn!(
+                                    " => Could not set owner {}:{} on {}: {}",
+                                    header.uid, header.gid, &filename, e
+                                );
+                            }
+                        }
+                        if TeleportFeatures::PreserveXattr.check_u32(features) {
+                            apply_xattrs(Path::new(&filename), &header.xattrs);
+                        }
+                        if let Some(m) = &opt.verify_manifest {
+                            let sent = String::from_utf8_lossy(&header.filename);
+                            check_manifest(m, &sent, file, &filename);
+                        }
+                        let duration = start_time.elapsed();
+                        let done = TransferStats {
+                            filename: filename.clone(),
+                            bytes: header.filesize,
+                            duration,
+                            mbps: utils::mbps(header.filesize, duration),
+                            hash: header.whole_hash,
+                            skipped: received == 0 && header.filesize > 0,
+                            used_delta,
+                            used_encryption: enc.is_some(),
+                        };
+                        let resolved = fs::canonicalize(&filename)
+                            .unwrap_or_else(|_| PathBuf::from(&filename));
+                        info!(
+                            " => Received file: {} (from: {} v{}) ({:.2?} @ {:.3} Mbps)",
+                            resolved.display(),
+                            ip,
+                            &header.version,
+                            done.duration,
+                            done.mbps
+                        );
+                        events::emit(
+                            "complete",
+                            json!({
+                                "filename": filename,
+                                "path": resolved.display().to_string(),
+                                "peer": ip.to_string(),
+                                "bytes": done.bytes,
+                                "seconds": done.duration.as_secs_f64(),
+                                "mbps": done.mbps,
+                                "skipped": done.skipped,
+                                "delta": done.used_delta,
+                                "encrypted": done.used_encryption,
+                            }),
+                        );
+                        outcome = Some(done);
+                        TeleportStatus::Proceed
+                    };
+
+                    // Let the client know if the file was accepted
+                    let resp = TeleportInitAck::new(status);
+                    if let Err(e) = send_ack(resp, &mut stream, version, &enc, stats) {
+                        warn!("Connection closed (reason: {:?}).", e);
+                    }
+                } else {
+                    // The client finished sending before the declared filesize was covered
+                    error!(
+                        " => Error receiving: {} ({} of {} chunks, {} of {} bytes missing)",
+                        &filename,
+                        chunks,
+                        chunk.seq,
+                        header.filesize.saturating_sub(written.total()),
+                        header.filesize
+                    );
+                    failure = "incomplete transfer";
+                    let resp = TeleportInitAck::new(TeleportStatus::Incomplete);
+                    if let Err(e) = send_ack(resp, &mut stream, version, &enc, stats) {
+                        warn!("Connection closed (reason: {:?}).", e);
+                    }
+                }
+                break;
+            }
+            chunks += 1;
+
+            // An empty chunk carries nothing to write
+            if chunk.data_len == 0 {
+                continue;
+            }
+
+            // Fill in a copied chunk from the original file, or decompress the chunk, never past
+            // the end of the declared filesize
+            let copied = chunk.is_copy();
+            if copied {
+                chunk.data = match copy_chunk(original.as_ref(), &chunk, copy_limit) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        error!(
+                            "Error: Invalid copy in {} (reason: {:?}). Aborted transfer.",
+                            &filename, e
+                        );
+                        break;
+                    }
+                };
+                chunk.data_len = u32::try_from(chunk.data.len())?;
+            } else if compress {
+                let max = header.filesize.saturating_sub(chunk.offset);
+                (chunk.data, chunk.data_len) = match compress::unpack(&chunk.data, max)
+                    .and_then(|d| Ok((u32::try_from(d.len())?, d)))
+                {
+                    Ok((len, d)) => (d, len),
+                    Err(e) => {
+                        error!(
+                            "Error: Invalid compressed data in {} (reason: {:?}). Aborted transfer.",
+                            &filename, e
+                        );
+                        failure = "invalid compressed data";
+                        break;
+                    }
+                };
+            }
+
+            // The client restarted from an earlier offset, discard the stale partial data
+            if resume && chunk.offset < resume_offset {
+                if let Err(e) = writer
+                    .flush()
+                    .and_then(|_| writer.get_ref().set_len(chunk.offset))
+                {
+                    error!(
+                        "Error truncating file: {} (reason: {}). Aborted transfer.",
+                        &filename, e
+                    );
+                    break;
+                }
+                written.truncate(chunk.offset);
+                resume_offset = chunk.offset;
+            }
+
+            // Never write past the declared filesize, which is within any --max-file-size quota
+            if chunk.offset.saturating_add(chunk.data_len as u64) > limit {
+                error!(
+                    "Error: Received data past the end of {}! Aborted transfer.",
+                    &filename
+                );
+                break;
+            }
+
+            // Seek to offset
+            if pos != Some(chunk.offset) {
+                if let Err(e) = writer.seek(SeekFrom::Start(chunk.offset)) {
+                    error!(
+                        "Error seeking in file: {} (reason: {}). Aborted transfer.",
+                        &filename, e
+                    );
+                    break;
+                }
+            }
+
+            // Write received data to file
+            if let Err(e) = writer.write_all(&chunk.data) {
+                error!(
+                    "Error writing to file: {} (read: {}, reason: {}). Out of space?",
+                    &filename, chunk.data_len, e
+                );
+                break;
+            }
+
+            let end = chunk.offset + chunk.data_len as u64;
+            written.insert(chunk.offset, end);
+            if !copied {
+                received += chunk.data_len as u64;
+            }
+            pos = Some(end);
+
+            update_list(&filename, written.total(), recv_list);
+            if let Some(p) = progress {
+                p.on_chunk(written.total(), header.filesize);
+            }
+        }
+
+        rm_filename_from_list(&filename, recv_list);
+        record_transfer(stats, outcome.as_ref());
+        if outcome.is_none() {
+            events::emit(
+                "error",
+                json!({ "filename": filename, "peer": ip.to_string(), "message": failure }),
+            );
+        }
+
+        // In batch mode the client either announces the next file or ends the connection
+        if !batch || outcome.is_none() {
+            return Ok(outcome);
+        }
+        packet = match next_in_batch(&mut stream, version, &enc)? {
+            Some(p) => p,
+            None => return Ok(outcome),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::parser::ValueSource;
+    use clap::Parser;
+    use rand::rngs::OsRng;
+
+    /// Receive one file on a loopback port, with options built on the server thread
+    fn spawn_receiver(
+        opts: impl FnOnce() -> ReceiveOptions<'static> + Send + 'static,
+    ) -> (
+        SocketAddr,
+        thread::JoinHandle<Result<TransferStats, TeleportError>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let addr = listener.local_addr().expect("Test should never fail");
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Test should never fail");
+            receive_file(stream, opts())
+        });
+        (addr, server)
+    }
+
+    /// Offer a file without ECDH and return the server's answer
+    fn offer_raw(stream: &mut TcpStream, header: &TeleportInit) -> TeleportInitAck {
+        let init = header.serialize().expect("Test should never fail");
+        utils::send_packet(stream, TeleportAction::Init, PROTOCOL_VERSION, &None, init)
+            .expect("Test should never fail");
+        let packet =
+            utils::recv_packet(stream, PROTOCOL_VERSION, &None).expect("Test should never fail");
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        ack
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let addr = listener.local_addr().expect("Test should never fail");
+        let opt = ListenOpt::parse_from(["listen", "--max-connections", "1"]);
+        let identity = Arc::new(StaticSecret::new(OsRng));
+        let stats = Arc::new(Mutex::new(ServerStats::default()));
+        let totals = Arc::clone(&stats);
+        thread::spawn(move || {
+            serve(
+                listener,
+                opt,
+                identity,
+                Arc::new(AtomicBool::new(false)),
+                totals,
+            )
+        });
+
+        // The first client holds the only slot while the server waits for its header
+        let _first = TcpStream::connect(addr).expect("Test should never fail");
+
+        // Every client over the cap is told the server is busy
+        for _ in 0..3 {
+            let mut extra = TcpStream::connect(addr).expect("Test should never fail");
+            let packet = utils::recv_packet(&mut extra, PROTOCOL_VERSION, &None)
+                .expect("Test should never fail");
+            assert_eq!(packet.action, TeleportAction::InitAck as u8);
+
+            let mut ack = TeleportInitAck::default();
+            ack.deserialize(&packet.data)
+                .expect("Test should never fail");
+            assert_eq!(ack.status, TeleportStatus::ServerBusy as u8);
+        }
+
+        // Every refusal was counted
+        let stats = stats.lock().expect("Test should never fail");
+        assert_eq!(stats.refused.get(&TeleportStatus::ServerBusy), Some(&3));
+        assert_eq!(stats.files, 0);
+    }
+
+    #[test]
+    fn test_idle_line() {
+        let line = idle_line(Duration::from_secs(3725), 1);
+        assert_eq!(
+            line.trim_end(),
+            "Listening... (up 1:02:05, 1 file received)"
+        );
+        assert_eq!(line.len(), 60);
+        let line = idle_line(Duration::from_millis(59_900), 0);
+        assert_eq!(line.trim_end(), "Listening... (up 00:59, 0 files received)");
+    }
+
+    #[test]
+    fn test_list_line() {
+        let mut list = BTreeMap::new();
+        assert_eq!(list_line(&list).trim_end(), "Listening...");
+
+        list.insert("b.bin".to_string(), (250, Some(1000)));
+        list.insert("a.bin".to_string(), (0, Some(0)));
+        let line = list_line(&list);
+        assert_eq!(line.trim_end(), "Receiving: a.bin 100%, b.bin 25%");
+        assert_eq!(line.len(), 60);
+
+        list.insert("c.log".to_string(), (4096, None));
+        list.insert("d.bin".to_string(), (1, Some(3)));
+        list.insert("e.bin".to_string(), (2, Some(3)));
+        assert_eq!(
+            list_line(&list).trim_end(),
+            "Receiving: a.bin 100%, b.bin 25%, c.log 4.000K, +2 more"
+        );
+    }
+
+    #[test]
+    fn test_confine() {
+        let dir = std::env::temp_dir().join(format!("teleporter-confine-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).expect("Test should never fail");
+        fs::create_dir_all(dir.join("outside")).expect("Test should never fail");
+
+        assert_eq!(confine(&root, "a/b.txt"), Some("a/b.txt".to_string()));
+        assert_eq!(confine(&root, "./sub/b.txt"), Some("sub/b.txt".to_string()));
+        // Collapses to "../" if the dots were only stripped once
+        assert_eq!(
+            confine(&root, "....//b.txt"),
+            Some("..../b.txt".to_string())
+        );
+        assert_eq!(confine(&root, "../b.txt"), None);
+        assert_eq!(confine(&root, "sub/../../b.txt"), None);
+        // Absolute paths land below the root
+        assert_eq!(
+            confine(&root, "/etc/passwd"),
+            Some("etc/passwd".to_string())
+        );
+        assert_eq!(
+            confine(&root, "//etc/passwd"),
+            Some("etc/passwd".to_string())
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            symlink(dir.join("outside"), root.join("escape")).expect("Test should never fail");
+            symlink(root.join("sub"), root.join("inside")).expect("Test should never fail");
+            assert_eq!(confine(&root, "escape/b.txt"), None);
+            assert_eq!(confine(&root, "escape"), None);
+            assert_eq!(
+                confine(&root, "inside/b.txt"),
+                Some("inside/b.txt".to_string())
+            );
+        }
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_windows_safe_name() {
+        assert!(windows_safe_name("photos/2023/a.jpg"));
+        assert!(windows_safe_name("./console.log"));
+        assert!(windows_safe_name("com10.txt"));
+        assert!(!windows_safe_name("CON"));
+        assert!(!windows_safe_name("dir/aux.txt"));
+        assert!(!windows_safe_name("Lpt1.tar.gz"));
+        assert!(!windows_safe_name("nul .txt"));
+        assert!(!windows_safe_name("c:/windows/a.txt"));
+        assert!(!windows_safe_name("file:stream"));
+        assert!(!windows_safe_name("what?.txt"));
+        assert!(!windows_safe_name("trailing."));
+        assert!(!windows_safe_name("dir\\trailing /a"));
+    }
+
+    #[test]
+    fn test_send_receive_file() {
+        let dir = std::env::temp_dir().join(format!("teleporter-api-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            ..Default::default()
+        });
+
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = crate::send::SendOptions {
+            filename: Some(dest.to_string_lossy().to_string()),
+            username: "test".to_string(),
+            ..Default::default()
+        };
+        let sent = crate::send::send_file(stream, &src, opts).expect("Test should never fail");
+        let received = server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+
+        assert_eq!(sent.bytes, data.len() as u64);
+        assert_eq!(sent.hash, received.hash);
+        assert!(!sent.skipped);
+        assert!(sent.used_encryption && received.used_encryption);
+        assert_eq!(received.filename, dest.to_string_lossy());
+        assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_max_bandwidth() {
+        let dir = std::env::temp_dir().join(format!("teleporter-rate-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        let data: Vec<u8> = (0..400 * 1024u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            max_bandwidth: Some(1024 * 1024),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = crate::send::SendOptions {
+            filename: Some(dest.to_string_lossy().to_string()),
+            username: "test".to_string(),
+            ..Default::default()
+        };
+        crate::send::send_file(stream, &src, opts).expect("Test should never fail");
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+
+        // 400K at 1M/s, less the 100ms burst allowance, takes at least ~290ms
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(elapsed >= 0.28, "elapsed: {elapsed}");
+        assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_throttle_total() {
+        let total = Mutex::new(TokenBucket::new(1024 * 1024));
+        let start = Instant::now();
+
+        // Two connections sharing one total limit of 1M/s each read 200K
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let mut throttle = Throttle::new(None, Some(&total));
+                    for _ in 0..20 {
+                        throttle.take(10 * 1024);
+                    }
+                });
+            }
+        });
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(elapsed >= 0.28, "elapsed: {elapsed}");
+    }
+
+    #[test]
+    fn test_concurrent_rename() {
+        let dir = std::env::temp_dir().join(format!("teleporter-rename-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).expect("Test should never fail");
+        fs::write(&dest, b"original").expect("Test should never fail");
+
+        let transfers: Vec<_> = (0..2)
+            .map(|_| {
+                let (addr, server) = spawn_receiver(move || ReceiveOptions {
+                    allow_dangerous_filepath: true,
+                    ..Default::default()
+                });
+                let (src, dest) = (src.clone(), dest.clone());
+                let client = thread::spawn(move || {
+                    let stream = TcpStream::connect(addr).expect("Test should never fail");
+                    let opts = crate::send::SendOptions {
+                        filename: Some(dest.to_string_lossy().to_string()),
+                        username: "test".to_string(),
+                        filename_append: true,
+                        ..Default::default()
+                    };
+                    crate::send::send_file(stream, &src, opts)
+                });
+                (server, client)
+            })
+            .collect();
+
+        let mut names = Vec::new();
+        for (server, client) in transfers {
+            client
+                .join()
+                .expect("Test should never fail")
+                .expect("Test should never fail");
+            let received = server
+                .join()
+                .expect("Test should never fail")
+                .expect("Test should never fail");
+            assert_eq!(
+                fs::read(&received.filename).expect("Test should never fail"),
+                data
+            );
+            names.push(received.filename);
+        }
+        names.sort();
+        let dest = dest.to_string_lossy();
+        assert_eq!(names, vec![format!("{dest}.1"), format!("{dest}.2")]);
+        assert_eq!(
+            fs::read(&*dest).expect("Test should never fail"),
+            b"original"
+        );
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_list_refused() {
+        let (addr, server) = spawn_receiver(ReceiveOptions::default);
+
+        // Listing is off unless the server allows it, and the refusal fails the command
+        let opt = crate::ListOpt::parse_from([
+            "list",
+            "-u",
+            "test",
+            "-d",
+            "127.0.0.1",
+            "-p",
+            &addr.port().to_string(),
+        ]);
+        assert!(matches!(
+            crate::list::run(opt),
+            Err(TeleportError::Refused(TeleportStatus::NoPermission))
+        ));
+        assert!(server.join().expect("Test should never fail").is_err());
+    }
+
+    #[test]
+    fn test_list_plaintext_env_passphrase() {
+        let (addr, server) = spawn_receiver(|| ReceiveOptions {
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        // A passphrase flag contradicts --plaintext, one from the environment is ignored
+        let opt = crate::ListOpt::parse_from([
+            "list",
+            "-u",
+            "test",
+            "-d",
+            "127.0.0.1",
+            "-p",
+            &addr.port().to_string(),
+            "--plaintext",
+            "--passphrase",
+            "secret",
+        ]);
+        assert!(matches!(
+            crate::list::run(opt.clone()),
+            Err(TeleportError::PassphrasePlaintext)
+        ));
+        assert!(matches!(
+            crate::list::run_with_passphrase_source(opt, Some(ValueSource::EnvVariable)),
+            Err(TeleportError::Refused(TeleportStatus::NoPermission))
+        ));
+        assert!(server.join().expect("Test should never fail").is_err());
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        let dir = std::env::temp_dir().join(format!("teleporter-user-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        fs::write(&src, b"data").expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allowed_users: Some(vec!["alice".to_string()]),
+            ..Default::default()
+        });
+
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = crate::send::SendOptions {
+            filename: Some(dir.join("dest.bin").to_string_lossy().to_string()),
+            username: "mallory".to_string(),
+            ..Default::default()
+        };
+        let sent = crate::send::send_file(stream, &src, opts);
+        assert!(matches!(
+            sent,
+            Err(TeleportError::Refused(TeleportStatus::UnknownUser))
+        ));
+        assert!(server.join().expect("Test should never fail").is_err());
+        assert!(!dir.join("dest.bin").exists());
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_plaintext_requires_opt_in() {
+        let dir = std::env::temp_dir().join(format!("teleporter-plain-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        fs::write(&src, b"plaintext data").expect("Test should never fail");
+
+        for allow_plaintext in [false, true] {
+            let dest = dir.join(format!("dest-{allow_plaintext}.bin"));
+            let (addr, server) = spawn_receiver(move || ReceiveOptions {
+                allow_dangerous_filepath: true,
+                allow_plaintext,
+                ..Default::default()
+            });
+
+            // A client that skips the ECDH handshake
+            let stream = TcpStream::connect(addr).expect("Test should never fail");
+            let opts = crate::send::SendOptions {
+                filename: Some(dest.to_string_lossy().to_string()),
+                username: "test".to_string(),
+                plaintext: true,
+                ..Default::default()
+            };
+            let sent = crate::send::send_file(stream, &src, opts);
+            let received = server.join().expect("Test should never fail");
+            if allow_plaintext {
+                let sent = sent.expect("Test should never fail");
+                assert!(!sent.used_encryption);
+                assert_eq!(received.expect("Test should never fail").hash, sent.hash);
+            } else {
+                assert!(matches!(
+                    sent,
+                    Err(TeleportError::Refused(TeleportStatus::RequiresEncryption))
+                ));
+                assert!(received.is_err());
+                assert!(!dest.exists());
+            }
+        }
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_unexpected_action() {
+        // A first packet no client sends, one with an unknown action and a Ping after ECDH
+        for case in 0..3 {
+            let (addr, server) = spawn_receiver(move || ReceiveOptions {
+                allow_plaintext: true,
+                ..Default::default()
+            });
+
+            let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+            let enc = match case {
+                0 => {
+                    utils::send_packet(
+                        &mut stream,
+                        TeleportAction::Data,
+                        PROTOCOL_VERSION,
+                        &None,
+                        vec![0; 8],
+                    )
+                    .expect("Test should never fail");
+                    None
+                }
+                1 => {
+                    let mut packet = TeleportHeader::new(TeleportAction::Init);
+                    packet.action = 0x30;
+                    packet
+                        .serialize_into(&mut stream)
+                        .expect("Test should never fail");
+                    None
+                }
+                _ => {
+                    let enc = crate::send::key_exchange(
+                        &mut stream,
+                        &mut PROTOCOL_VERSION.clone(),
+                        None,
+                        None,
+                    )
+                    .map(Some)
+                    .expect("Test should never fail");
+                    utils::send_packet(
+                        &mut stream,
+                        TeleportAction::Ping,
+                        PROTOCOL_VERSION,
+                        &enc,
+                        vec![],
+                    )
+                    .expect("Test should never fail");
+                    enc
+                }
+            };
+
+            let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &enc)
+                .expect("Test should never fail");
+            let mut ack = TeleportInitAck::new(TeleportStatus::Proceed);
+            ack.deserialize(&packet.data)
+                .expect("Test should never fail");
+            assert_eq!(ack.status, TeleportStatus::UnknownAction as u8);
+            assert!(server.join().expect("Test should never fail").is_err());
+        }
+    }
+
+    #[test]
+    fn test_chunk_checksums() {
+        let dir = std::env::temp_dir().join(format!("teleporter-csum-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        fs::write(&src, vec![7; 10_000]).expect("Test should never fail");
+
+        for corrupt in [false, true] {
+            let dest = dir.join(format!("dest-{corrupt}.bin"));
+            let (addr, server) = spawn_receiver(move || ReceiveOptions {
+                allow_dangerous_filepath: true,
+                allow_plaintext: true,
+                ..Default::default()
+            });
+
+            let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+            if !corrupt {
+                let opts = crate::send::SendOptions {
+                    filename: Some(dest.to_string_lossy().to_string()),
+                    username: "test".to_string(),
+                    plaintext: true,
+                    chunk_checksums: true,
+                    ..Default::default()
+                };
+                crate::send::send_file(stream, &src, opts).expect("Test should never fail");
+                server
+                    .join()
+                    .expect("Test should never fail")
+                    .expect("Test should never fail");
+                assert_eq!(
+                    fs::read(&dest).expect("Test should never fail"),
+                    vec![7; 10_000]
+                );
+                continue;
+            }
+
+            // A chunk whose data changed after its checksum was computed
+            let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+            header.filesize = 4;
+            header.features |= TeleportFeatures::ChunkChecksum as u32;
+            header.filename = dest.to_string_lossy().as_bytes().to_vec();
+            header.username = b"test".to_vec();
+            let ack = offer_raw(&mut stream, &header);
+            assert!(TeleportFeatures::ChunkChecksum.check(&ack.features));
+
+            let mut chunk = TeleportData::new();
+            chunk.data = b"data".to_vec();
+            chunk.data_len = 4;
+            let mut chunk = chunk.with_checksum();
+            chunk.data[0] = b'D';
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Data,
+                PROTOCOL_VERSION,
+                &None,
+                chunk.serialize().expect("Test should never fail"),
+            )
+            .expect("Test should never fail");
+            let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+                .expect("Test should never fail");
+            let mut ack = TeleportInitAck::default();
+            ack.deserialize(&packet.data)
+                .expect("Test should never fail");
+            assert_eq!(ack.status, TeleportStatus::HashMismatch as u8);
+            assert!(server.join().expect("Test should never fail").is_err());
+            assert!(!dest.exists());
+        }
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_passphrase() {
+        let dir = std::env::temp_dir().join(format!("teleporter-pass-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        fs::write(&src, b"secret data").expect("Test should never fail");
+
+        let cases = [(Some("right"), true), (Some("wrong"), false), (None, false)];
+        for (num, (passphrase, accepted)) in cases.into_iter().enumerate() {
+            let dest = dir.join(format!("dest-{num}.bin"));
+            let (addr, server) = spawn_receiver(move || ReceiveOptions {
+                allow_dangerous_filepath: true,
+                passphrase: Some("right".to_string()),
+                ..Default::default()
+            });
+
+            let stream = TcpStream::connect(addr).expect("Test should never fail");
+            let opts = crate::send::SendOptions {
+                filename: Some(dest.to_string_lossy().to_string()),
+                username: "test".to_string(),
+                passphrase: passphrase.map(|p| p.to_string()),
+                ..Default::default()
+            };
+            let sent = crate::send::send_file(stream, &src, opts);
+            let received = server.join().expect("Test should never fail");
+            if accepted {
+                assert!(sent.is_ok() && received.is_ok());
+            } else {
+                assert!(matches!(
+                    sent,
+                    Err(TeleportError::Refused(TeleportStatus::EncryptionError))
+                ));
+                assert!(received.is_err());
+                assert!(!dest.exists());
+            }
+        }
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_passphrase_plaintext_init() {
+        let dir = std::env::temp_dir().join(format!("teleporter-passplain-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let dest = dir.join("dest.bin");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            passphrase: Some("right".to_string()),
+            ..Default::default()
+        });
+
+        // A peer without the passphrase completes ECDH, then skips encrypting its offer
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        crate::send::key_exchange(
+            &mut stream,
+            &mut PROTOCOL_VERSION.clone(),
+            None,
+            Some("wrong"),
+        )
+        .expect("Test should never fail");
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.filesize = 4;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::EncryptionError as u8);
+        assert!(server.join().expect("Test should never fail").is_err());
+        assert!(!dest.exists());
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuse_symlink_target() {
+        let dir = std::env::temp_dir().join(format!("teleporter-link-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        let victim = dir.join("victim.txt");
+        fs::write(&src, b"attacker data").expect("Test should never fail");
+        fs::write(&victim, b"precious").expect("Test should never fail");
+        std::os::unix::fs::symlink(&victim, &dest).expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            ..Default::default()
+        });
+
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = crate::send::SendOptions {
+            filename: Some(dest.to_string_lossy().to_string()),
+            username: "test".to_string(),
+            overwrite: true,
+            ..Default::default()
+        };
+        let sent = crate::send::send_file(stream, &src, opts);
+        assert!(matches!(
+            sent,
+            Err(TeleportError::Refused(TeleportStatus::NoPermission))
+        ));
+        assert!(server.join().expect("Test should never fail").is_err());
+        assert_eq!(
+            fs::read(&victim).expect("Test should never fail"),
+            b"precious"
+        );
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_delta_chunk_size() {
+        let opt = ListenOpt::parse_from(["listen"]);
+        assert_eq!(delta_chunk_size(&opt, 100_000_000), 65536);
+        let opt = ListenOpt::parse_from(["listen", "--delta-chunks", "16"]);
+        assert_eq!(delta_chunk_size(&opt, 100_000_000), 8 << 20);
+
+        // A fixed size is used as is unless the TeleportDelta could not hold that many chunks
+        let opt = ListenOpt::parse_from(["listen", "--delta-chunk-size", "3000"]);
+        assert_eq!(delta_chunk_size(&opt, 5000), 3000);
+        assert_eq!(delta_chunk_size(&opt, 100_000_000), 3000);
+        assert_eq!(delta_chunk_size(&opt, 1 << 30), 32768);
+
+        assert!(ListenOpt::try_parse_from(["listen", "--delta-chunks", "0"]).is_err());
+        assert!(ListenOpt::try_parse_from(["listen", "--delta-chunk-size", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_copy_chunk() {
+        let path = std::env::temp_dir().join(format!("teleporter-copy-{}", std::process::id()));
+        fs::write(&path, b"0123456789").expect("Test should never fail");
+        let file = File::open(&path).expect("Test should never fail");
+
+        let copy = |source, len, limit| {
+            copy_chunk(Some(&file), &TeleportData::copy(0, 0, source, len), limit)
+        };
+        assert_eq!(copy(2, 4, 4).expect("Test should never fail"), b"2345");
+        assert!(copy(2, 5, 4).is_err());
+        assert!(copy(8, 4, 4).is_err());
+        assert!(copy_chunk(None, &TeleportData::copy(0, 0, 0, 4), 4).is_err());
+
+        fs::remove_file(&path).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_clamp_mode() {
+        assert_eq!(clamp_mode(0o644, 0o022, false, "f"), 0o644);
+        assert_eq!(clamp_mode(0o666, 0o022, false, "f"), 0o644);
+        assert_eq!(clamp_mode(0o4777, 0o022, false, "f"), 0o755);
+        assert_eq!(clamp_mode(0o7777, 0, false, "f"), 0o777);
+        assert_eq!(clamp_mode(0o444, 0o022, false, "f"), 0o444);
+        assert_eq!(clamp_mode(0o000, 0o077, false, "f"), 0o400);
+        assert_eq!(clamp_mode(0o1777, 0o022, true, "d"), 0o755);
+        assert_eq!(clamp_mode(0o555, 0o022, true, "d"), 0o755);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_setuid_dropped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("teleporter-setuid-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let dest = dir.join("dest.bin");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.filesize = 10;
+        header.chmod = 0o4777;
+        header.features |= TeleportFeatures::Resume as u32;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        // The mode is applied as soon as the ".part" file is created, before any data arrives
+        let mode = fs::metadata(dir.join("dest.bin.part"))
+            .expect("Test should never fail")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o755);
+
+        drop(stream);
+        assert!(server.join().expect("Test should never fail").is_err());
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir().join(format!("teleporter-owner-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        fs::write(&src, b"owned data").expect("Test should never fail");
+
+        // Only a privileged test run can hand the file to someone else, others keep their own
+        utils::set_owner(&src, 4242, 4242).ok();
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            ..Default::default()
+        });
+
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = crate::send::SendOptions {
+            filename: Some(dest.to_string_lossy().to_string()),
+            username: "test".to_string(),
+            preserve_owner: true,
+            ..Default::default()
+        };
+        crate::send::send_file(stream, &src, opts).expect("Test should never fail");
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+
+        let owner = |p: &Path| {
+            let meta = fs::metadata(p).expect("Test should never fail");
+            (meta.uid(), meta.gid())
+        };
+        assert_eq!(owner(&dest), owner(&src));
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_preserve_xattrs() {
+        let dir = std::env::temp_dir().join(format!("teleporter-xattr-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        fs::write(&src, b"labelled data").expect("Test should never fail");
+
+        // Not every temp directory supports user attributes
+        if xattr::write(&src, b"user.teleporter", b"label").is_err() {
+            fs::remove_dir_all(&dir).expect("Test should never fail");
+            return;
+        }
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            ..Default::default()
+        });
+
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = crate::send::SendOptions {
+            filename: Some(dest.to_string_lossy().to_string()),
+            username: "test".to_string(),
+            preserve_xattrs: true,
+            ..Default::default()
+        };
+        crate::send::send_file(stream, &src, opts).expect("Test should never fail");
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+
+        let file = File::open(&dest).expect("Test should never fail");
+        let xattrs = xattr::read(&file).expect("Test should never fail");
+        assert!(xattrs.contains(&(b"user.teleporter".to_vec(), b"label".to_vec())));
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_xattr_refused() {
+        assert!(xattr::refused(b"security.capability"));
+        assert!(!xattr::refused(b"user.teleporter"));
+    }
+
+    #[test]
+    fn test_link_inside() {
+        assert!(link_inside("a.txt", "b.txt"));
+        assert!(link_inside("dir/a.txt", "../b.txt"));
+        assert!(link_inside("dir/a.txt", "./sub/../../b.txt"));
+        assert!(!link_inside("a.txt", "../b.txt"));
+        assert!(!link_inside("dir/a.txt", "../../b.txt"));
+        assert!(!link_inside("a.txt", "/etc/passwd"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_types() {
+        let dir = std::env::temp_dir().join(format!("teleporter-types-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+
+        // Offer a single entry to receive_file and return the status it answers with
+        let offer = |name: &str, file_type: FileType, target: &str| {
+            let out = dir.clone();
+            let (addr, server) = spawn_receiver(move || ReceiveOptions {
+                output_dir: out,
+                allow_plaintext: true,
+                ..Default::default()
+            });
+
+            let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+            TeleportFeatures::FileType.add_u32(&mut header.features);
+            header.file_type = file_type as u8;
+            header.chmod = 0o750;
+            header.filename = name.as_bytes().to_vec();
+            header.username = b"test".to_vec();
+            header.link_target = target.as_bytes().to_vec();
+
+            let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+            let ack = offer_raw(&mut stream, &header);
+            let _ = server.join().expect("Test should never fail");
+            ack.status
+        };
+
+        assert_eq!(
+            offer("sub/inner", FileType::Dir, ""),
+            TeleportStatus::Proceed as u8
+        );
+        let meta = fs::metadata(dir.join("sub/inner")).expect("Test should never fail");
+        assert!(meta.is_dir());
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(meta.permissions().mode() & 0o777, 0o750);
+        }
+
+        assert_eq!(
+            offer("sub/link", FileType::Symlink, "inner"),
+            TeleportStatus::Proceed as u8
+        );
+        let link = fs::read_link(dir.join("sub/link")).expect("Test should never fail");
+        assert_eq!(link, Path::new("inner"));
+
+        // Existing entries are not replaced without overwrite, and links must not lead out
+        assert_eq!(
+            offer("sub/link", FileType::Symlink, "other"),
+            TeleportStatus::NoOverwrite as u8
+        );
+        assert_eq!(
+            offer("escape", FileType::Symlink, "../outside"),
+            TeleportStatus::BadFileName as u8
+        );
+        assert_eq!(
+            offer("sub/link", FileType::Dir, ""),
+            TeleportStatus::NoOverwrite as u8
+        );
+        assert!(fs::symlink_metadata(dir.join("escape")).is_err());
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_rotate_backups() {
+        let dir = std::env::temp_dir().join(format!("teleporter-bak-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let file = dir.join("file.txt");
+        let name = file.to_string_lossy().to_string();
+        for version in ["one", "two", "three", "four"] {
+            fs::write(&file, version).expect("Test should never fail");
+            rotate_backups(&name, 3).expect("Test should never fail");
+        }
+
+        let read = |n: &str| fs::read_to_string(format!("{name}{n}")).ok();
+        assert_eq!(read(".bak").as_deref(), Some("four"));
+        assert_eq!(read(".bak.1").as_deref(), Some("three"));
+        assert_eq!(read(".bak.2").as_deref(), Some("two"));
+        assert_eq!(read(".bak.3"), None);
+
+        // A single backup keeps overwriting ".bak"
+        rotate_backups(&name, 1).expect("Test should never fail");
+        assert_eq!(read(".bak.1").as_deref(), Some("three"));
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_written_ranges() {
+        let mut written = WrittenRanges::default();
+        written.insert(8, 12);
+        written.insert(0, 4);
+        assert!(!written.covers(12));
+        written.insert(4, 8);
+        assert!(written.covers(12));
+        assert_eq!(written.total(), 12);
+
+        // Overlapping writes are only counted once
+        written.insert(2, 10);
+        assert_eq!(written.total(), 12);
+
+        written.truncate(6);
+        assert_eq!(written.total(), 6);
+        assert!(written.covers(6) && !written.covers(7));
+    }
+
+    #[test]
+    fn test_invalid_compressed_chunk() {
+        let dir = std::env::temp_dir().join(format!("teleporter-badzstd-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let dest = dir.join("dest.bin");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        TeleportFeatures::Compress.add_u32(&mut header.features);
+        header.filesize = 4;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        // A chunk that claims to be a zstd frame but is not one ends the transfer cleanly
+        let chunk = TeleportData {
+            offset: 0,
+            seq: 0,
+            flags: 0,
+            data_len: 5,
+            checksum: 0,
+            data: vec![0x01, 0xde, 0xad, 0xbe, 0xef],
+        };
+        let payload = chunk.serialize().expect("Test should never fail");
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Data,
+            PROTOCOL_VERSION,
+            &None,
+            payload,
+        )
+        .expect("Test should never fail");
+        assert!(matches!(
+            server.join().expect("Test should never fail"),
+            Err(TeleportError::NoTransfer)
+        ));
+        assert!(!dest.exists());
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_stdout_invalid_chunk() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let addr = listener.local_addr().expect("Test should never fail");
+        let opt = ListenOpt::parse_from(["listen", "--to-stdout", "--allow-plaintext"]);
+        let identity = Arc::new(StaticSecret::new(OsRng));
+        let stats = Arc::new(Mutex::new(ServerStats::default()));
+        let server = thread::spawn(move || {
+            serve(
+                listener,
+                opt,
+                identity,
+                Arc::new(AtomicBool::new(false)),
+                stats,
+            )
+        });
+
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        TeleportFeatures::Compress.add_u32(&mut header.features);
+        header.filesize = 4;
+        header.filename = b"stdout.bin".to_vec();
+        header.username = b"test".to_vec();
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        // Part of the data reaches stdout before a chunk that cannot be decompressed
+        for (seq, data) in [vec![0x00, b'\n'], vec![0x01, 0xde, 0xad]]
+            .into_iter()
+            .enumerate()
+        {
+            let chunk = TeleportData {
+                offset: seq as u64,
+                seq: seq as u64,
+                flags: 0,
+                data_len: data.len() as u32,
+                checksum: 0,
+                data,
+            };
+            let payload = chunk.serialize().expect("Test should never fail");
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Data,
+                PROTOCOL_VERSION,
+                &None,
+                payload,
+            )
+            .expect("Test should never fail");
+        }
+
+        // The server stops instead of waiting for another transfer to append
+        assert!(matches!(
+            server.join().expect("Test should never fail"),
+            Err(TeleportError::StdoutIncomplete)
+        ));
+    }
+
+    #[test]
+    fn test_out_of_order_chunks() {
+        let dir = std::env::temp_dir().join(format!("teleporter-order-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let file = fs::File::open(&src).expect("Test should never fail");
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.filesize = data.len() as u64;
+        header.whole_hash = TeleportDelta::delta_hash(&file, HashAlgorithm::Xxh3)
+            .expect("Test should never fail")
+            .hash;
+        header.chmod = 0o644;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        // Send the chunks last to first with keepalive pings in between, then the completion marker
+        let chunks: Vec<(usize, &[u8])> = data.chunks(4096).enumerate().collect();
+        for (i, bytes) in chunks.into_iter().rev() {
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Ping,
+                PROTOCOL_VERSION,
+                &None,
+                Vec::new(),
+            )
+            .expect("Test should never fail");
+            let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+                .expect("Test should never fail");
+            assert_eq!(packet.action, TeleportAction::PingAck as u8);
+
+            let chunk = TeleportData {
+                offset: (i * 4096) as u64,
+                seq: i as u64,
+                flags: 0,
+                data_len: bytes.len() as u32,
+                checksum: 0,
+                data: bytes.to_vec(),
+            };
+            let payload = chunk.serialize().expect("Test should never fail");
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Data,
+                PROTOCOL_VERSION,
+                &None,
+                payload,
+            )
+            .expect("Test should never fail");
+        }
+        let done = TeleportData {
+            offset: data.len() as u64,
+            seq: data.chunks(4096).len() as u64,
+            flags: TeleportData::FINAL,
+            data_len: 0,
+            checksum: 0,
+            data: Vec::new(),
+        };
+        let payload = done.serialize().expect("Test should never fail");
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Data,
+            PROTOCOL_VERSION,
+            &None,
+            payload,
+        )
+        .expect("Test should never fail");
+
+        let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+            .expect("Test should never fail");
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        let received = server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+        assert!(!received.skipped);
+        assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_incomplete_transfer() {
+        let dir = std::env::temp_dir().join(format!("teleporter-short-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let dest = dir.join("dest.bin");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.filesize = 8192;
+        header.chmod = 0o644;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        // Only the first half of the declared filesize, then a well-formed completion marker
+        for chunk in [
+            TeleportData {
+                offset: 0,
+                seq: 0,
+                flags: 0,
+                data_len: 4096,
+                checksum: 0,
+                data: vec![1; 4096],
+            },
+            TeleportData {
+                offset: 8192,
+                seq: 1,
+                flags: TeleportData::FINAL,
+                data_len: 0,
+                checksum: 0,
+                data: Vec::new(),
+            },
+        ] {
+            let payload = chunk.serialize().expect("Test should never fail");
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Data,
+                PROTOCOL_VERSION,
+                &None,
+                payload,
+            )
+            .expect("Test should never fail");
+        }
+
+        let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+            .expect("Test should never fail");
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        assert_eq!(ack.status, TeleportStatus::Incomplete as u8);
+        assert!(server.join().expect("Test should never fail").is_err());
+        assert!(!dest.exists());
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let dir = std::env::temp_dir().join(format!("teleporter-empty-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let dest = dir.join("dest.bin");
+        fs::write(&dest, b"old data").expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let src = dir.join("src.bin");
+        fs::write(&src, b"").expect("Test should never fail");
+
+        let file = fs::File::open(&src).expect("Test should never fail");
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.features |= TeleportFeatures::Overwrite as u32 | TeleportFeatures::Delta as u32;
+        header.whole_hash = TeleportDelta::delta_hash(&file, HashAlgorithm::Xxh3)
+            .expect("Test should never fail")
+            .hash;
+        header.chmod = 0o644;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+        assert!(ack.delta.is_none());
+
+        // No data chunks, just the completion marker
+        let done = TeleportData {
+            offset: 0,
+            seq: 0,
+            flags: TeleportData::FINAL,
+            data_len: 0,
+            checksum: 0,
+            data: Vec::new(),
+        };
+        let payload = done.serialize().expect("Test should never fail");
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Data,
+            PROTOCOL_VERSION,
+            &None,
+            payload,
+        )
+        .expect("Test should never fail");
+
+        let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+            .expect("Test should never fail");
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        let received = server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+        assert!(!received.skipped);
+        assert_eq!(received.bytes, 0);
+        assert_eq!(fs::read(&dest).expect("Test should never fail"), b"");
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_newer_client() {
+        let dir = std::env::temp_dir().join(format!("teleporter-newer-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let dest = dir.join("dest.bin");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let src = dir.join("src.bin");
+        fs::write(&src, b"").expect("Test should never fail");
+
+        let file = fs::File::open(&src).expect("Test should never fail");
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.whole_hash = TeleportDelta::delta_hash(&file, HashAlgorithm::Xxh3)
+            .expect("Test should never fail")
+            .hash;
+        header.chmod = 0o644;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+
+        // A client one version ahead offers in its own version and gets the server's back
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let init = header.serialize().expect("Test should never fail");
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Init,
+            PROTOCOL_VERSION + 1,
+            &None,
+            init,
+        )
+        .expect("Test should never fail");
+        let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION + 1, &None)
+            .expect("Test should never fail");
+        assert_eq!(packet.version, PROTOCOL_VERSION);
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        // The rest of the transfer uses the negotiated version
+        let done = TeleportData {
+            offset: 0,
+            seq: 0,
+            flags: TeleportData::FINAL,
+            data_len: 0,
+            checksum: 0,
+            data: Vec::new(),
+        };
+        let payload = done.serialize().expect("Test should never fail");
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Data,
+            packet.version,
+            &None,
+            payload,
+        )
+        .expect("Test should never fail");
+        let packet =
+            utils::recv_packet(&mut stream, packet.version, &None).expect("Test should never fail");
+        assert_eq!(packet.version, PROTOCOL_VERSION);
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+        assert_eq!(fs::read(&dest).expect("Test should never fail"), b"");
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_unknown_size() {
+        let dir = std::env::temp_dir().join(format!("teleporter-unknown-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let dest = dir.join("dest.bin");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).expect("Test should never fail");
+
+        let (addr, server) = spawn_receiver(move || ReceiveOptions {
+            allow_dangerous_filepath: true,
+            allow_plaintext: true,
+            ..Default::default()
+        });
+
+        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+        header.features |= TeleportFeatures::UnknownSize as u32;
+        header.chmod = 0o644;
+        header.filename = dest.to_string_lossy().as_bytes().to_vec();
+        header.username = b"test".to_vec();
+
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        let ack = offer_raw(&mut stream, &header);
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+        assert!(TeleportFeatures::UnknownSize.check(&ack.features));
+
+        // Uneven chunks like a pipe delivers them, then the size and hash in the final chunk
+        let mut offset = 0;
+        for (seq, bytes) in data.chunks(3000).enumerate() {
+            let chunk = TeleportData {
+                offset: offset as u64,
+                seq: seq as u64,
+                flags: 0,
+                data_len: bytes.len() as u32,
+                checksum: 0,
+                data: bytes.to_vec(),
+            };
+            offset += bytes.len();
+            let payload = chunk.serialize().expect("Test should never fail");
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Data,
+                PROTOCOL_VERSION,
+                &None,
+                payload,
+            )
+            .expect("Test should never fail");
+        }
+        let file = fs::File::open(&src).expect("Test should never fail");
+        let hash =
+            TeleportDelta::stream_hash(&file, HashAlgorithm::Xxh3).expect("Test should never fail");
+        let done = TeleportData {
+            offset: offset as u64,
+            seq: data.chunks(3000).len() as u64,
+            flags: TeleportData::FINAL,
+            data_len: 8,
+            checksum: 0,
+            data: hash.to_le_bytes().to_vec(),
+        };
+        let payload = done.serialize().expect("Test should never fail");
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Data,
+            PROTOCOL_VERSION,
+            &None,
+            payload,
+        )
+        .expect("Test should never fail");
+
+        let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+            .expect("Test should never fail");
+        let mut ack = TeleportInitAck::default();
+        ack.deserialize(&packet.data)
+            .expect("Test should never fail");
+        assert_eq!(ack.status, TeleportStatus::Proceed as u8);
+
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+        assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_parallel_streams() {
+        let dir = std::env::temp_dir().join(format!("teleporter-streams-{}", std::process::id()));
+        let out = dir.join("out");
+        fs::create_dir_all(&out).expect("Test should never fail");
+        let src = dir.join("src.bin");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).expect("Test should never fail");
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let port = listener
+            .local_addr()
+            .expect("Test should never fail")
+            .port();
+        let opt = ListenOpt::parse_from(["listen", "-o", &out.to_string_lossy()]);
+        let identity = Arc::new(StaticSecret::new(OsRng));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&shutdown);
+        let stats = Arc::new(Mutex::new(ServerStats::default()));
+        let totals = Arc::clone(&stats);
+        let server = thread::spawn(move || serve(listener, opt, identity, stop, totals));
+
+        let opt = crate::SendOpt::parse_from([
+            "send",
+            "-i",
+            &src.to_string_lossy(),
+            "-d",
+            "127.0.0.1",
+            "-p",
+            &port.to_string(),
+            "-u",
+            "test",
+            "--streams",
+            "4",
+        ]);
+        crate::send::run(opt).expect("Test should never fail");
+        shutdown.store(true, Ordering::SeqCst);
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+
+        let hash = |path: &Path| {
+            let file = File::open(path).expect("Test should never fail");
+            TeleportDelta::delta_hash(&file, HashAlgorithm::Xxh3)
+                .expect("Test should never fail")
+                .hash
+        };
+        assert_eq!(hash(&out.join("src.bin")), hash(&src));
+        let stats = stats.lock().expect("Test should never fail");
+        assert_eq!((stats.files, stats.bytes), (1, data.len() as u64));
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_rebind() {
+        let listener =
+            bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).expect("Test should never fail");
+        let addr = listener.local_addr().expect("Test should never fail");
+
+        // Closing the accepted side first leaves the server's end of the connection in TIME_WAIT
+        let client = TcpStream::connect(addr).expect("Test should never fail");
+        let (accepted, _) = listener.accept().expect("Test should never fail");
+        drop(accepted);
+        drop(listener);
+        drop(client);
+
+        bind(addr).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let listener =
+            bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).expect("Test should never fail");
+        let port = listener
+            .local_addr()
+            .expect("Test should never fail")
+            .port();
+        let opt = ListenOpt::parse_from(["listen"]);
+        let identity = Arc::new(StaticSecret::new(OsRng));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&shutdown);
+        let server = thread::spawn(move || serve(listener, opt, identity, stop, Arc::default()));
+
+        // The one listener answers pings from both address families
+        for addr in [
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+        ] {
+            let stream = TcpStream::connect(addr).expect("Test should never fail");
+            let ack = crate::scan::query(stream).expect("Test should never fail");
+            assert_eq!(ack.status, TeleportStatus::Pong as u8);
+        }
+
+        shutdown.store(true, Ordering::SeqCst);
+        server
+            .join()
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+    }
 }
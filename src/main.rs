@@ -1,12 +1,21 @@
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use env_logger::Env;
+use log::error;
+use std::io::Write;
 
-use teleporter::{listen, scan, send};
-use teleporter::{ListenOpt, ScanOpt, SendOpt};
+use serde_json::json;
+use teleporter::{config, events, list, listen, ping, scan, send};
+use teleporter::{ListOpt, ListenOpt, PingOpt, ScanOpt, SendOpt};
 
 /// Teleporter is a simple application for sending files from Point A to Point B
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
 #[command(version)]
 pub struct Opt {
+    /// Print one JSON object per event to stdout, text messages move to stderr
+    #[arg(long, global = true)]
+    json: bool,
+
     /// Command
     #[command(subcommand)]
     cmd: Cmd,
@@ -15,27 +24,185 @@ pub struct Opt {
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
 pub enum Cmd {
     /// Start a teleporter in server (receiving) mode
+    #[command(visible_alias = "receive")]
     Listen(ListenOpt),
     /// Start a teleporter in client (sending) mode
     Send(SendOpt),
     /// Scan all network devices for any reachable Teleport listeners
     Scan(ScanOpt),
+    /// Ping a Teleport listener and report its version and round-trip time
+    Ping(PingOpt),
+    /// List the files a Teleport listener already has
+    List(ListOpt),
+}
+
+/// Where the subcommand's passphrase came from, if it takes one
+fn passphrase_source(matches: &ArgMatches) -> Option<ValueSource> {
+    match matches.subcommand()? {
+        ("send" | "list", sub) => sub.value_source("passphrase"),
+        _ => None,
+    }
 }
 
 fn main() {
     // Process arguments
-    let opt = Opt::parse();
+    let mut argv: Vec<String> = std::env::args().collect();
+    let matches = Opt::command().get_matches_from(&argv);
+    let mut opt = Opt::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // Options from the listen config file go right after the subcommand, flags still win
+    if let (Cmd::Listen(l), Some(("listen", sub))) = (&opt.cmd, matches.subcommand()) {
+        match config::listen_args(l, sub) {
+            Ok(args) if !args.is_empty() => {
+                match argv.iter().skip(1).position(|a| !a.starts_with('-')) {
+                    Some(i) => argv.splice(i + 2..i + 2, args),
+                    None => unreachable!("listen was parsed without a subcommand"),
+                };
+                opt = Opt::try_parse_from(&argv).unwrap_or_else(|e| e.exit());
+            }
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    // Log plain messages to stdout, verbosity is controlled with RUST_LOG. JSON events or a
+    // received file take over stdout when requested
+    let to_stdout = matches!(&opt.cmd, Cmd::Listen(l) if l.to_stdout());
+    let target = match opt.json || to_stdout {
+        true => env_logger::Target::Stderr,
+        false => env_logger::Target::Stdout,
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .target(target)
+        .init();
+    if opt.json && to_stdout {
+        error!("Error: --json cannot be combined with --to-stdout");
+        std::process::exit(2);
+    }
+    if opt.json {
+        events::enable_json();
+    }
 
     // Execute command
     let out = match opt.cmd {
         Cmd::Listen(l) => listen::run(l),
-        Cmd::Send(s) => send::run(s),
+        Cmd::Send(s) => send::run_with_passphrase_source(s, passphrase_source(&matches)),
         Cmd::Scan(s) => scan::run(s),
+        Cmd::Ping(p) => ping::run(p),
+        Cmd::List(l) => list::run_with_passphrase_source(l, passphrase_source(&matches)),
     };
 
     // Display any errors
     match out {
         Ok(()) => {}
-        Err(s) => println!("Error: {s}"),
+        Err(s) => {
+            events::emit("error", json!({ "message": s.to_string() }));
+            error!("Error: {s}");
+            std::process::exit(1);
+        }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held by every test that parses options, as they all read the variables test_env_fallback sets
+    static ENV: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_receive_alias() {
+        let _env = ENV.lock().expect("Test should never fail");
+        let listen = Opt::parse_from(["teleporter", "listen", "-p", "9002"]);
+        let receive = Opt::parse_from(["teleporter", "receive", "-p", "9002"]);
+        assert_eq!(listen, receive);
+        assert!(matches!(receive.cmd, Cmd::Listen(_)));
+    }
+
+    #[test]
+    fn test_passphrase_source() {
+        let _env = ENV.lock().expect("Test should never fail");
+        // --plaintext no longer conflicts at parse time, the flag's source decides instead
+        let matches = Opt::command().get_matches_from([
+            "teleporter",
+            "send",
+            "-u",
+            "test",
+            "--plaintext",
+            "--passphrase",
+            "secret",
+        ]);
+        assert_eq!(passphrase_source(&matches), Some(ValueSource::CommandLine));
+        let matches = Opt::command().get_matches_from(["teleporter", "ping"]);
+        assert_eq!(passphrase_source(&matches), None);
+    }
+
+    #[test]
+    fn test_env_fallback() {
+        let _env = ENV.lock().expect("Test should never fail");
+        let parse = |args: &[&str]| match Opt::parse_from(args).cmd {
+            Cmd::Listen(l) => l,
+            _ => unreachable!(),
+        };
+        let default = parse(&["teleporter", "listen"]);
+
+        std::env::set_var("TELEPORT_PORT", "9100");
+        std::env::set_var("TELEPORT_BIND", "127.0.0.1");
+        std::env::set_var("TELEPORT_PASSPHRASE", "secret");
+        let env = parse(&["teleporter", "listen"]);
+        let flags = parse(&["teleporter", "listen", "-p", "9200"]);
+        let send = Opt::parse_from(["teleporter", "send", "-u", "test"]).cmd;
+        let list = Opt::parse_from(["teleporter", "list", "-u", "test"]).cmd;
+        let plaintext =
+            Opt::command().get_matches_from(["teleporter", "send", "-u", "test", "--plaintext"]);
+        std::env::remove_var("TELEPORT_PORT");
+        std::env::remove_var("TELEPORT_BIND");
+        std::env::remove_var("TELEPORT_PASSPHRASE");
+
+        let expected = ListenOpt::parse_from([
+            "listen",
+            "-p",
+            "9100",
+            "-b",
+            "127.0.0.1",
+            "--passphrase",
+            "secret",
+        ]);
+        assert_eq!(env, expected);
+        assert_eq!(
+            flags,
+            ListenOpt::parse_from([
+                "listen",
+                "-p",
+                "9200",
+                "-b",
+                "127.0.0.1",
+                "--passphrase",
+                "secret",
+            ])
+        );
+        assert_eq!(parse(&["teleporter", "listen"]), default);
+
+        // The variable no longer conflicts with --plaintext, send and list ignore it then
+        assert_eq!(
+            passphrase_source(&plaintext),
+            Some(ValueSource::EnvVariable)
+        );
+
+        // Clients read the same variables, so the shared secret stays off their command line too
+        let client = ["-u", "test", "-p", "9100", "--passphrase", "secret"];
+        assert_eq!(
+            send,
+            Cmd::Send(SendOpt::parse_from(["send"].iter().chain(&client)))
+        );
+        assert_eq!(
+            list,
+            Cmd::List(ListOpt::parse_from(["list"].iter().chain(&client)))
+        );
+    }
+}
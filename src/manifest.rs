@@ -0,0 +1,122 @@
+use crate::errors::TeleportError;
+use crate::teleport::{HashAlgorithm, TeleportDelta};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Expected digests by path, read from a manifest of `hash  path` lines
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest(Arc<HashMap<String, String>>);
+
+impl Manifest {
+    /// Parse the lines of a manifest, skipping blank ones
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut entries = HashMap::new();
+        for (num, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (hash, path) = match line.split_once("  ") {
+                Some((h, p)) if !p.is_empty() => (h, p),
+                _ => return Err(format!("line {}: expected `hash  path`", num + 1)),
+            };
+            if algorithm(hash).is_none() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(format!(
+                    "line {}: not an xxh3 or BLAKE3 hash: {hash}",
+                    num + 1
+                ));
+            }
+            entries.insert(path.to_string(), hash.to_ascii_lowercase());
+        }
+
+        Ok(Manifest(Arc::new(entries)))
+    }
+
+    /// Number of files listed
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The expected digest of `path`, if it is listed
+    pub fn get(&self, path: &str) -> Option<&str> {
+        self.0.get(path).map(|h| h.as_str())
+    }
+}
+
+/// Read and parse a manifest file, used as the value parser of --verify-manifest
+pub fn load(path: &str) -> Result<Manifest, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    Manifest::parse(&text)
+}
+
+/// One manifest line, in the format b3sum prints and checks
+pub fn line(hash: &str, path: &str) -> String {
+    format!("{hash}  {path}\n")
+}
+
+/// The algorithm a hex digest of this length comes from
+fn algorithm(hash: &str) -> Option<HashAlgorithm> {
+    match hash.len() {
+        16 => Some(HashAlgorithm::Xxh3),
+        64 => Some(HashAlgorithm::Blake3),
+        _ => None,
+    }
+}
+
+/// True if the data of `file` has the `expected` digest
+pub fn matches(file: &File, expected: &str) -> Result<bool, TeleportError> {
+    match algorithm(expected) {
+        Some(algo) => Ok(TeleportDelta::digest(file, algo)? == expected),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The digests of no data
+    const EMPTY_XXH3: &str = "2d06800538d394c2";
+    const EMPTY_BLAKE3: &str = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+
+    #[test]
+    fn test_parse_manifest() {
+        let text = format!(
+            "{}\n\n{}",
+            line(&EMPTY_XXH3.to_uppercase(), "a.bin").trim_end(),
+            line(EMPTY_BLAKE3, "dir/b c.bin")
+        );
+        let m = Manifest::parse(&text).expect("Test should never fail");
+        assert_eq!(m.count(), 2);
+        assert_eq!(m.get("a.bin"), Some(EMPTY_XXH3));
+        assert_eq!(m.get("dir/b c.bin"), Some(EMPTY_BLAKE3));
+        assert_eq!(m.get("c.bin"), None);
+
+        for bad in [
+            "2d06800538d394c2 a.bin",
+            "2d06800538d394c2  ",
+            "2d0680  a.bin",
+            "2d06800538d394cg  a.bin",
+        ] {
+            assert!(Manifest::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_manifest_matches() {
+        let path = std::env::temp_dir().join(format!("teleporter-manifest-{}", std::process::id()));
+        fs::write(&path, b"").expect("Test should never fail");
+        let file = File::open(&path).expect("Test should never fail");
+        assert!(matches(&file, EMPTY_XXH3).expect("Test should never fail"));
+        assert!(matches(&file, EMPTY_BLAKE3).expect("Test should never fail"));
+        assert!(!matches(&file, &EMPTY_BLAKE3.replace('a', "b")).expect("Test should never fail"));
+
+        assert_eq!(
+            TeleportDelta::digest(&file, HashAlgorithm::Blake3).expect("Test should never fail"),
+            EMPTY_BLAKE3
+        );
+        drop(file);
+        fs::remove_file(&path).expect("Test should never fail");
+    }
+}
@@ -0,0 +1,35 @@
+use crate::errors::TeleportError;
+use crate::scan;
+use crate::PingOpt;
+use log::{error, info};
+use std::io;
+use std::net::TcpStream;
+use std::time::Instant;
+
+/// Ping a teleporter server and report its version and the round-trip time
+pub fn run(opt: PingOpt) -> Result<(), TeleportError> {
+    let stream = TcpStream::connect((opt.dest.as_str(), opt.port))?;
+
+    let start = Instant::now();
+    match scan::query(stream) {
+        Ok(ack) => {
+            info!(
+                "Teleporter v{} at {}:{} replied in {:.2?}",
+                ack.version,
+                opt.dest,
+                opt.port,
+                start.elapsed()
+            );
+            Ok(())
+        }
+        // Incompatible or older servers drop the connection without answering
+        Err(TeleportError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            error!(
+                "{}:{} closed the connection without replying, it may be running an incompatible or older teleporter",
+                opt.dest, opt.port
+            );
+            Err(TeleportError::Io(e))
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{events, utils};
+use is_terminal::IsTerminal;
+use log::debug;
+use serde_json::json;
+use std::cell::Cell;
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Receives updates as file data is sent or received
+pub trait ProgressReporter {
+    /// Called after each chunk with the bytes transferred so far and the total file size
+    fn on_chunk(&self, transferred: u64, total: u64);
+}
+
+/// How often PrintUpdates redraws its progress line
+const PRINT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Weight given to the newest speed sample in the moving average
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Default reporter, prints percent, speed and ETA of one file to stdout
+///
+/// On a terminal a single line is redrawn at most every 100ms, otherwise a plain line is printed
+/// for every 10% of the file so logs are not flooded with carriage returns.
+pub struct PrintUpdates {
+    tty: bool,
+    state: Cell<Option<PrintState>>,
+}
+
+#[derive(Clone, Copy)]
+struct PrintState {
+    last: Instant,
+    bytes: u64,
+    rate: f64,
+    step: u64,
+}
+
+impl PrintUpdates {
+    pub fn new() -> Self {
+        PrintUpdates {
+            tty: io::stdout().is_terminal(),
+            state: Cell::new(None),
+        }
+    }
+}
+
+impl Default for PrintUpdates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressReporter for PrintUpdates {
+    fn on_chunk(&self, transferred: u64, total: u64) {
+        let now = Instant::now();
+        let mut state = match self.state.get() {
+            Some(s) => s,
+            None => PrintState {
+                last: now,
+                bytes: transferred,
+                rate: 0.0,
+                step: 0,
+            },
+        };
+        let done = transferred >= total;
+        let elapsed = now.duration_since(state.last);
+        if elapsed < PRINT_INTERVAL && !done && self.state.get().is_some() {
+            return;
+        }
+
+        // Blend the speed since the last redraw into the moving average
+        if !elapsed.is_zero() {
+            let sample = transferred.saturating_sub(state.bytes) as f64 / elapsed.as_secs_f64();
+            state.rate = match state.rate > 0.0 {
+                true => RATE_SMOOTHING * sample + (1.0 - RATE_SMOOTHING) * state.rate,
+                false => sample,
+            };
+        }
+        state.last = now;
+        state.bytes = transferred;
+
+        let eta = match (done, state.rate > 0.0) {
+            (true, _) => Some(Duration::ZERO),
+            (false, true) => Some(Duration::from_secs_f64(
+                (total - transferred) as f64 / state.rate,
+            )),
+            (false, false) => None,
+        };
+        let line = utils::format_updates(transferred as f64, total as f64, state.rate, eta);
+
+        let mut stdout = io::stdout().lock();
+        let res = match self.tty {
+            true => write!(stdout, "\r{line}"),
+            false => {
+                let step = match total {
+                    0 => 10,
+                    _ => transferred * 10 / total,
+                };
+                let res = match step > state.step || self.state.get().is_none() {
+                    true => writeln!(stdout, "{line}"),
+                    false => Ok(()),
+                };
+                state.step = step;
+                res
+            }
+        };
+        if res.and_then(|_| stdout.flush()).is_err() {
+            debug!("Could not print progress");
+        }
+        self.state.set(Some(state));
+    }
+}
+
+/// How often JsonUpdates prints a progress event
+const JSON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prints progress events for one file when JSON output is enabled, at most every 100ms
+pub struct JsonUpdates {
+    filename: String,
+    last: Cell<Option<Instant>>,
+}
+
+impl JsonUpdates {
+    pub fn new(filename: &str) -> Self {
+        JsonUpdates {
+            filename: filename.to_string(),
+            last: Cell::new(None),
+        }
+    }
+}
+
+impl ProgressReporter for JsonUpdates {
+    fn on_chunk(&self, transferred: u64, total: u64) {
+        let due = self
+            .last
+            .get()
+            .map_or(true, |t| t.elapsed() >= JSON_INTERVAL);
+        if !due && transferred < total {
+            return;
+        }
+
+        self.last.set(Some(Instant::now()));
+        events::emit(
+            "progress",
+            json!({ "filename": self.filename, "bytes": transferred, "total": total }),
+        );
+    }
+}
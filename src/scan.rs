@@ -3,7 +3,9 @@ use crate::teleport::{
     TeleportAction, TeleportFeatures, TeleportInit, TeleportInitAck, TeleportStatus,
 };
 use crate::utils;
+use crate::PROTOCOL_VERSION;
 use ipnetwork::IpNetwork;
+use log::info;
 use pnet_datalink::interfaces;
 use std::net::SocketAddr;
 use std::net::TcpStream;
@@ -38,7 +40,7 @@ fn scan_network(network: &IpNetwork, port: u16) -> Result<(), TeleportError> {
         let socket = sa.to_socket_addrs().unwrap();
         for s in socket {
             if let Ok(ack) = ping(&s) {
-                println!("Teleporter v{} detected on {sa}", ack.version);
+                info!("Teleporter v{} detected on {sa}", ack.version);
             };
         }
     }
@@ -51,17 +53,18 @@ fn ping(ip_addr: &SocketAddr) -> Result<TeleportInitAck, TeleportError> {
     query(stream)
 }
 
-fn query(mut stream: TcpStream) -> Result<TeleportInitAck, TeleportError> {
+pub(crate) fn query(mut stream: TcpStream) -> Result<TeleportInitAck, TeleportError> {
     let header = TeleportInit::new(TeleportFeatures::Ping);
 
     utils::send_packet(
         &mut stream,
         TeleportAction::Ping,
+        PROTOCOL_VERSION,
         &None,
         header.serialize()?,
     )?;
 
-    let packet = utils::recv_packet(&mut stream, &None)?;
+    let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)?;
     let mut ack = TeleportInitAck::default();
     ack.deserialize(&packet.data)?;
     if ack.status != TeleportStatus::Pong as u8 {
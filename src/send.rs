@@ -1,16 +1,30 @@
+use crate::compress::Compressor;
 use crate::errors::TeleportError;
-use crate::teleport::{TeleportAction, TeleportFeatures, TeleportStatus};
-use crate::teleport::{TeleportData, TeleportDelta, TeleportEnc, TeleportInit, TeleportInitAck};
-use crate::SendOpt;
-use crate::VERSION;
-use crate::{crypto, utils};
+use crate::progress::{JsonUpdates, PrintUpdates, ProgressReporter};
+use crate::teleport::STREAM_ID_LEN;
+use crate::teleport::{
+    DeltaOp, TeleportData, TeleportDelta, TeleportEnc, TeleportInit, TeleportInitAck,
+};
+use crate::teleport::{HashAlgorithm, TeleportAction, TeleportFeatures, TeleportStatus};
+use crate::{crypto, events, manifest, scan, utils, xattr};
+use crate::{SendOpt, TransferStats};
+use crate::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::{PROTOCOL_VERSION, VERSION};
+use clap::parser::ValueSource;
+use glob::{MatchOptions, Pattern};
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::os::unix::fs::PermissionsExt;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 #[derive(Debug)]
 struct Replace {
@@ -18,66 +32,186 @@ struct Replace {
     new: Vec<String>,
 }
 
-fn get_file_list(opt: &SendOpt) -> Vec<String> {
-    let mut files = Vec::<String>::new();
+/// A file to send, with its path relative to the input directory when found by recursion
+struct SendFile {
+    path: String,
+    relative: Option<String>,
+}
+
+fn get_file_list(opt: &SendOpt) -> Vec<SendFile> {
+    let mut files = Vec::<SendFile>::new();
 
     // Iterate over each item in list
     for item in opt.input.iter() {
         if opt.recursive && item.is_dir() {
             // Recurse into directories
-            let mut tmp = match scope_dir(item) {
+            let mut visited = HashSet::<PathBuf>::new();
+            let walk = Walk {
+                root: item,
+                follow_symlinks: opt.follow_symlinks,
+                exclude: &opt.exclude,
+            };
+            let tmp = match scope_dir(&walk, item, &mut visited) {
                 Ok(t) => t,
                 Err(_) => {
-                    println!("Error: Cannot read item: {item:?}");
+                    error!("Error: Cannot read item: {item:?}");
                     continue;
                 }
             };
-            // Append any files located
-            files.append(&mut tmp);
+
+            // Append any files located, keeping their path below the input directory
+            for path in tmp {
+                let relative = relative_path(item, &path);
+                files.push(SendFile { path, relative });
+            }
         } else if item.exists() && item.is_file() {
+            if !opt.follow_symlinks && item.is_symlink() {
+                warn!("Skipping symlink: {item:?} (use --follow-symlinks to send it)");
+                continue;
+            }
+
             // Append the file
-            files.push(
-                item.to_str()
+            files.push(SendFile {
+                path: item
+                    .to_str()
                     .expect("Fatal error converting item to str")
                     .to_string(),
-            );
+                relative: None,
+            });
         }
     }
 
     files
 }
 
-fn scope_dir(dir: &Path) -> Result<Vec<String>, TeleportError> {
-    let path = Path::new(&dir);
+/// Path of a walked file relative to the parent of the input directory, e.g. "dir/sub/file"
+fn relative_path(dir: &Path, path: &str) -> Option<String> {
+    let rel = Path::new(path).strip_prefix(dir).ok()?;
+    let rel = match dir.file_name() {
+        Some(name) => Path::new(name).join(rel),
+        None => rel.to_path_buf(),
+    };
+
+    rel.to_str().map(|r| r.to_string())
+}
+
+/// Settings shared by every level of a recursive directory walk
+struct Walk<'a> {
+    root: &'a Path,
+    follow_symlinks: bool,
+    exclude: &'a [Pattern],
+}
+
+impl Walk<'_> {
+    /// True if the path below the walked directory, or its name, matches an --exclude pattern
+    fn excluded(&self, path: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let rel = path.strip_prefix(self.root).unwrap_or(path);
+        let name = path.file_name().map(|n| n.to_string_lossy());
+        self.exclude.iter().any(|p| {
+            p.matches_path_with(rel, options)
+                || matches!(&name, Some(n) if p.matches_with(n, options))
+        })
+    }
+}
+
+fn scope_dir(
+    walk: &Walk,
+    dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<Vec<String>, TeleportError> {
     let mut files = Vec::<String>::new();
 
+    // Never walk the same directory twice, symlinks can form loops
+    if !visited.insert(dir.canonicalize()?) {
+        return Ok(files);
+    }
+
     // Iterate over each item in directory
-    for entry in path.read_dir()? {
-        if entry.as_ref().unwrap().file_type().unwrap().is_dir() {
-            // Skip current directory
-            if entry.as_ref().unwrap().path() == *dir {
+    let mut empty = true;
+    for entry in dir.read_dir()? {
+        empty = false;
+        let entry = entry?;
+        let path = entry.path();
+        let mut file_type = entry.file_type()?;
+
+        // Excluded directories are not even read
+        if walk.excluded(&path) {
+            debug!("Excluding: {path:?}");
+            continue;
+        }
+
+        if file_type.is_symlink() {
+            if !walk.follow_symlinks {
+                warn!("Skipping symlink: {path:?} (use --follow-symlinks to send it)");
                 continue;
             }
+            file_type = fs::metadata(&path)?.file_type();
+        }
 
+        if file_type.is_dir() {
             // Recurse into subdirectories
-            let mut tmp = match scope_dir(&entry.as_ref().unwrap().path()) {
+            let mut tmp = match scope_dir(walk, &path, visited) {
                 Ok(t) => t,
                 Err(_) => {
-                    println!("Error: Cannot read dir: {entry:?}");
+                    error!("Error: Cannot read dir: {path:?}");
                     continue;
                 }
             };
             // Append any files located
             files.append(&mut tmp);
-        } else if entry.as_ref().unwrap().file_type().unwrap().is_file() {
+        } else if file_type.is_file() {
             // Append the file
-            files.push(entry.unwrap().path().to_str().unwrap().to_string());
+            match path.to_str() {
+                Some(p) => files.push(p.to_string()),
+                None => error!("Error: Cannot send non UTF-8 path: {path:?}"),
+            }
         }
     }
 
+    // Directories are not transferred on their own, so empty ones are skipped
+    if empty {
+        info!("Skipping empty directory: {dir:?}");
+    }
+
     Ok(files)
 }
 
+/// Expand glob patterns in the input list in place, for shells that pass them through unexpanded
+fn expand_globs(opt: &mut SendOpt) -> Result<(), TeleportError> {
+    let mut input = Vec::<PathBuf>::new();
+
+    for item in opt.input.iter() {
+        // Paths that exist are never patterns, even if they contain glob characters
+        let pattern = match item.to_str() {
+            Some(p) if !item.exists() && p.contains(['*', '?', '[']) => p,
+            _ => {
+                input.push(item.clone());
+                continue;
+            }
+        };
+
+        // Matches come back sorted, so the order of files and progress counts is stable
+        let before = input.len();
+        for entry in glob::glob(pattern)? {
+            match entry {
+                Ok(path) => input.push(path),
+                Err(e) => warn!("Skipping unreadable path: {e}"),
+            }
+        }
+        if input.len() == before {
+            return Err(TeleportError::NoMatch(pattern.to_string()));
+        }
+        debug!("Expanded {:?} to {:?}", pattern, &input[before..]);
+    }
+    opt.input = input;
+
+    Ok(())
+}
+
 fn find_replacements(opt: &mut SendOpt) -> Replace {
     let mut rep = Replace {
         orig: Vec::<String>::new(),
@@ -115,9 +249,8 @@ fn find_replacements(opt: &mut SendOpt) -> Replace {
     }
 
     // For every replacement being made
-    while !poppers.is_empty() {
+    while let Some(idx) = poppers.pop() {
         // Get the index of the string to be replaced
-        let idx = poppers.pop().unwrap();
         // Remove the string from the input list
         opt.input.remove(idx);
         // Insert the original file name to be used
@@ -129,304 +262,1569 @@ fn find_replacements(opt: &mut SendOpt) -> Replace {
     rep
 }
 
-fn connect_to_client(
-    ip_addrs: std::vec::IntoIter<std::net::SocketAddr>,
-) -> Result<TcpStream, TeleportError> {
-    for addr in ip_addrs {
-        match TcpStream::connect(addr) {
-            Ok(s) => return Ok(s),
-            Err(_) => {
-                continue;
-            }
+/// Name of the file on the server, with the path kept or stripped and any rename applied
+fn remote_name(item: &SendFile, rep: &Replace, opt: &SendOpt) -> String {
+    let filepath = &item.path;
+    let mut filename = match (&item.relative, opt.keep_path) {
+        (Some(rel), false) => rel.clone(),
+        _ => filepath.clone(),
+    };
+
+    // Locate and replace the filename of the transfer file, if renamed
+    for (idx, item) in rep.orig.iter().enumerate() {
+        if item.contains(&filepath.to_string()) {
+            filename = rep.new[idx].clone();
+        }
+    }
+
+    // Remove all path info if !opt.keep_path, unless found by recursion and not sending flat
+    if opt.flat || (!opt.keep_path && item.relative.is_none()) {
+        filename = Path::new(&filename)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+    }
+
+    filename
+}
+
+/// Print each file a dry run would send, with its size and the total
+fn print_plan(files: &[SendFile], rep: &Replace, opt: &SendOpt) -> Result<(), TeleportError> {
+    info!("Dry run, no file data will be sent:");
+    let mut total = 0;
+    for item in files {
+        let filename = remote_name(item, rep, opt);
+        let bytes = fs::metadata(&item.path)?.len();
+        total += bytes;
+        info!(
+            " => {}: {} ({})",
+            item.path,
+            filename,
+            utils::format_size(bytes)
+        );
+        events::emit(
+            "plan",
+            json!({ "path": item.path, "filename": filename, "bytes": bytes }),
+        );
+    }
+    info!(
+        "Would send {} file(s), {} in total",
+        files.len(),
+        utils::format_size(total)
+    );
+
+    Ok(())
+}
+
+/// True if a teleporter server answers a ping at the destination
+fn reachable(opt: &SendOpt) -> bool {
+    let res = connect(opt).and_then(scan::query);
+    if let Err(e) = &res {
+        warn!(
+            "Server {}:{} is not reachable, skipping the delta check: {}",
+            opt.dest, opt.port, e
+        );
+    }
+
+    res.is_ok()
+}
+
+/// Resolve the destination, keeping only the address family asked for with --ipv4 or --ipv6
+fn resolve(opt: &SendOpt) -> Result<Vec<SocketAddr>, TeleportError> {
+    // A bare IPv6 address needs no brackets, as the port is given separately
+    let host = match opt.dest.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(h) => h,
+        None => opt.dest.as_str(),
+    };
+    let addrs = match (host, opt.port).to_socket_addrs() {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Cannot resolve destination {}: {}", opt.dest, e);
+            return Err(TeleportError::InvalidDest);
+        }
+    };
+
+    let addrs: Vec<SocketAddr> = addrs
+        .filter(|a| !(opt.ipv4 && a.is_ipv6() || opt.ipv6 && a.is_ipv4()))
+        .collect();
+    if addrs.is_empty() {
+        let family = match opt.ipv4 {
+            true => "IPv4",
+            false => "IPv6",
+        };
+        error!("Destination {} has no {} address", opt.dest, family);
+        return Err(TeleportError::InvalidDest);
+    }
+
+    Ok(addrs)
+}
+
+/// Connect to the destination, racing its IPv4 addresses against the IPv6 ones
+fn connect(opt: &SendOpt) -> Result<TcpStream, TeleportError> {
+    race(resolve(opt)?, HAPPY_EYEBALLS_DELAY)
+}
+
+/// Happy eyeballs: try the IPv6 addresses in order, and the IPv4 ones alongside them once the
+/// first family failed an address or has not connected for `delay`. The first connection wins
+fn race(addrs: Vec<SocketAddr>, delay: Duration) -> Result<TcpStream, TeleportError> {
+    let total = addrs.len();
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut families = [v6, v4].into_iter().filter(|f| !f.is_empty());
+    let (tx, rx) = mpsc::channel();
+    if let Some(first) = families.next() {
+        attempt(first, &tx);
+    }
+    let mut next = families.next();
+
+    let mut failed = Vec::new();
+    while failed.len() < total {
+        let result = match next {
+            Some(_) => match rx.recv_timeout(delay) {
+                Ok(r) => r,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Some(family) = next.take() {
+                        debug!("No connection after {delay:?}, trying {family:?} too");
+                        attempt(family, &tx);
+                    }
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(r) => r,
+                Err(_) => break,
+            },
         };
+        match result {
+            (_, Ok(stream)) => return Ok(stream),
+            (addr, Err(e)) => {
+                debug!("Could not connect to {addr}: {e}");
+                failed.push(format!("{addr} ({e})"));
+                if let Some(family) = next.take() {
+                    attempt(family, &tx);
+                }
+            }
+        }
+    }
+
+    Err(TeleportError::ConnectFailed(failed.join(", ")))
+}
+
+/// Connect to `addrs` one after the other in the background, reporting each attempt until one
+/// connects. A connection made after another attempt already won is closed right away
+fn attempt(addrs: Vec<SocketAddr>, tx: &mpsc::Sender<(SocketAddr, io::Result<TcpStream>)>) {
+    let tx = tx.clone();
+    thread::spawn(move || {
+        for addr in addrs {
+            let result = TcpStream::connect(addr);
+            let connected = result.is_ok();
+            // Sending fails once the race is decided, which drops and closes the stream
+            if tx.send((addr, result)).is_err() || connected {
+                break;
+            }
+        }
+    });
+}
+
+/// How long the IPv6 addresses have to connect before the IPv4 ones are tried alongside them
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// How often progress is reported while chunks are sent over several connections
+const PROGRESS_POLL: Duration = Duration::from_millis(100);
+
+/// How long a connection may go without file data before it is pinged, unless --keepalive is given
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Longest wait between two retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Connect to the server and set up encryption
+fn open(opt: &SendOpt) -> Result<(TcpStream, u8, Option<TeleportEnc>), TeleportError> {
+    let mut stream = connect(opt)?;
+    stream.set_nodelay(!opt.nagle)?;
+    let mut version = PROTOCOL_VERSION;
+    let enc = handshake(&mut stream, &mut version, opt)?;
+
+    Ok((stream, version, enc))
+}
+
+/// Wait before retrying after a network error, doubling the delay each time. False once out of retries
+fn retry(e: &TeleportError, attempt: &mut u32, delay: &mut Duration, opt: &SendOpt) -> bool {
+    let network = matches!(
+        e,
+        TeleportError::Io(_) | TeleportError::InvalidDest | TeleportError::ConnectFailed(_)
+    );
+    if !network || *attempt >= opt.retries {
+        return false;
     }
 
-    Err(TeleportError::InvalidDest)
+    *attempt += 1;
+    warn!(
+        "{}, retrying in {:.2?} (attempt {}/{})",
+        e, delay, attempt, opt.retries
+    );
+    thread::sleep(*delay);
+    *delay = (*delay * 2).min(MAX_RETRY_DELAY);
+
+    true
 }
 
 /// Client function sends filename and file data for each filepath
-pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
-    print!("Teleporter Client {VERSION} => ");
+pub fn run(opt: SendOpt) -> Result<(), TeleportError> {
+    run_with_passphrase_source(opt, Some(ValueSource::CommandLine))
+}
+
+/// Like `run`, where a passphrase that did not come from the command line gives way to --plaintext
+pub fn run_with_passphrase_source(
+    mut opt: SendOpt,
+    source: Option<ValueSource>,
+) -> Result<(), TeleportError> {
+    info!("Teleporter Client {VERSION}");
+    if opt.plaintext && opt.passphrase.is_some() {
+        match source {
+            Some(ValueSource::CommandLine) => return Err(TeleportError::PassphrasePlaintext),
+            _ => opt.passphrase = None,
+        }
+    }
     let start_time = Instant::now();
     let mut sent = 0;
     let mut skip = 0;
+    let mut batch: Option<(TcpStream, u8, Option<TeleportEnc>)> = None;
+    let mut manifest = String::new();
 
     if opt.username.is_empty() {
-        println!(" => No username specified");
+        error!("No username specified");
         return Ok(());
     }
+    if opt.encrypt {
+        warn!("`--encrypt` is deprecated, transfers are encrypted unless --plaintext is given");
+    }
+    // Stream stdin as a single file whose size is only known once it ends
+    if opt.stdin {
+        return send_stdin(&opt);
+    }
+
+    // Expand any patterns the shell left alone, e.g. on Windows or when quoted
+    expand_globs(&mut opt)?;
+
     // Generate a list of replacement names and fix up the input list
     let rep = find_replacements(&mut opt);
-    println!("input: {:?}", &opt.input);
-    println!("rep: {:?}", &rep.new);
+    debug!("input: {:?}", &opt.input);
+    debug!("rep: {:?}", &rep.new);
 
     // Generate the file list
     let files = get_file_list(&opt);
 
     // If file list is empty, exit
     if files.is_empty() {
-        println!(" => No files to send. (Did you mean to add '-r'?)");
+        warn!("No files to send. (Did you mean to add '-r'?)");
         return Ok(());
     }
 
-    // For each filepath in the input vector...
-    for (num, item) in files.iter().enumerate() {
-        let file_time = Instant::now();
-
-        let mut enc: Option<TeleportEnc> = None;
-
-        let filepath = item;
-        let mut filename = filepath.clone().to_string();
-
-        // Locate and replace the filename of the transfer file, if renamed
-        for (idx, item) in rep.orig.iter().enumerate() {
-            if item.contains(&filepath.to_string()) {
-                filename = rep.new[idx].clone();
-            }
+    // Print what would be sent, then only ask the server about deltas if it can be reached
+    if opt.dry_run {
+        print_plan(&files, &rep, &opt)?;
+        if opt.no_delta || !reachable(&opt) {
+            return Ok(());
         }
+        info!("Asking {}:{} which chunks differ", opt.dest, opt.port);
+        opt.verify_only = true;
+    }
+
+    // For each filepath in the input vector...
+    'files: for (num, item) in files.iter().enumerate() {
+        let filepath = &item.path;
+        let filename = remote_name(item, &rep, &opt);
 
         // Validate file
         let file = match File::open(filepath) {
             Ok(f) => f,
             Err(s) => {
-                println!("Error opening file: {filepath}");
+                error!("Error opening file: {filepath}");
                 return Err(TeleportError::Io(s));
             }
         };
 
-        let thread_file = File::open(filepath)?;
-        // Skip if opt.no_delta present, otherwise calculate the delta hash of the file
-        let handle = match opt.overwrite && !opt.no_delta {
-            true => Some(thread::spawn(move || {
-                TeleportDelta::delta_hash(&thread_file).unwrap()
-            })),
-            false => None,
-        };
+        // Calculate the delta hash of the file, the whole file hash is verified by the server
+        let local_delta = TeleportDelta::delta_hash(&file, hash_algorithm(&opt))?;
+
+        let header = build_header(&file, &filename, local_delta.hash, &opt)?;
+
+        // Reconnect after network errors, the server resumes the file if --resume is given
+        let mut attempt = 0;
+        let mut delay = opt.retry_delay;
+        let (stream, version, enc, keep, stats) = loop {
+            // Reuse the connection of the previous file in batch mode, otherwise connect to server
+            let (mut stream, mut version, enc) = match batch.take() {
+                Some(b) => b,
+                None => match open(&opt) {
+                    Ok(c) => c,
+                    Err(TeleportError::Refused(s)) if is_fatal(s) => break 'files,
+                    Err(TeleportError::Refused(_)) => continue 'files,
+                    Err(e) if retry(&e, &mut attempt, &mut delay, &opt) => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+
+            // Announce the file, skipping it or giving up if the server refuses
+            let recv = match offer(&mut stream, &header, &mut version, &enc, &opt) {
+                Ok(r) => r,
+                Err(TeleportError::Refused(s)) => {
+                    events::emit(
+                        "ack",
+                        json!({ "filename": filename, "status": format!("{s:?}") }),
+                    );
+                    match is_fatal(s) {
+                        true => break 'files,
+                        false => continue 'files,
+                    }
+                }
+                Err(e) if retry(&e, &mut attempt, &mut delay, &opt) => continue,
+                Err(e) => return Err(e),
+            };
+
+            // The server keeps the connection open after this file if it supports batching
+            let keep = opt.batch && TeleportFeatures::Batch.check(&recv.features);
+
+            if num == 0 && attempt == 0 {
+                info!("Server {}", recv.version);
+                if let Some(ctx) = &enc {
+                    info!("Server key fingerprint: {}", ctx.fingerprint());
+                }
+                events::emit(
+                    "connect",
+                    json!({
+                        "server": format!("{}:{}", opt.dest, opt.port),
+                        "version": recv.version.to_string(),
+                        "fingerprint": enc.as_ref().map(|ctx| ctx.fingerprint()),
+                    }),
+                );
+            }
+            events::emit("ack", json!({ "filename": filename, "status": "Proceed" }));
+
+            // Compare against the server's copy and hang up without sending anything
+            if opt.verify_only {
+                match verify(&file, &filename, &recv, &local_delta, &opt)? {
+                    true => skip += 1,
+                    false => sent += 1,
+                }
+                continue 'files;
+            }
+
+            info!("Sending file {}/{}: {}", num + 1, files.len(), &filename);
 
-        // Remove all path info if !opt.keep_path
-        if !opt.keep_path {
-            filename = Path::new(&filename)
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
+            let json_updates = JsonUpdates::new(&filename);
+            let print_updates = PrintUpdates::new();
+            let progress: Option<&dyn ProgressReporter> = match events::json() {
+                true => Some(&json_updates),
+                false => Some(&print_updates),
+            };
+            match transfer(
+                &mut stream,
+                file.try_clone()?,
+                &filename,
+                version,
+                &enc,
+                recv,
+                local_delta.clone(),
+                &opt,
+                progress,
+            ) {
+                Ok(s) => break (stream, version, enc, keep, s),
+                Err(TeleportError::Refused(TeleportStatus::HashMismatch)) => {
+                    error!(" => The server rejected the file, hash mismatch: {filename}");
+                    events::emit(
+                        "error",
+                        json!({ "filename": filename, "message": "hash mismatch" }),
+                    );
+                    continue 'files;
+                }
+                Err(TeleportError::Refused(TeleportStatus::Incomplete)) => {
+                    error!(" => The server received less data than the size of: {filename}");
+                    events::emit(
+                        "error",
+                        json!({ "filename": filename, "message": "incomplete transfer" }),
+                    );
+                    continue 'files;
+                }
+                Err(TeleportError::Refused(s)) => {
+                    warn!(" => The server did not accept the transfer of: {filename} (server responded: {s})");
+                    events::emit(
+                        "error",
+                        json!({ "filename": filename, "message": format!("server responded: {s}") }),
+                    );
+                    continue 'files;
+                }
+                Err(e) if retry(&e, &mut attempt, &mut delay, &opt) => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        match stats.skipped {
+            true => skip += 1,
+            false => sent += 1,
+        }
+        if keep {
+            batch = Some((stream, version, enc));
+        }
+        if opt.checksum_manifest.is_some() {
+            let hash = TeleportDelta::digest(&file, hash_algorithm(&opt))?;
+            manifest.push_str(&manifest::line(&hash, &filename));
         }
 
-        // Populate features
-        let meta = file.metadata()?;
-        let mut header = TeleportInit::new(TeleportFeatures::NewFile);
-        let mut features: u32 = 0;
+        // Print file transfer statistics
+        info!(
+            " done! Time: {:.2?} Speed: {:.3} Mbps",
+            stats.duration, stats.mbps
+        );
+        events::emit(
+            "complete",
+            json!({
+                "filename": stats.filename,
+                "bytes": stats.bytes,
+                "seconds": stats.duration.as_secs_f64(),
+                "mbps": stats.mbps,
+                "skipped": stats.skipped,
+                "delta": stats.used_delta,
+                "encrypted": stats.used_encryption,
+            }),
+        );
+    }
 
-        // Add delta flag by default
-        if !opt.no_delta {
-            TeleportFeatures::Delta.add_u32(&mut features);
+    // Tell the server no more files are coming over the batch connection
+    if let Some((mut stream, version, enc)) = batch {
+        if let Err(e) =
+            utils::send_packet(&mut stream, TeleportAction::Done, version, &enc, Vec::new())
+        {
+            debug!("Could not end the batch: {e:?}");
         }
+    }
+    if let Some(path) = &opt.checksum_manifest {
+        fs::write(path, &manifest)?;
+        info!("Wrote checksum manifest: {}", path.display());
+    }
+    let total_time = start_time.elapsed();
+    let done = match opt.verify_only {
+        true => "different",
+        false => "sent",
+    };
+    events::emit(
+        "summary",
+        json!({ done: sent, "same": skip, "total": sent + skip, "seconds": total_time.as_secs_f64() }),
+    );
+    if opt.verify_only {
+        info!(
+            "Verified {}/{}/{} Different/Same/Total in {:.2?}",
+            sent,
+            skip,
+            sent + skip,
+            total_time
+        );
+        return Ok(());
+    }
+    info!(
+        "Teleported {}/{}/{} Sent/Same/Total in {:.2?}",
+        sent,
+        skip,
+        sent + skip,
+        total_time
+    );
+    Ok(())
+}
+
+/// Options for sending a single file with `send_file`
+#[derive(Clone, Default)]
+pub struct SendOptions<'a> {
+    /// Name to give the file on the server [default: the file name of the path]
+    pub filename: Option<String>,
+    /// Username to identify as on the remote server
+    pub username: String,
+    /// Shared secret token to authenticate the username with the server
+    pub token: Option<String>,
+    /// Overwrite the remote file
+    pub overwrite: bool,
+    /// Backup the remote file to a ".bak" extension when overwriting it
+    pub backup: bool,
+    /// Append a ".1" (or next available number) to the remote filename instead of overwriting
+    pub filename_append: bool,
+    /// Send without encryption, only accepted by servers that allow plaintext
+    pub plaintext: bool,
+    /// Disable delta transfer
+    pub no_delta: bool,
+    /// Compress file data with zstd
+    pub compress: bool,
+    /// Resume an interrupted transfer from the partial file left on the remote server
+    pub resume: bool,
+    /// Give the received file the same uid and gid, if the server is privileged to
+    pub preserve_owner: bool,
+    /// Send the file's extended attributes for the server to reapply
+    pub preserve_xattrs: bool,
+    /// Checksum each chunk of a plaintext transfer for the server to verify
+    pub chunk_checksums: bool,
+    /// Bytes of file data per chunk when not sending a delta [default: 4096]
+    pub chunk_size: Option<usize>,
+    /// Limit upload bandwidth in bytes/sec
+    pub rate_limit: Option<u64>,
+    /// Hash with BLAKE3 instead of xxh3 for delta matching and verification
+    pub strong_hash: bool,
+    /// Abort unless the server's key fingerprint matches this one
+    pub pin: Option<String>,
+    /// Pre-shared passphrase mixed into the encryption key
+    pub passphrase: Option<String>,
+    /// Receives progress updates as file data is sent
+    pub progress: Option<&'a dyn ProgressReporter>,
+}
 
-        // Add overwrite flag if enabled
-        if opt.overwrite {
-            TeleportFeatures::Overwrite.add_u32(&mut features);
+impl SendOptions<'_> {
+    /// The equivalent command line options for a single file
+    fn to_opt(&self) -> SendOpt {
+        SendOpt {
+            input: Vec::new(),
+            dest: String::new(),
+            port: 0,
+            ipv4: false,
+            ipv6: false,
+            overwrite: self.overwrite,
+            recursive: false,
+            stdin: false,
+            name: None,
+            exclude: Vec::new(),
+            follow_symlinks: false,
+            plaintext: self.plaintext,
+            encrypt: false,
+            no_delta: self.no_delta,
+            keep_path: false,
+            flat: false,
+            backup: self.backup,
+            filename_append: self.filename_append,
+            compress: self.compress,
+            resume: self.resume,
+            preserve_owner: self.preserve_owner,
+            preserve_xattrs: self.preserve_xattrs,
+            chunk_checksums: self.chunk_checksums,
+            username: self.username.clone(),
+            token: self.token.clone(),
+            chunk_size: self.chunk_size,
+            rate_limit: self.rate_limit,
+            strong_hash: self.strong_hash,
+            batch: false,
+            streams: 1,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            pin: self.pin.clone(),
+            passphrase: self.passphrase.clone(),
+            nagle: false,
+            keepalive: KEEPALIVE,
+            verify_only: false,
+            dry_run: false,
+            checksum_manifest: None,
         }
+    }
+}
 
-        // Add backup flag if enabled
-        if opt.backup {
-            TeleportFeatures::Backup.add_u32(&mut features);
+/// Send a single file over an already connected stream
+pub fn send_file(
+    mut stream: TcpStream,
+    path: &Path,
+    opts: SendOptions,
+) -> Result<TransferStats, TeleportError> {
+    if opts.username.is_empty() {
+        return Err(TeleportError::InvalidUserName);
+    }
+    if let Some(size) = opts.chunk_size {
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+            return Err(TeleportError::InvalidLength);
         }
+    }
+
+    let filename = match (&opts.filename, path.file_name()) {
+        (Some(f), _) => f.clone(),
+        (None, Some(f)) => f.to_string_lossy().to_string(),
+        (None, None) => return Err(TeleportError::InvalidFileName),
+    };
+
+    let opt = opts.to_opt();
+    let file = File::open(path)?;
+    let local_delta = TeleportDelta::delta_hash(&file, hash_algorithm(&opt))?;
+    let header = build_header(&file, &filename, local_delta.hash, &opt)?;
 
-        // Add rename flag if enabled
-        if opt.filename_append {
-            TeleportFeatures::Rename.add_u32(&mut features);
+    let mut version = PROTOCOL_VERSION;
+    let enc = handshake(&mut stream, &mut version, &opt)?;
+    let recv = offer(&mut stream, &header, &mut version, &enc, &opt)?;
+    transfer(
+        &mut stream,
+        file,
+        &filename,
+        version,
+        &enc,
+        recv,
+        local_delta,
+        &opt,
+        opts.progress,
+    )
+}
+
+/// Report how much of the file differs from the server's copy, true if they are identical
+fn verify(
+    file: &File,
+    filename: &str,
+    recv: &TeleportInitAck,
+    local: &TeleportDelta,
+    opt: &SendOpt,
+) -> Result<bool, TeleportError> {
+    let remote = match &recv.delta {
+        Some(d) => d,
+        None => {
+            info!(
+                " => {}: not on the server, all {} differ",
+                filename,
+                utils::format_size(local.filesize)
+            );
+            events::emit(
+                "verify",
+                json!({ "filename": filename, "exists": false, "bytes": local.filesize }),
+            );
+            return Ok(local.filesize == 0);
         }
-        header.features = features;
-        header.chmod = meta.permissions().mode();
-        header.filesize = meta.len();
-        header.filename = filename.as_bytes().to_vec();
-        header.username = opt.username.as_bytes().to_vec();
-        
+    };
 
-        // Connect to server
-        let addr = match format!("{}:{}", opt.dest, opt.port).to_socket_addrs() {
-            Ok(a) => a,
-            Err(_) => {
-                return Err(TeleportError::InvalidDest);
-            }
-        };
-        let mut stream = connect_to_client(addr)?;
-
-        // If encrypt is enabled
-        if opt.encrypt {
-            // Generate EC keypair
-            let mut ctx = TeleportEnc::new();
-            let privkey = crypto::genkey(&mut ctx);
-            // Send pubkey
-            utils::send_packet(&mut stream, TeleportAction::Ecdh, &None, ctx.serialize())?;
-            // Receive remote pubkey and generate session secret
-            let packet = utils::recv_packet(&mut stream, &None)?;
-            if packet.action == TeleportAction::EcdhAck as u8 {
-                ctx.deserialize(&packet.data)?;
-                ctx.calc_secret(privkey);
-                enc = Some(ctx);
-            }
+    // Rehash the local file if the chunk sizes do not line up
+    let rehashed;
+    let local = match local.chunk_size == remote.chunk_size {
+        true => local,
+        false => {
+            let size = remote.chunk_size as usize;
+            rehashed =
+                TeleportDelta::delta_hash_chunks(file, local.filesize, size, hash_algorithm(opt))?;
+            &rehashed
         }
+    };
+
+    let changed = remote.changed_chunks(local);
+    let chunk = remote.chunk_size as u64;
+    let bytes: u64 = changed
+        .iter()
+        .map(|&i| chunk.min(local.filesize - i as u64 * chunk))
+        .sum();
+    info!(
+        " => {}: {} of {} chunks differ ({} to send)",
+        filename,
+        changed.len(),
+        local.chunk_hash.len(),
+        utils::format_size(bytes)
+    );
+    events::emit(
+        "verify",
+        json!({
+            "filename": filename,
+            "exists": true,
+            "changed": changed.len(),
+            "chunks": local.chunk_hash.len(),
+            "bytes": bytes,
+            "same": remote.hash == local.hash,
+        }),
+    );
 
-        // Send header first
-        utils::send_packet(&mut stream, TeleportAction::Init, &enc, header.serialize()?)?;
+    Ok(remote.hash == local.hash)
+}
 
-        // Receive response from server
-        let packet = utils::recv_packet(&mut stream, &enc)?;
-        let mut recv = TeleportInitAck::new(TeleportStatus::Proceed);
-        recv.deserialize(&packet.data)?;
-        if let Some(ref x) = recv.delta {
-            println!("[recv][delta] filesize: {}, hash: {}, chunk_size: {}, chunk_hash: {:?}", x.filesize, x.hash, x.chunk_size, x.chunk_hash);
+/// Hash algorithm the client was asked to use
+fn hash_algorithm(opt: &SendOpt) -> HashAlgorithm {
+    match opt.strong_hash {
+        true => HashAlgorithm::Blake3,
+        false => HashAlgorithm::Xxh3,
+    }
+}
+
+/// Build the header announcing a file to the server
+fn build_header(
+    file: &File,
+    filename: &str,
+    hash: u64,
+    opt: &SendOpt,
+) -> Result<TeleportInit, TeleportError> {
+    // Populate features
+    let meta = file.metadata()?;
+    let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+    let mut features: u32 = 0;
+
+    // Add delta flag by default, overwriting finds moved chunks with a rolling delta
+    if !opt.no_delta {
+        TeleportFeatures::Delta.add_u32(&mut features);
+        if opt.overwrite && !opt.verify_only {
+            TeleportFeatures::RollingDelta.add_u32(&mut features);
         }
-        
+    }
+
+    // Add overwrite flag if enabled, verifying needs it to get the delta of an existing file
+    if opt.overwrite || opt.verify_only {
+        TeleportFeatures::Overwrite.add_u32(&mut features);
+    }
+
+    // Add backup flag if enabled
+    if opt.backup {
+        TeleportFeatures::Backup.add_u32(&mut features);
+    }
+
+    // Add rename flag if enabled
+    if opt.filename_append {
+        TeleportFeatures::Rename.add_u32(&mut features);
+    }
+
+    // Add compress flag if enabled
+    if opt.compress {
+        TeleportFeatures::Compress.add_u32(&mut features);
+    }
+
+    // Add resume flag if enabled
+    if opt.resume {
+        TeleportFeatures::Resume.add_u32(&mut features);
+    }
+
+    // Add strong hash flag if enabled
+    if opt.strong_hash {
+        TeleportFeatures::StrongHash.add_u32(&mut features);
+    }
+
+    // Ask the server to verify each chunk, encrypted data is already authenticated
+    if opt.chunk_checksums && opt.plaintext {
+        TeleportFeatures::ChunkChecksum.add_u32(&mut features);
+    }
+
+    // Ask to keep the connection open for the next file
+    if opt.batch {
+        TeleportFeatures::Batch.add_u32(&mut features);
+    }
 
-        if num == 0 {
-            println!("Server {}", recv.version);
+    // Ask for an id to send chunks over extra connections
+    if opt.streams > 1 {
+        TeleportFeatures::Streams.add_u32(&mut features);
+    }
+    // Add modification time so the server can preserve it
+    if let Ok(mtime) = meta.modified() {
+        if let Ok(since) = mtime.duration_since(UNIX_EPOCH) {
+            TeleportFeatures::PreserveTimes.add_u32(&mut features);
+            header.mtime = since.as_secs();
         }
+    }
 
-        // Validate response
-        match recv.status.try_into()? {
-            TeleportStatus::NoOverwrite => {
-                println!("The server refused to overwrite the file: {}", &filename);
-                continue;
-            }
-            TeleportStatus::NoPermission => {
-                println!(
-                    "The server does not have permission to write to this file: {}",
-                    &filename
-                );
-                continue;
-            }
-            TeleportStatus::NoSpace => {
-                println!(
-                    "The server has no space available to write the file: {}",
-                    &filename
+    // Add the owner so a privileged server can restore it
+    #[cfg(unix)]
+    if opt.preserve_owner {
+        use std::os::unix::fs::MetadataExt;
+        TeleportFeatures::PreserveOwner.add_u32(&mut features);
+        header.uid = meta.uid();
+        header.gid = meta.gid();
+    }
+
+    // Add the extended attributes unless there are none or they do not fit in the header
+    if opt.preserve_xattrs {
+        match xattr::read(file) {
+            Ok(x) if x.is_empty() => {}
+            Ok(x) if xattr::serialized_size(&x) > xattr::MAX_XATTR_SIZE => {
+                warn!(
+                    "Not sending extended attributes larger than {} bytes: {}",
+                    xattr::MAX_XATTR_SIZE,
+                    filename
                 );
-                continue;
             }
-            TeleportStatus::WrongVersion => {
-                println!("Version mismatch! Server: {} Us: {}", recv.version, VERSION);
-                break;
+            Ok(x) => {
+                TeleportFeatures::PreserveXattr.add_u32(&mut features);
+                header.xattrs = x;
             }
-            TeleportStatus::RequiresEncryption => {
-                println!("The server requires encryption");
-                break;
+            Err(e) => warn!("Could not read extended attributes of {}: {}", filename, e),
+        }
+    }
+    header.features = features;
+    header.chmod = utils::file_mode(&meta.permissions());
+    header.filesize = meta.len();
+    header.whole_hash = hash;
+    header.filename = filename.as_bytes().to_vec();
+    header.username = opt.username.as_bytes().to_vec();
+    if let Some(token) = &opt.token {
+        header.token = token.as_bytes().to_vec();
+    }
+
+    Ok(header)
+}
+
+/// Refusals that end the whole session rather than skipping a single file
+fn is_fatal(status: TeleportStatus) -> bool {
+    matches!(
+        status,
+        TeleportStatus::WrongVersion
+            | TeleportStatus::RequiresEncryption
+            | TeleportStatus::EncryptionError
+            | TeleportStatus::ServerBusy
+            | TeleportStatus::UnknownAction
+    )
+}
+
+/// Run the ECDH key exchange with the server and return the session context, `version` becomes
+/// the wire format version the server answered in
+pub(crate) fn key_exchange(
+    stream: &mut TcpStream,
+    version: &mut u8,
+    pin: Option<&str>,
+    passphrase: Option<&str>,
+) -> Result<TeleportEnc, TeleportError> {
+    // Generate EC keypair
+    let mut ctx = TeleportEnc::new()?;
+    let privkey = crypto::genkey(&mut ctx)?;
+    // Send pubkey
+    utils::send_packet(
+        stream,
+        TeleportAction::Ecdh,
+        *version,
+        &None,
+        ctx.serialize(),
+    )?;
+    // Receive remote pubkey and generate session secret
+    let packet = utils::recv_packet(stream, *version, &None)?;
+    *version = packet.version;
+    match TeleportAction::try_from(packet.action)? {
+        TeleportAction::EcdhAck => {
+            ctx.deserialize(&packet.data)?;
+            ctx.calc_secret(&privkey);
+            ctx.bind_remote_identity(&privkey)?;
+            if let Some(p) = passphrase {
+                ctx.bind_passphrase(p.as_bytes());
             }
-            TeleportStatus::EncryptionError => {
-                println!("Error initializing encryption handshake");
-                break;
+
+            // Trust on first use, unless the user pinned the key they expect
+            let fingerprint = ctx.fingerprint();
+            debug!("Server key fingerprint: {}", fingerprint);
+            if let Some(pin) = pin {
+                if !crypto::fingerprint_matches(&fingerprint, pin) {
+                    error!(
+                        "The server key fingerprint {} does not match the pinned {}",
+                        fingerprint, pin
+                    );
+                    return Err(TeleportError::KeyMismatch);
+                }
             }
-            _ => (),
-        };
+            Ok(ctx)
+        }
+        TeleportAction::InitAck => {
+            // The server refused the connection before the key exchange
+            let mut recv = TeleportInitAck::default();
+            recv.deserialize(&packet.data)?;
+            let status: TeleportStatus = recv.status.try_into()?;
+            error!("The server responded: {}", status);
+            Err(TeleportError::Refused(status))
+        }
+        action => {
+            // Anything else would leave the key, and the pin, unchecked
+            error!("Unexpected response to the key exchange: {}", action);
+            Err(TeleportError::EncryptionFailure)
+        }
+    }
+}
 
-        // If TeleportDelta was received, else None
-        let csum_recv = recv.delta.as_ref().map(|r| r.hash);
-        let mut file_delta: Option<TeleportDelta> = None;
-        if TeleportFeatures::Overwrite.check(&recv.features) {
-            file_delta = handle.map(|s| s.join().expect("calc_file_hash panicked"));
+/// Set up encryption unless plaintext was requested
+fn handshake(
+    stream: &mut TcpStream,
+    version: &mut u8,
+    opt: &SendOpt,
+) -> Result<Option<TeleportEnc>, TeleportError> {
+    match opt.plaintext {
+        true => Ok(None),
+        false => key_exchange(
+            stream,
+            version,
+            opt.pin.as_deref(),
+            opt.passphrase.as_deref(),
+        )
+        .map(Some),
+    }
+}
+
+/// Send the header and check the server's response, which settles `version` on plaintext connections
+fn offer(
+    stream: &mut TcpStream,
+    header: &TeleportInit,
+    version: &mut u8,
+    enc: &Option<TeleportEnc>,
+    opt: &SendOpt,
+) -> Result<TeleportInitAck, TeleportError> {
+    let filename = String::from_utf8_lossy(&header.filename);
+
+    // Send header first
+    utils::send_packet(
+        stream,
+        TeleportAction::Init,
+        *version,
+        enc,
+        header.serialize()?,
+    )?;
+
+    // Receive response from server, which refuses a key it cannot use in plaintext
+    let packet = match utils::recv_packet(stream, *version, enc) {
+        Ok(p) => p,
+        Err(TeleportError::DecryptionFailure) => {
+            error!("Error initializing encryption handshake, check the passphrase");
+            return Err(TeleportError::Refused(TeleportStatus::EncryptionError));
+        }
+        Err(e) => return Err(e),
+    };
+    *version = packet.version;
+    let mut recv = TeleportInitAck::new(TeleportStatus::Proceed);
+    recv.deserialize(&packet.data)?;
+    if let Some(ref x) = recv.delta {
+        debug!(
+            "[recv][delta] filesize: {}, hash: {}, chunk_size: {}, chunk_hash: {:?}",
+            x.filesize, x.hash, x.chunk_size, x.chunk_hash
+        );
+    }
+
+    // Validate response
+    let status: TeleportStatus = recv.status.try_into()?;
+    match status {
+        TeleportStatus::NoOverwrite => {
+            warn!("The server refused to overwrite the file: {}", &filename);
+        }
+        TeleportStatus::NoPermission => {
+            warn!(
+                "The server does not have permission to write to this file: {}",
+                &filename
+            );
+        }
+        TeleportStatus::NoSpace => {
+            warn!(
+                "The server has no space available to write the file: {}",
+                &filename
+            );
         }
+        TeleportStatus::WrongVersion => {
+            error!(
+                "Version mismatch! Server: {} (wire v{}) Us: {} (wire v{})",
+                recv.version, packet.version, VERSION, PROTOCOL_VERSION
+            );
+        }
+        TeleportStatus::RequiresEncryption => {
+            error!("The server requires encryption");
+        }
+        TeleportStatus::EncryptionError => {
+            error!("Error initializing encryption handshake, check the passphrase");
+        }
+        TeleportStatus::UnknownUser => {
+            warn!(
+                "The server rejected unknown user: {}, skipping: {}",
+                opt.username, &filename
+            );
+        }
+        TeleportStatus::ServerBusy => {
+            error!("The server is busy, try again later");
+        }
+        TeleportStatus::TooLarge => {
+            warn!("The server does not accept files this large: {}", &filename);
+        }
+        TeleportStatus::UnknownAction => {
+            error!("The server did not expect this request, check both run the same version");
+        }
+        TeleportStatus::Proceed => {
+            // The whole file hash was already sent as BLAKE3, an older server cannot verify it
+            if opt.strong_hash && !TeleportFeatures::StrongHash.check(&recv.features) {
+                error!("The server does not support --strong-hash");
+                return Err(TeleportError::StrongHashUnsupported);
+            }
+            return Ok(recv);
+        }
+        s => {
+            warn!("The server responded: {} for file: {}", s, &filename);
+        }
+    };
 
-        println!("Sending file {}/{}: {}", num + 1, files.len(), &filename);
+    Err(TeleportError::Refused(status))
+}
 
-        if csum_recv.is_some()
-            && file_delta.is_some()
-            && file_delta.as_ref().unwrap().hash == csum_recv.unwrap()
-        {
-            // File matches hash
-            send_data_complete(stream, &enc, header.filesize)?;
-            skip += 1;
-        } else {
-            // Send file data
-            send(stream, file, &header, &enc, recv.delta, file_delta)?;
-            sent += 1;
+/// Send stdin to the server as `--name`, its size and hash follow in the final chunk
+fn send_stdin(opt: &SendOpt) -> Result<(), TeleportError> {
+    let filename = opt.name.clone().unwrap_or_default();
+    let (mut stream, mut version, enc) = open(opt)?;
+    info!("Sending stdin as: {filename}");
+
+    let mut header = TeleportInit::new(TeleportFeatures::NewFile);
+    let mut features = TeleportFeatures::UnknownSize as u32;
+    for (set, flag) in [
+        (opt.overwrite, TeleportFeatures::Overwrite),
+        (opt.backup, TeleportFeatures::Backup),
+        (opt.filename_append, TeleportFeatures::Rename),
+        (opt.strong_hash, TeleportFeatures::StrongHash),
+        (
+            opt.chunk_checksums && opt.plaintext,
+            TeleportFeatures::ChunkChecksum,
+        ),
+    ] {
+        if set {
+            flag.add_u32(&mut features);
         }
+    }
+    header.features = features;
+    header.chmod = 0o644;
+    header.filename = filename.as_bytes().to_vec();
+    header.username = opt.username.as_bytes().to_vec();
+    if let Some(token) = &opt.token {
+        header.token = token.as_bytes().to_vec();
+    }
 
-        // Print file transfer statistics
-        let duration = file_time.elapsed();
-        let speed = (header.filesize as f64 * 8.0) / duration.as_secs() as f64 / 1024.0 / 1024.0;
-        println!(" done! Time: {duration:.2?} Speed: {speed:.3} Mbps");
+    let recv = offer(&mut stream, &header, &mut version, &enc, opt)?;
+    if !TeleportFeatures::UnknownSize.check(&recv.features) {
+        error!("The server does not accept --stdin");
+        return Err(TeleportError::UnknownSizeUnsupported);
     }
-    let total_time = start_time.elapsed();
-    println!(
-        "Teleported {}/{}/{} Sent/Same/Total in {:.2?}",
-        sent,
-        skip,
-        sent + skip,
-        total_time
+    let checksum = TeleportFeatures::ChunkChecksum.check(&recv.features);
+
+    // Send whatever arrives as its own chunk, hashing exactly the bytes sent
+    let start_time = Instant::now();
+    let mut hasher = hash_algorithm(opt).hasher();
+    let mut bucket = opt.rate_limit.map(utils::TokenBucket::new);
+    let mut keepalive = Keepalive::new(opt.keepalive);
+    let mut buf = vec![0; opt.chunk_size.unwrap_or(4096)];
+    let mut stdin = io::stdin().lock();
+    let mut offset: u64 = 0;
+    let mut seq: u64 = 0;
+    loop {
+        let len = match stdin.read(&mut buf) {
+            Ok(0) => break,
+            Ok(l) => l,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(TeleportError::Io(e)),
+        };
+        hasher.write(&buf[..len]);
+        let chunk = TeleportData {
+            offset,
+            seq,
+            flags: 0,
+            data_len: len as u32,
+            checksum: 0,
+            data: buf[..len].to_vec(),
+        };
+        let chunk = match checksum {
+            true => chunk.with_checksum(),
+            false => chunk,
+        };
+        offset += len as u64;
+        seq += 1;
+
+        if let Some(b) = bucket.as_mut() {
+            b.take(len);
+        }
+        keepalive.check(&mut stream, version, &enc)?;
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Data,
+            version,
+            &enc,
+            chunk.serialize()?,
+        )?;
+        keepalive.sent();
+    }
+
+    let hash = hasher.finish();
+    let chunk = TeleportData {
+        offset,
+        seq,
+        flags: TeleportData::FINAL,
+        data_len: 8,
+        checksum: 0,
+        data: hash.to_le_bytes().to_vec(),
+    };
+    utils::send_packet(
+        &mut stream,
+        TeleportAction::Data,
+        version,
+        &enc,
+        chunk.serialize()?,
+    )?;
+    recv_complete(&mut stream, version, &enc)?;
+
+    let duration = start_time.elapsed();
+    let mbps = utils::mbps(offset, duration);
+    info!(
+        " => Sent {} from stdin. Time: {:.2?} Speed: {:.3} Mbps",
+        utils::format_size(offset),
+        duration,
+        mbps
+    );
+    events::emit(
+        "complete",
+        json!({
+            "filename": filename,
+            "bytes": offset,
+            "seconds": duration.as_secs_f64(),
+            "mbps": mbps,
+            "skipped": false,
+            "delta": false,
+            "encrypted": enc.is_some(),
+        }),
     );
+
     Ok(())
 }
 
+/// Send the file data, or only the completion marker if the server already has the same file
+#[allow(clippy::too_many_arguments)]
+fn transfer(
+    stream: &mut TcpStream,
+    file: File,
+    filename: &str,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    recv: TeleportInitAck,
+    local_delta: TeleportDelta,
+    opt: &SendOpt,
+    progress: Option<&dyn ProgressReporter>,
+) -> Result<TransferStats, TeleportError> {
+    let start_time = Instant::now();
+    let filesize = local_delta.filesize;
+    let hash = local_delta.hash;
+
+    // If TeleportDelta was received, else None
+    let csum_recv = recv.delta.as_ref().map(|r| r.hash);
+    let mut file_delta: Option<TeleportDelta> = None;
+    // Skip if opt.no_delta present
+    if opt.overwrite && !opt.no_delta && TeleportFeatures::Overwrite.check(&recv.features) {
+        file_delta = Some(local_delta);
+    }
+
+    let used_delta = csum_recv.is_some() && file_delta.is_some();
+    let skipped =
+        filesize > 0 && used_delta && file_delta.as_ref().unwrap().hash == csum_recv.unwrap();
+    if skipped {
+        // File matches hash
+        send_data_complete(stream, version, enc, filesize, 0)?;
+    } else {
+        // Send file data
+        send(stream, file, version, enc, recv, file_delta, opt, progress)?;
+    }
+    recv_complete(stream, version, enc)?;
+
+    let duration = start_time.elapsed();
+    Ok(TransferStats {
+        filename: filename.to_string(),
+        bytes: filesize,
+        duration,
+        mbps: utils::mbps(filesize, duration),
+        hash,
+        skipped,
+        used_delta,
+        used_encryption: enc.is_some(),
+    })
+}
+
+/// Mark the end of the file data, after `seq` chunks were sent
 fn send_data_complete(
-    mut stream: TcpStream,
+    stream: &mut TcpStream,
+    version: u8,
     enc: &Option<TeleportEnc>,
     filesize: u64,
+    seq: u64,
 ) -> Result<(), TeleportError> {
-    let mut chunk = TeleportData {
+    let chunk = TeleportData {
         offset: filesize,
+        seq,
+        flags: TeleportData::FINAL,
         data_len: 0,
+        checksum: 0,
         data: Vec::<u8>::new(),
     };
 
     // Send the data chunk
-    utils::send_packet(&mut stream, TeleportAction::Data, enc, chunk.serialize()?)?;
+    utils::send_packet(
+        stream,
+        TeleportAction::Data,
+        version,
+        enc,
+        chunk.serialize()?,
+    )?;
 
     Ok(())
 }
 
+/// Receive the server's verification of the completed file
+fn recv_complete(
+    stream: &mut TcpStream,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+) -> Result<(), TeleportError> {
+    let packet = match utils::recv_packet(stream, version, enc) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!(" => The server did not confirm the transfer");
+            return Err(e);
+        }
+    };
+
+    let mut ack = TeleportInitAck::default();
+    ack.deserialize(&packet.data)?;
+    match ack.status.try_into()? {
+        TeleportStatus::Proceed => Ok(()),
+        s => Err(TeleportError::Refused(s)),
+    }
+}
+
+/// Validate the partial file reported by the server and return the offset to resume from
+fn resume_offset(file: &File, recv: &TeleportInitAck) -> Result<u64, TeleportError> {
+    if !TeleportFeatures::Resume.check(&recv.features) {
+        return Ok(0);
+    }
+
+    let (offset, hash) = match (recv.resume_offset, recv.resume_hash) {
+        (Some(o), Some(h)) => (o, h),
+        _ => return Ok(0),
+    };
+
+    if offset == 0 || offset > file.metadata()?.len() {
+        return Ok(0);
+    }
+
+    // A different local file restarts the transfer from the beginning
+    let algo = HashAlgorithm::from_features(recv.features.unwrap_or(0));
+    let partial = TeleportDelta::delta_hash_len(file, offset, algo)?;
+    if partial.hash != hash {
+        warn!(" => Remote partial file does not match, restarting transfer");
+        return Ok(0);
+    }
+
+    Ok(offset)
+}
+
+/// Read until `buf` is full or the file ends, a buffered reader may return less per call
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, TeleportError> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(TeleportError::Io(e)),
+        }
+    }
+
+    Ok(len)
+}
+
+/// Pings the server when a connection has not carried file data for a while
+struct Keepalive {
+    interval: Duration,
+    last: Instant,
+}
+
+impl Keepalive {
+    fn new(interval: Duration) -> Self {
+        Keepalive {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Ping the server and wait for its answer if nothing was sent for the whole interval
+    fn check(
+        &mut self,
+        stream: &mut TcpStream,
+        version: u8,
+        enc: &Option<TeleportEnc>,
+    ) -> Result<(), TeleportError> {
+        if self.last.elapsed() < self.interval {
+            return Ok(());
+        }
+
+        debug!("Sending keepalive ping");
+        utils::send_packet(stream, TeleportAction::Ping, version, enc, Vec::new())?;
+        let packet = utils::recv_packet(stream, version, enc)?;
+        if packet.action != TeleportAction::PingAck as u8 {
+            return Err(TeleportError::InvalidProtocol);
+        }
+        self.sent();
+
+        Ok(())
+    }
+
+    /// Note that the connection just carried data
+    fn sent(&mut self) {
+        self.last = Instant::now();
+    }
+}
+
+/// A file whose chunks are sent over several connections at once
+struct Parallel<'a> {
+    file: &'a File,
+    filesize: u64,
+    chunk_size: usize,
+    compress: bool,
+    checksum: bool,
+    // Bytes sent over all of the connections so far
+    sent: AtomicU64,
+}
+
+impl Parallel<'_> {
+    /// Split the chunks between up to `opt.streams` connections, returns how many were sent
+    #[allow(clippy::too_many_arguments)]
+    fn send(
+        &self,
+        stream: &mut TcpStream,
+        version: u8,
+        enc: &Option<TeleportEnc>,
+        id: [u8; STREAM_ID_LEN],
+        offsets: Vec<u64>,
+        opt: &SendOpt,
+        progress: Option<&dyn ProgressReporter>,
+    ) -> Result<u64, TeleportError> {
+        let streams = opt.streams as usize;
+        let share = ((offsets.len() + streams - 1) / streams).max(1);
+        debug!(
+            "Sending {} chunks over {} connections",
+            offsets.len(),
+            offsets.chunks(share).len()
+        );
+
+        thread::scope(|s| {
+            let workers: Vec<_> = offsets
+                .chunks(share)
+                .map(|part| s.spawn(move || self.send_stream(id, part, opt)))
+                .collect();
+
+            // The reporter stays on this thread, so poll the shared byte count. The main
+            // connection carries no data meanwhile, so keep it alive
+            let mut keepalive = Keepalive::new(opt.keepalive);
+            while !workers.iter().all(|w| w.is_finished()) {
+                if let Some(p) = progress {
+                    p.on_chunk(self.sent.load(Ordering::SeqCst), self.filesize);
+                }
+                keepalive.check(stream, version, enc)?;
+                thread::sleep(PROGRESS_POLL);
+            }
+
+            let mut seq = 0;
+            for w in workers {
+                seq += w.join().expect("Fatal error joining stream thread")?;
+            }
+            if let Some(p) = progress {
+                p.on_chunk(self.sent.load(Ordering::SeqCst), self.filesize);
+            }
+
+            Ok(seq)
+        })
+    }
+
+    /// Open a connection, join it to the transfer and send one share of the chunks
+    fn send_stream(
+        &self,
+        id: [u8; STREAM_ID_LEN],
+        offsets: &[u64],
+        opt: &SendOpt,
+    ) -> Result<u64, TeleportError> {
+        let (mut stream, mut version, enc) = open(opt)?;
+        utils::send_packet(
+            &mut stream,
+            TeleportAction::Join,
+            version,
+            &enc,
+            id.to_vec(),
+        )?;
+
+        let packet = utils::recv_packet(&mut stream, version, &enc)?;
+        version = packet.version;
+        let mut recv = TeleportInitAck::new(TeleportStatus::Proceed);
+        recv.deserialize(&packet.data)?;
+        let status: TeleportStatus = recv.status.try_into()?;
+        if status != TeleportStatus::Proceed {
+            warn!("The server refused an extra connection: {}", status);
+            return Err(TeleportError::Refused(status));
+        }
+
+        // Every connection gets an even share of the rate limit
+        let mut bucket = opt
+            .rate_limit
+            .map(|r| utils::TokenBucket::new((r / opt.streams as u64).max(1)));
+        let mut compressor = Compressor::new(self.compress);
+        let mut buf = vec![0; self.chunk_size];
+        let mut keepalive = Keepalive::new(opt.keepalive);
+
+        let mut seq: u64 = 0;
+        for &offset in offsets {
+            let len = utils::read_at(self.file, &mut buf, offset)?;
+            if len == 0 {
+                break;
+            }
+
+            let data = &buf[..len];
+            let data = match self.compress {
+                true => compressor.pack(data)?,
+                false => data.to_vec(),
+            };
+            let chunk = TeleportData {
+                offset,
+                seq,
+                flags: 0,
+                data_len: data.len() as u32,
+                checksum: 0,
+                data,
+            };
+            let chunk = match self.checksum {
+                true => chunk.with_checksum(),
+                false => chunk,
+            };
+            seq += 1;
+
+            if let Some(b) = bucket.as_mut() {
+                b.take(chunk.data.len());
+            }
+            keepalive.check(&mut stream, version, &enc)?;
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::Data,
+                version,
+                &enc,
+                chunk.serialize()?,
+            )?;
+            keepalive.sent();
+            self.sent.fetch_add(len as u64, Ordering::SeqCst);
+        }
+
+        send_data_complete(&mut stream, version, &enc, self.filesize, seq)?;
+
+        Ok(seq)
+    }
+}
+
 /// Send function receives the ACK for data and sends the file data
+#[allow(clippy::too_many_arguments)]
 fn send(
-    mut stream: TcpStream,
-    mut file: File,
-    header: &TeleportInit,
+    stream: &mut TcpStream,
+    file: File,
+    version: u8,
     enc: &Option<TeleportEnc>,
-    delta: Option<TeleportDelta>,
+    recv: TeleportInitAck,
     file_delta: Option<TeleportDelta>,
+    opt: &SendOpt,
+    progress: Option<&dyn ProgressReporter>,
 ) -> Result<(), TeleportError> {
     let mut buf = Vec::<u8>::new();
     let meta = file.metadata()?;
 
-    // Set transfer chunk size to delta chunk size, or default to 4096
+    // Only resume if the remote partial file matches the start of our file
+    let offset = resume_offset(&file, &recv)?;
+    if offset > 0 {
+        info!(" => Resuming at byte {offset}");
+    }
+
+    // Only compress if the server agreed to decompress
+    let mut compressor = Compressor::new(TeleportFeatures::Compress.check(&recv.features));
+    let delta = recv.delta;
+
+    // Set transfer chunk size to delta chunk size, or the requested chunk size
     match delta {
         Some(ref d) => buf.resize(d.chunk_size as usize, 0),
-        None => buf.resize(4096, 0),
+        None => buf.resize(opt.chunk_size.unwrap_or(4096), 0),
     }
 
-    // If present, get the lengths of the delta hash arrays
-    let compare_delta = delta.is_some() && file_delta.is_some();
-    let delta_len = if delta.is_some() {
-        delta.as_ref().unwrap().chunk_hash.len()
-    } else {
-        0
+    // Copy the chunks the server has from wherever they are in the new file
+    if let (Some(d), Some(_)) = (&delta, &file_delta) {
+        if TeleportFeatures::RollingDelta.check(&recv.features) {
+            let algo = HashAlgorithm::from_features(recv.features.unwrap_or(0));
+            let ops = d.rolling_ops(&file, algo)?;
+            let copied = ops
+                .iter()
+                .filter(|op| matches!(op, DeltaOp::Copy { .. }))
+                .count();
+            let literal: u64 = ops
+                .iter()
+                .map(|op| match op {
+                    DeltaOp::Literal { len, .. } => *len as u64,
+                    DeltaOp::Copy { .. } => 0,
+                })
+                .sum();
+            info!(
+                " => Delta: {} chunks found on the server, {} to send",
+                copied,
+                utils::format_size(literal)
+            );
+            let compress = TeleportFeatures::Compress.check(&recv.features);
+            let seq = send_ops(
+                stream,
+                &file,
+                version,
+                enc,
+                &ops,
+                &mut compressor,
+                compress,
+                TeleportFeatures::ChunkChecksum.check(&recv.features),
+                opt,
+                progress,
+            )?;
+            return send_data_complete(stream, version, enc, meta.len(), seq);
+        }
+    }
+
+    // If present, only send the chunks that differ from the server's file
+    let changed = match (&delta, file_delta) {
+        (Some(d), Some(f)) => {
+            // Rehash the local file if the chunk sizes do not line up
+            let local = match f.chunk_size == d.chunk_size {
+                true => f,
+                false => {
+                    let algo = HashAlgorithm::from_features(recv.features.unwrap_or(0));
+                    TeleportDelta::delta_hash_chunks(&file, meta.len(), buf.len(), algo)?
+                }
+            };
+            let changed = d.changed_chunks(&local);
+            info!(
+                " => Delta: {} of {} chunks changed",
+                changed.len(),
+                local.chunk_hash.len()
+            );
+            Some(changed)
+        }
+        _ => None,
     };
-    let file_delta_len = if file_delta.is_some() {
-        file_delta.as_ref().unwrap().chunk_hash.len()
-    } else {
-        0
+
+    // Offsets of each chunk to send
+    let chunk = buf.len() as u64;
+    let offsets: Box<dyn Iterator<Item = u64>> = match changed {
+        Some(c) => Box::new(c.into_iter().map(move |i| i as u64 * chunk)),
+        None => Box::new((offset..meta.len()).step_by(buf.len())),
     };
 
+    // Checksum each chunk if the server agreed to verify them
+    let checksum = TeleportFeatures::ChunkChecksum.check(&recv.features);
+
+    // Spread the chunks over extra connections if the server handed out an id for them
+    if let Some(id) = recv.stream_id {
+        let compress = TeleportFeatures::Compress.check(&recv.features);
+        let parallel = Parallel {
+            file: &file,
+            filesize: meta.len(),
+            chunk_size: buf.len(),
+            compress,
+            checksum,
+            sent: AtomicU64::new(0),
+        };
+        let seq = parallel.send(stream, version, enc, id, offsets.collect(), opt, progress)?;
+        return send_data_complete(stream, version, enc, meta.len(), seq);
+    }
+
+    // Throttle the upload if a rate limit was requested
+    let mut bucket = opt.rate_limit.map(utils::TokenBucket::new);
+
+    // Only seek when the next chunk does not follow the last one read
+    let mut reader = BufReader::with_capacity(utils::FILE_BUFFER, file);
+    let mut pos: Option<u64> = None;
+
+    // Ping the server whenever reading or throttling leaves the connection idle for too long
+    let mut keepalive = Keepalive::new(opt.keepalive);
+
     // Send file data
-    let mut sent = 0;
-    loop {
-        // Check if hash matches, if so: skip chunk
-        let index = sent / buf.len();
-        if compare_delta
-            && index < delta_len
-            && index < file_delta_len
-            && delta.as_ref().unwrap().chunk_hash[index]
-                == file_delta.as_ref().unwrap().chunk_hash[index]
-        {
-            sent += buf.len();
-            continue;
+    let mut seq: u64 = 0;
+    for sent in offsets {
+        if pos != Some(sent) {
+            reader.seek(SeekFrom::Start(sent))?;
         }
-
-        file.seek(SeekFrom::Start(sent as u64))?;
         // Read a chunk of the file
-        let len = match file.read(&mut buf) {
-            Ok(l) => l,
-            Err(s) => return Err(TeleportError::Io(s)),
-        };
+        let len = read_chunk(&mut reader, &mut buf)?;
+        pos = Some(sent + len as u64);
 
         // If a length of 0 was read, we're done sending
         if len == 0 {
@@ -434,20 +1832,397 @@ fn send(
         }
 
         let data = &buf[..len];
-        let mut chunk = TeleportData {
-            offset: sent as u64,
-            data_len: len as u32,
-            data: data.to_vec(),
+        let data = match TeleportFeatures::Compress.check(&recv.features) {
+            true => compressor.pack(data)?,
+            false => data.to_vec(),
         };
+        let chunk = TeleportData {
+            offset: sent,
+            seq,
+            flags: 0,
+            data_len: data.len() as u32,
+            checksum: 0,
+            data,
+        };
+        let chunk = match checksum {
+            true => chunk.with_checksum(),
+            false => chunk,
+        };
+        seq += 1;
 
         // Send the data chunk
-        utils::send_packet(&mut stream, TeleportAction::Data, enc, chunk.serialize()?)?;
+        if let Some(b) = bucket.as_mut() {
+            b.take(chunk.data.len());
+        }
+        keepalive.check(stream, version, enc)?;
+        utils::send_packet(
+            stream,
+            TeleportAction::Data,
+            version,
+            enc,
+            chunk.serialize()?,
+        )?;
+        keepalive.sent();
 
-        sent += len;
-        utils::print_updates(sent as f64, header);
+        if let Some(p) = progress {
+            p.on_chunk(sent + len as u64, meta.len());
+        }
     }
 
-    send_data_complete(stream, enc, meta.len())?;
+    send_data_complete(stream, version, enc, meta.len(), seq)?;
 
     Ok(())
 }
+
+/// Send the literal data of a rolling delta and the chunks the server copies from its own file,
+/// returning the number of chunks sent
+#[allow(clippy::too_many_arguments)]
+fn send_ops(
+    stream: &mut TcpStream,
+    file: &File,
+    version: u8,
+    enc: &Option<TeleportEnc>,
+    ops: &[DeltaOp],
+    compressor: &mut Compressor,
+    compress: bool,
+    checksum: bool,
+    opt: &SendOpt,
+    progress: Option<&dyn ProgressReporter>,
+) -> Result<u64, TeleportError> {
+    let filesize = file.metadata()?.len();
+    let mut bucket = opt.rate_limit.map(utils::TokenBucket::new);
+    let mut keepalive = Keepalive::new(opt.keepalive);
+    let mut buf = Vec::<u8>::new();
+
+    for (seq, op) in ops.iter().enumerate() {
+        let (chunk, end) = match *op {
+            DeltaOp::Literal { offset, len } => {
+                buf.resize(len as usize, 0);
+                let len = utils::read_at(file, &mut buf, offset)?;
+                let data = match compress {
+                    true => compressor.pack(&buf[..len])?,
+                    false => buf[..len].to_vec(),
+                };
+                let chunk = TeleportData {
+                    offset,
+                    seq: seq as u64,
+                    flags: 0,
+                    data_len: data.len() as u32,
+                    checksum: 0,
+                    data,
+                };
+                let chunk = match checksum {
+                    true => chunk.with_checksum(),
+                    false => chunk,
+                };
+                (chunk, offset + len as u64)
+            }
+            DeltaOp::Copy {
+                offset,
+                source,
+                len,
+            } => (
+                TeleportData::copy(offset, seq as u64, source, len),
+                offset + len as u64,
+            ),
+        };
+
+        // Send the data chunk
+        if let Some(b) = bucket.as_mut() {
+            b.take(chunk.data.len());
+        }
+        keepalive.check(stream, version, enc)?;
+        utils::send_packet(
+            stream,
+            TeleportAction::Data,
+            version,
+            enc,
+            chunk.serialize()?,
+        )?;
+        keepalive.sent();
+
+        if let Some(p) = progress {
+            p.on_chunk(end, filesize);
+        }
+    }
+
+    Ok(ops.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_expand_globs() {
+        let dir = std::env::temp_dir().join(format!("teleporter-glob-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Test should never fail");
+        for name in ["b.log", "a.log", "c.txt"] {
+            fs::write(dir.join(name), name).expect("Test should never fail");
+        }
+
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let mut opt =
+            SendOpt::parse_from(["send", "-u", "test", "-i", &path("c.txt"), &path("*.log")]);
+        expand_globs(&mut opt).expect("Test should never fail");
+        assert_eq!(
+            opt.input,
+            vec![dir.join("c.txt"), dir.join("a.log"), dir.join("b.log")]
+        );
+
+        let mut opt = SendOpt::parse_from(["send", "-u", "test", "-i", &path("*.zip")]);
+        assert!(matches!(
+            expand_globs(&mut opt),
+            Err(TeleportError::NoMatch(p)) if p == path("*.zip")
+        ));
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_exclude() {
+        let dir = std::env::temp_dir().join(format!("teleporter-exclude-{}", std::process::id()));
+        for sub in [".git", "src/node_modules", "src/lib"] {
+            fs::create_dir_all(dir.join(sub)).expect("Test should never fail");
+        }
+        for file in [
+            ".git/HEAD",
+            "src/node_modules/dep.js",
+            "src/main.rs",
+            "src/main.o",
+            "src/lib/mod.rs",
+        ] {
+            fs::write(dir.join(file), file).expect("Test should never fail");
+        }
+
+        let root = dir.to_string_lossy().to_string();
+        let opt = SendOpt::parse_from([
+            "send",
+            "-u",
+            "test",
+            "-r",
+            "-i",
+            &root,
+            "--exclude",
+            ".git",
+            "--exclude",
+            "node_modules",
+            "--exclude",
+            "*.o",
+            "--exclude",
+            "src/lib/*",
+        ]);
+        let mut files: Vec<_> = get_file_list(&opt).into_iter().map(|f| f.path).collect();
+        files.sort();
+        assert_eq!(files, vec![dir.join("src/main.rs").to_string_lossy()]);
+
+        fs::remove_dir_all(&dir).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let opt = |args: &[&str]| {
+            SendOpt::parse_from(["send", "-u", "test", "-p", "9001"].iter().chain(args))
+        };
+        let localhost: SocketAddr = "127.0.0.1:9001".parse().expect("Test should never fail");
+        let v6: SocketAddr = "[::1]:9001".parse().expect("Test should never fail");
+
+        assert_eq!(
+            resolve(&opt(&["-d", "127.0.0.1"])).expect("Test should never fail"),
+            vec![localhost]
+        );
+        for dest in ["::1", "[::1]"] {
+            assert_eq!(
+                resolve(&opt(&["-d", dest])).expect("Test should never fail"),
+                vec![v6]
+            );
+        }
+
+        // Link-local addresses keep their scope, here the loopback interface index
+        let scoped = resolve(&opt(&["-d", "fe80::1%1"])).expect("Test should never fail");
+        assert!(matches!(scoped[..], [SocketAddr::V6(a)] if a.scope_id() == 1));
+
+        // A hostname, restricted to one family
+        let any = resolve(&opt(&["-d", "localhost"])).expect("Test should never fail");
+        assert!(!any.is_empty());
+        assert!(resolve(&opt(&["-d", "localhost", "-4"]))
+            .expect("Test should never fail")
+            .iter()
+            .all(|a| a.is_ipv4()));
+        assert!(matches!(
+            resolve(&opt(&["-d", "127.0.0.1", "--ipv6"])),
+            Err(TeleportError::InvalidDest)
+        ));
+        assert!(SendOpt::try_parse_from(["send", "-u", "test", "-4", "-6"]).is_err());
+    }
+
+    #[test]
+    fn test_connect_failed() {
+        // A port that was just free has nothing listening on it
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .expect("Test should never fail")
+            .local_addr()
+            .expect("Test should never fail")
+            .port();
+        let opt = SendOpt::parse_from([
+            "send",
+            "-u",
+            "test",
+            "-d",
+            "127.0.0.1",
+            "-p",
+            &port.to_string(),
+        ]);
+        match connect(&opt) {
+            Err(TeleportError::ConnectFailed(s)) => {
+                assert!(s.starts_with(&format!("127.0.0.1:{port} (")), "{s}")
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_race() {
+        let v4 = std::net::TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let v6 = std::net::TcpListener::bind("[::1]:0").expect("Test should never fail");
+        let v4_addr = v4.local_addr().expect("Test should never fail");
+        let v6_addr = v6.local_addr().expect("Test should never fail");
+        let peer = |s: TcpStream| s.peer_addr().expect("Test should never fail");
+
+        // IPv6 goes first whatever order the addresses came in
+        let stream =
+            race(vec![v4_addr, v6_addr], Duration::from_secs(10)).expect("Test should never fail");
+        assert_eq!(peer(stream), v6_addr);
+
+        // A refused IPv6 address starts IPv4 right away, an unreachable one at the latest after the delay
+        let refused = {
+            let l = std::net::TcpListener::bind("[::1]:0").expect("Test should never fail");
+            l.local_addr().expect("Test should never fail")
+        };
+        let unreachable: SocketAddr = "[100::1]:9".parse().expect("Test should never fail");
+        for dead in [refused, unreachable] {
+            let start = Instant::now();
+            let stream =
+                race(vec![dead, v4_addr], HAPPY_EYEBALLS_DELAY).expect("Test should never fail");
+            assert_eq!(peer(stream), v4_addr);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_deprecated_encrypt_flags() {
+        // Scripts written for older versions still parse, encryption is simply the default
+        let opt = SendOpt::parse_from(["send", "-u", "test", "-e"]);
+        assert!(opt.encrypt && !opt.plaintext);
+        let opt = crate::ListenOpt::parse_from(["listen", "-m"]);
+        assert!(opt.must_encrypt && !opt.allow_plaintext);
+    }
+
+    #[test]
+    fn test_key_exchange_unexpected_reply() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let addr = listener.local_addr().expect("Test should never fail");
+        let server = std::thread::spawn(move || {
+            // Answer the key exchange with something other than an EcdhAck
+            let (mut stream, _) = listener.accept().expect("Test should never fail");
+            utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+                .expect("Test should never fail");
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::PingAck,
+                PROTOCOL_VERSION,
+                &None,
+                Vec::new(),
+            )
+            .expect("Test should never fail");
+        });
+
+        let mut stream = TcpStream::connect(addr).expect("Test should never fail");
+        assert!(matches!(
+            key_exchange(
+                &mut stream,
+                &mut PROTOCOL_VERSION.clone(),
+                Some("00:11:22:33"),
+                None
+            ),
+            Err(TeleportError::EncryptionFailure)
+        ));
+        server.join().expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_unconfirmed_transfer() {
+        let src =
+            std::env::temp_dir().join(format!("teleporter-unconfirmed-{}", std::process::id()));
+        fs::write(&src, b"data").expect("Test should never fail");
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Test should never fail");
+        let addr = listener.local_addr().expect("Test should never fail");
+        let server = std::thread::spawn(move || {
+            // Take the whole file, then hang up without confirming it
+            let (mut stream, _) = listener.accept().expect("Test should never fail");
+            utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+                .expect("Test should never fail");
+            let ack = TeleportInitAck::new(TeleportStatus::Proceed);
+            utils::send_packet(
+                &mut stream,
+                TeleportAction::InitAck,
+                PROTOCOL_VERSION,
+                &None,
+                ack.serialize().expect("Test should never fail"),
+            )
+            .expect("Test should never fail");
+            loop {
+                let packet = utils::recv_packet(&mut stream, PROTOCOL_VERSION, &None)
+                    .expect("Test should never fail");
+                let mut chunk = TeleportData::new();
+                chunk
+                    .deserialize(&packet.data)
+                    .expect("Test should never fail");
+                if chunk.flags & TeleportData::FINAL != 0 {
+                    break;
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).expect("Test should never fail");
+        let opts = SendOptions {
+            username: "test".to_string(),
+            plaintext: true,
+            ..Default::default()
+        };
+        assert!(send_file(stream, &src, opts).is_err());
+        server.join().expect("Test should never fail");
+
+        fs::remove_file(&src).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_remote_name() {
+        let item = SendFile {
+            path: "photos/2023/a.jpg".to_string(),
+            relative: Some("2023/a.jpg".to_string()),
+        };
+        let given = SendFile {
+            path: "dir/a/b.txt".to_string(),
+            relative: None,
+        };
+        let rep = Replace {
+            orig: Vec::new(),
+            new: Vec::new(),
+        };
+
+        let name = |args: &[&str], item: &SendFile| {
+            let opt = SendOpt::parse_from(["send", "-u", "test"].iter().chain(args));
+            remote_name(item, &rep, &opt)
+        };
+        assert_eq!(name(&[], &item), "2023/a.jpg");
+        assert_eq!(name(&[], &given), "b.txt");
+        assert_eq!(name(&["--relative"], &item), "photos/2023/a.jpg");
+        assert_eq!(name(&["--relative"], &given), "dir/a/b.txt");
+        assert_eq!(name(&["--flat"], &item), "a.jpg");
+        assert_eq!(name(&["--flat"], &given), "b.txt");
+        assert!(SendOpt::try_parse_from(["send", "-u", "test", "-k", "--flat"]).is_err());
+    }
+}
@@ -1,18 +1,30 @@
 use crate::crypto;
 use crate::errors::TeleportError;
-use crate::{PROTOCOL, VERSION};
-use byteorder::{LittleEndian, ReadBytesExt};
+use crate::xattr::{self, Xattr, MAX_XATTR_SIZE};
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL, PROTOCOL_VERSION, VERSION};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::debug;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
 use semver::Version;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::hash::Hasher;
-use std::io::{Read, Seek};
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use std::io;
+use std::io::{BufReader, Read, Seek, Write};
+use x25519_dalek::{PublicKey, StaticSecret};
 use xxhash_rust::xxh3;
+use zeroize::Zeroize;
+
+/// Length of the fixed part of every packet: protocol, version, data length and action
+pub const HEADER_LEN: usize = 8 + 1 + 4 + 1;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct TeleportHeader {
     protocol: u64,
+    pub version: u8,
     data_len: u32,
     pub action: u8,
     pub iv: Option<[u8; 12]>,
@@ -29,12 +41,45 @@ pub enum TeleportAction {
     PingAck = 0x20,
     Data = 0x40,
     Encrypted = 0x80,
+    // Every single bit is taken, so newer actions use the unused combinations
+    List = 0x03,
+    ListAck = 0x05,
+    Done = 0x06,
+    Join = 0x07,
+}
+
+impl fmt::Display for TeleportAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({:#04x})", self, *self as u8)
+    }
+}
+
+impl TryFrom<u8> for TeleportAction {
+    type Error = TeleportError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v & !(TeleportAction::Encrypted as u8) {
+            x if x == TeleportAction::Init as u8 => Ok(TeleportAction::Init),
+            x if x == TeleportAction::InitAck as u8 => Ok(TeleportAction::InitAck),
+            x if x == TeleportAction::Ecdh as u8 => Ok(TeleportAction::Ecdh),
+            x if x == TeleportAction::EcdhAck as u8 => Ok(TeleportAction::EcdhAck),
+            x if x == TeleportAction::Ping as u8 => Ok(TeleportAction::Ping),
+            x if x == TeleportAction::PingAck as u8 => Ok(TeleportAction::PingAck),
+            x if x == TeleportAction::Data as u8 => Ok(TeleportAction::Data),
+            x if x == TeleportAction::List as u8 => Ok(TeleportAction::List),
+            x if x == TeleportAction::ListAck as u8 => Ok(TeleportAction::ListAck),
+            x if x == TeleportAction::Done as u8 => Ok(TeleportAction::Done),
+            x if x == TeleportAction::Join as u8 => Ok(TeleportAction::Join),
+            _ => Err(TeleportError::InvalidProtocol),
+        }
+    }
 }
 
 impl TeleportHeader {
     pub fn new(action: TeleportAction) -> TeleportHeader {
         TeleportHeader {
             protocol: PROTOCOL,
+            version: PROTOCOL_VERSION,
             data_len: 0,
             action: action as u8,
             iv: None,
@@ -42,30 +87,53 @@ impl TeleportHeader {
         }
     }
 
-    pub fn serialize(&mut self) -> Result<Vec<u8>, TeleportError> {
+    #[allow(dead_code)]
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
         let mut out = Vec::<u8>::new();
+        self.serialize_into(&mut out)?;
+
+        Ok(out)
+    }
 
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
         // Add Protocol identifier
-        out.append(&mut self.protocol.to_le_bytes().to_vec());
+        w.write_u64::<LittleEndian>(self.protocol)?;
+
+        // Add wire format version
+        w.write_u8(self.version)?;
 
         // Add data length
-        self.data_len = u32::try_from(self.data.len())?;
-        out.append(&mut self.data_len.to_le_bytes().to_vec());
+        w.write_u32::<LittleEndian>(u32::try_from(self.data.len())?)?;
 
         // Add action code
         let mut action = self.action;
         if self.iv.is_some() {
             action |= TeleportAction::Encrypted as u8;
         }
-        out.push(action);
+        w.write_u8(action)?;
 
         // If Encrypted, add IV
         if let Some(iv) = self.iv {
-            out.append(&mut iv[..].to_vec());
+            w.write_all(&iv)?;
         };
 
         // Add data
-        out.append(&mut self.data.clone());
+        w.write_all(&self.data)?;
+
+        Ok(())
+    }
+
+    /// Serialize the unencrypted header fields, authenticated as AEAD associated data
+    pub fn aad(&self, data_len: usize) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        out.append(&mut self.protocol.to_le_bytes().to_vec());
+        out.push(self.version);
+        out.append(&mut u32::try_from(data_len)?.to_le_bytes().to_vec());
+        out.push(self.action | TeleportAction::Encrypted as u8);
+        if let Some(iv) = self.iv {
+            out.append(&mut iv[..].to_vec());
+        };
 
         Ok(out)
     }
@@ -73,15 +141,26 @@ impl TeleportHeader {
     pub fn deserialize(&mut self, input: Vec<u8>) -> Result<(), TeleportError> {
         let mut buf: &[u8] = &input;
 
+        // Protocol, version, data length and action code must be present
+        if input.len() < HEADER_LEN {
+            return Err(TeleportError::InvalidLength);
+        }
+
         // Extract Protocol
         self.protocol = buf.read_u64::<LittleEndian>()?;
         if self.protocol != PROTOCOL {
             return Err(TeleportError::InvalidHeaderRead);
         }
 
+        // Extract version, newer ones keep this fixed header so the caller checks the upper bound
+        self.version = buf.read_u8()?;
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(TeleportError::UnsupportedProtocol(self.version));
+        }
+
         // Extract data length
         self.data_len = buf.read_u32::<LittleEndian>()?;
-        let mut data_ofs = 13;
+        let mut data_ofs = HEADER_LEN;
 
         // Extract action code
         let action = buf.read_u8()?;
@@ -89,10 +168,13 @@ impl TeleportHeader {
 
         // If Encrypted, extract IV
         if (action & TeleportAction::Encrypted as u8) == TeleportAction::Encrypted as u8 {
-            if input.len() < 25 {
+            if input.len() < HEADER_LEN + 12 {
                 return Err(TeleportError::InvalidIV);
             }
-            let iv: [u8; 12] = input[13..25].try_into().expect("Error reading IV");
+            let iv: [u8; 12] = match input[HEADER_LEN..HEADER_LEN + 12].try_into() {
+                Ok(iv) => iv,
+                Err(_) => return Err(TeleportError::InvalidIV),
+            };
             self.iv = Some(iv);
             data_ofs += 12;
         }
@@ -107,24 +189,61 @@ impl TeleportHeader {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TeleportEnc {
     secret: [u8; 32],
     remote: [u8; 32],
     pub public: [u8; 32],
+    /// The server's long-term identity public key, all zeros until known
+    pub identity: [u8; 32],
+    prefix: [u8; 4],
+    counter: Cell<u64>,
 }
 
 impl TeleportEnc {
-    pub fn new() -> TeleportEnc {
-        TeleportEnc {
+    pub fn new() -> Result<TeleportEnc, TeleportError> {
+        TeleportEnc::new_from(&mut OsRng)
+    }
+
+    /// Start a session context whose nonce prefix is drawn from `rng`
+    pub fn new_from<R: RngCore + CryptoRng>(rng: &mut R) -> Result<TeleportEnc, TeleportError> {
+        let mut prefix: [u8; 4] = [0; 4];
+        crypto::random_bytes_from(rng, &mut prefix)?;
+
+        Ok(TeleportEnc {
             secret: [0; 32],
             remote: [0; 32],
             public: [0; 32],
+            identity: [0; 32],
+            prefix,
+            counter: Cell::new(0),
+        })
+    }
+
+    /// Build the next 12-byte nonce from the random prefix and the message counter.
+    /// The counter must never wrap within a session, so running out is an error.
+    pub fn next_nonce(&self) -> Result<[u8; 12], TeleportError> {
+        let count = self.counter.get();
+        if count == u64::MAX {
+            return Err(TeleportError::NonceExhausted);
         }
+        self.counter.set(count + 1);
+
+        let mut nonce: [u8; 12] = [0; 12];
+        nonce[..4].copy_from_slice(&self.prefix);
+        nonce[4..].copy_from_slice(&count.to_le_bytes());
+
+        Ok(nonce)
     }
 
-    pub fn serialize(self) -> Vec<u8> {
-        self.public.to_vec()
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = self.public.to_vec();
+        // The server follows its ephemeral key with its identity key
+        if self.identity != [0; 32] {
+            out.extend_from_slice(&self.identity);
+        }
+
+        out
     }
 
     pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
@@ -132,22 +251,113 @@ impl TeleportEnc {
             return Err(TeleportError::InvalidPubKey);
         }
 
-        self.remote = input[..32].try_into().expect("Error reading public key");
+        let remote: [u8; 32] = match input[..32].try_into() {
+            Ok(r) => r,
+            Err(_) => return Err(TeleportError::InvalidPubKey),
+        };
+
+        // Refuse keys that would force a predictable shared secret
+        if crypto::is_low_order(&remote) {
+            return Err(TeleportError::InvalidPubKey);
+        }
+
+        self.remote = remote;
+
+        if input.len() >= 64 {
+            let identity: [u8; 32] = match input[32..64].try_into() {
+                Ok(i) => i,
+                Err(_) => return Err(TeleportError::InvalidPubKey),
+            };
+            if crypto::is_low_order(&identity) {
+                return Err(TeleportError::InvalidPubKey);
+            }
+            self.identity = identity;
+        }
 
         Ok(())
     }
 
-    pub fn calc_secret(&mut self, privkey: EphemeralSecret) {
+    pub fn calc_secret(&mut self, privkey: &StaticSecret) {
         let pubkey = PublicKey::from(self.remote);
-        self.secret = privkey.diffie_hellman(&pubkey).to_bytes()
+        let shared = privkey.diffie_hellman(&pubkey);
+        self.secret = crypto::derive_key(None, shared.as_bytes());
+
+        // Keep each direction's nonces apart, since both peers share the same key
+        if self.public < self.remote {
+            self.prefix[0] &= 0x7f;
+        } else {
+            self.prefix[0] |= 0x80;
+        }
+    }
+
+    /// Mix the server's identity into the key on the server, with the identity secret key
+    pub fn bind_identity(&mut self, identity: &StaticSecret) {
+        self.identity = PublicKey::from(identity).to_bytes();
+        let shared = identity.diffie_hellman(&PublicKey::from(self.remote));
+        self.mix_secret(shared.as_bytes());
+    }
+
+    /// Mix the server's identity into the key on the client, with the client's ephemeral secret key
+    pub fn bind_remote_identity(&mut self, privkey: &StaticSecret) -> Result<(), TeleportError> {
+        if self.identity == [0; 32] {
+            return Err(TeleportError::InvalidPubKey);
+        }
+        let shared = privkey.diffie_hellman(&PublicKey::from(self.identity));
+        self.mix_secret(shared.as_bytes());
+
+        Ok(())
+    }
+
+    /// Only a peer holding the server's identity secret key can derive the same key
+    fn mix_secret(&mut self, shared: &[u8; 32]) {
+        let mut input = [0u8; 64];
+        input[..32].copy_from_slice(&self.secret);
+        input[32..].copy_from_slice(shared);
+        self.secret = crypto::derive_key(None, &input);
+        input.zeroize();
+    }
+
+    /// Mix a pre-shared passphrase into the key, so peers without it cannot decrypt anything
+    pub fn bind_passphrase(&mut self, passphrase: &[u8]) {
+        let mut secret = self.secret;
+        self.secret = crypto::derive_key(Some(passphrase), &secret);
+        secret.zeroize();
+    }
+
+    /// Fingerprint of the server's identity key
+    pub fn fingerprint(&self) -> String {
+        crypto::fingerprint(&self.identity)
+    }
+
+    pub fn encrypt(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        input: &[u8],
+    ) -> Result<Vec<u8>, TeleportError> {
+        crypto::encrypt(&self.secret, nonce.to_vec(), aad, input.to_vec())
+    }
+
+    pub fn decrypt(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        input: &[u8],
+    ) -> Result<Vec<u8>, TeleportError> {
+        crypto::decrypt(&self.secret, nonce.to_vec(), aad, input.to_vec())
     }
+}
 
-    pub fn encrypt(self, nonce: &[u8; 12], input: &[u8]) -> Result<Vec<u8>, TeleportError> {
-        crypto::encrypt(&self.secret, nonce.to_vec(), input.to_vec())
+impl Zeroize for TeleportEnc {
+    fn zeroize(&mut self) {
+        self.secret.zeroize();
+        self.remote.zeroize();
     }
+}
 
-    pub fn decrypt(self, nonce: &[u8; 12], input: &[u8]) -> Result<Vec<u8>, TeleportError> {
-        crypto::decrypt(&self.secret, nonce.to_vec(), input.to_vec())
+impl Drop for TeleportEnc {
+    fn drop(&mut self) {
+        self.zeroize();
     }
 }
 
@@ -159,6 +369,18 @@ pub enum TeleportFeatures {
     Backup = 0x08,
     Rename = 0x10,
     Ping = 0x20,
+    Resume = 0x40,
+    Compress = 0x80,
+    PreserveTimes = 0x100,
+    StrongHash = 0x200,
+    Batch = 0x400,
+    Streams = 0x800,
+    UnknownSize = 0x1000,
+    FileType = 0x2000,
+    RollingDelta = 0x4000,
+    PreserveOwner = 0x8000,
+    PreserveXattr = 0x10000,
+    ChunkChecksum = 0x20000,
 }
 
 impl TeleportFeatures {
@@ -192,6 +414,80 @@ impl TeleportFeatures {
     }
 }
 
+/// Hash used for delta chunks and whole file verification
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Xxh3,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// BLAKE3 if StrongHash is in the negotiated features, xxh3 otherwise
+    pub fn from_features(features: u32) -> Self {
+        match TeleportFeatures::StrongHash.check_u32(features) {
+            true => HashAlgorithm::Blake3,
+            false => HashAlgorithm::Xxh3,
+        }
+    }
+
+    pub(crate) fn hasher(&self) -> ChunkHasher {
+        match self {
+            HashAlgorithm::Xxh3 => ChunkHasher::Xxh3(Box::new(xxh3::Xxh3::new())),
+            HashAlgorithm::Blake3 => ChunkHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Streaming hasher for either HashAlgorithm, producing a 64-bit value
+pub(crate) enum ChunkHasher {
+    Xxh3(Box<xxh3::Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChunkHasher {
+    pub(crate) fn write(&mut self, data: &[u8]) {
+        match self {
+            ChunkHasher::Xxh3(h) => h.write(data),
+            ChunkHasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        match self {
+            ChunkHasher::Xxh3(h) => h.finish(),
+            ChunkHasher::Blake3(h) => {
+                // Truncate the BLAKE3 digest to the first 8 bytes
+                let mut out = [0; 8];
+                out.copy_from_slice(&h.finalize().as_bytes()[..8]);
+                u64::from_le_bytes(out)
+            }
+        }
+    }
+
+    /// 128-bit variant used for delta chunks, where 64 bits could collide in large files
+    fn finish128(&self) -> u128 {
+        match self {
+            ChunkHasher::Xxh3(h) => h.digest128(),
+            ChunkHasher::Blake3(h) => {
+                // Truncate the BLAKE3 digest to the first 16 bytes
+                let mut out = [0; 16];
+                out.copy_from_slice(&h.finalize().as_bytes()[..16]);
+                u128::from_le_bytes(out)
+            }
+        }
+    }
+
+    /// Full digest in hex, 16 digits for xxh3 and 64 for BLAKE3 as b3sum prints it
+    pub(crate) fn finish_hex(&self) -> String {
+        match self {
+            ChunkHasher::Xxh3(h) => format!("{:016x}", h.finish()),
+            ChunkHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct TeleportVersion {
     pub major: u16,
@@ -200,12 +496,12 @@ pub struct TeleportVersion {
 }
 
 impl TeleportVersion {
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut out = Vec::<u8>::new();
-        out.append(&mut self.major.to_le_bytes().to_vec());
-        out.append(&mut self.minor.to_le_bytes().to_vec());
-        out.append(&mut self.patch.to_le_bytes().to_vec());
-        out
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
+        w.write_u16::<LittleEndian>(self.major)?;
+        w.write_u16::<LittleEndian>(self.minor)?;
+        w.write_u16::<LittleEndian>(self.patch)?;
+
+        Ok(())
     }
 
     pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
@@ -216,8 +512,30 @@ impl TeleportVersion {
         Ok(())
     }
 
-    pub fn is_compatible(&self, version: &Version) -> bool {
-        version.major == self.major as u64 && version.minor == self.minor as u64
+    /// Semver compatibility: 0.x versions must match major and minor, later ones only major
+    pub fn is_compatible<V: Into<TeleportVersion>>(&self, version: V) -> bool {
+        let version = version.into();
+        if version.major != self.major {
+            return false;
+        }
+
+        self.major > 0 || version.minor == self.minor
+    }
+}
+
+impl From<&Version> for TeleportVersion {
+    fn from(v: &Version) -> Self {
+        TeleportVersion {
+            major: v.major as u16,
+            minor: v.minor as u16,
+            patch: v.patch as u16,
+        }
+    }
+}
+
+impl From<&TeleportVersion> for TeleportVersion {
+    fn from(v: &TeleportVersion) -> Self {
+        v.clone()
     }
 }
 
@@ -233,12 +551,42 @@ pub struct TeleportInit {
     pub features: u32,
     pub chmod: u32,
     pub filesize: u64,
+    pub whole_hash: u64,
     pub filename_len: u16,
     pub filename: Vec<u8>,
-    // added by lee
     pub username_len: u16,
     pub username: Vec<u8>,
-    // added end
+    pub token_len: u16,
+    pub token: Vec<u8>,
+    pub mtime: u64,
+    pub file_type: u8,
+    pub link_target_len: u16,
+    pub link_target: Vec<u8>,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: Vec<Xattr>,
+}
+
+/// Kind of entry a TeleportInit creates, sent with the FileType feature
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FileType {
+    Regular = 0x00,
+    Dir = 0x01,
+    Symlink = 0x02,
+}
+
+impl TryFrom<u8> for FileType {
+    type Error = TeleportError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            x if x == FileType::Regular as u8 => Ok(FileType::Regular),
+            x if x == FileType::Dir as u8 => Ok(FileType::Dir),
+            x if x == FileType::Symlink as u8 => Ok(FileType::Symlink),
+            _ => Err(TeleportError::InvalidProtocol),
+        }
+    }
 }
 
 impl TeleportInit {
@@ -254,49 +602,100 @@ impl TeleportInit {
             features: features as u32,
             chmod: 0o644,
             filesize: 0,
+            whole_hash: 0,
             filename_len: 0,
             filename: Vec::<u8>::new(),
-            // added by lee
             username_len: 0,
             username: Vec::<u8>::new(),
-            //added end
+            token_len: 0,
+            token: Vec::<u8>::new(),
+            mtime: 0,
+            file_type: FileType::Regular as u8,
+            link_target_len: 0,
+            link_target: Vec::<u8>::new(),
+            uid: 0,
+            gid: 0,
+            xattrs: Vec::new(),
         }
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
         let mut out = Vec::<u8>::new();
+        self.serialize_into(&mut out)?;
+
+        Ok(out)
+    }
 
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
         // Add version
-        out.append(&mut self.version.serialize());
+        self.version.serialize_into(w)?;
 
         // Add features
-        out.append(&mut self.features.to_le_bytes().to_vec());
+        w.write_u32::<LittleEndian>(self.features)?;
 
         // Add chmod
-        out.append(&mut self.chmod.to_le_bytes().to_vec());
+        w.write_u32::<LittleEndian>(self.chmod)?;
 
         // Add filesize
-        out.append(&mut self.filesize.to_le_bytes().to_vec());
+        w.write_u64::<LittleEndian>(self.filesize)?;
+
+        // Add whole file hash
+        w.write_u64::<LittleEndian>(self.whole_hash)?;
 
         // Add filename_len
-        let flen = u16::try_from(self.filename.len())?;
-        out.append(&mut flen.to_le_bytes().to_vec());
+        w.write_u16::<LittleEndian>(u16::try_from(self.filename.len())?)?;
 
         // Add filename
-        out.append(&mut self.filename.to_vec());
+        w.write_all(&self.filename)?;
+
+        // Add username
+        debug!("username: {:?}", self.username);
 
-        // added by lee
-        println!("username: {:?}", self.username);
-        
         let ulen = u16::try_from(self.username.len())?;
-        out.append(&mut ulen.to_le_bytes().to_vec());
-        println!("username_len: {}", ulen);
+        w.write_u16::<LittleEndian>(ulen)?;
+        debug!("username_len: {}", ulen);
 
-        out.append(&mut self.username.to_vec());
+        w.write_all(&self.username)?;
 
-        // added end
+        // Add token
+        w.write_u16::<LittleEndian>(u16::try_from(self.token.len())?)?;
+        w.write_all(&self.token)?;
 
-        Ok(out)
+        // Add modification time if requested
+        if TeleportFeatures::PreserveTimes.check_u32(self.features) {
+            w.write_u64::<LittleEndian>(self.mtime)?;
+        }
+
+        // Add the entry type, and where a symlink points
+        if TeleportFeatures::FileType.check_u32(self.features) {
+            w.write_u8(self.file_type)?;
+            if self.file_type == FileType::Symlink as u8 {
+                w.write_u16::<LittleEndian>(u16::try_from(self.link_target.len())?)?;
+                w.write_all(&self.link_target)?;
+            }
+        }
+
+        // Add the owner if requested
+        if TeleportFeatures::PreserveOwner.check_u32(self.features) {
+            w.write_u32::<LittleEndian>(self.uid)?;
+            w.write_u32::<LittleEndian>(self.gid)?;
+        }
+
+        // Add the extended attributes if requested, bounded to keep the packet small
+        if TeleportFeatures::PreserveXattr.check_u32(self.features) {
+            if xattr::serialized_size(&self.xattrs) > MAX_XATTR_SIZE {
+                return Err(TeleportError::InvalidLength);
+            }
+            w.write_u16::<LittleEndian>(u16::try_from(self.xattrs.len())?)?;
+            for (name, value) in &self.xattrs {
+                w.write_u16::<LittleEndian>(u16::try_from(name.len())?)?;
+                w.write_all(name)?;
+                w.write_u16::<LittleEndian>(u16::try_from(value.len())?)?;
+                w.write_all(value)?;
+            }
+        }
+
+        Ok(())
     }
 
     pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
@@ -314,31 +713,83 @@ impl TeleportInit {
         // Extract file size
         self.filesize = buf.read_u64::<LittleEndian>()?;
 
+        // Extract whole file hash
+        self.whole_hash = buf.read_u64::<LittleEndian>()?;
+
         // Extract filename_len
         self.filename_len = buf.read_u16::<LittleEndian>()?;
 
         // Extract filename
-        let fname = &buf[..self.filename_len as usize].to_vec();
-        self.filename = fname.to_vec();
-        if self.filename.len() != self.filename_len as usize {
+        if buf.len() < self.filename_len as usize {
             return Err(TeleportError::InvalidFileName);
         }
+        self.filename = buf[..self.filename_len as usize].to_vec();
+        debug!("fname: {}", String::from_utf8_lossy(&self.filename));
 
-        let s = String::from_utf8(fname.clone()).unwrap();
-        println!("fname: {}", s);
-        
-        // added by lee
+        // Extract username
         buf = &buf[self.filename_len as usize..];
         self.username_len = buf.read_u16::<LittleEndian>()?;
-        println!("username len: {}", self.username_len);
-        // Extract filename
-        let uname = &buf[..self.username_len as usize].to_vec();
-        self.username = uname.to_vec();
-        if self.username.len() != self.username_len as usize {
+        debug!("username len: {}", self.username_len);
+        if buf.len() < self.username_len as usize {
+            return Err(TeleportError::InvalidUserName);
+        }
+        self.username = buf[..self.username_len as usize].to_vec();
+
+        // Extract token
+        buf = &buf[self.username_len as usize..];
+        self.token_len = buf.read_u16::<LittleEndian>()?;
+        if buf.len() < self.token_len as usize {
             return Err(TeleportError::InvalidUserName);
         }
+        self.token = buf[..self.token_len as usize].to_vec();
+        buf = &buf[self.token_len as usize..];
+
+        // Extract modification time, older clients do not send it
+        if TeleportFeatures::PreserveTimes.check_u32(self.features) {
+            self.mtime = buf.read_u64::<LittleEndian>()?;
+        }
+
+        // Extract the entry type, older clients only send regular files
+        if TeleportFeatures::FileType.check_u32(self.features) {
+            self.file_type = FileType::try_from(buf.read_u8()?)? as u8;
+            if self.file_type == FileType::Symlink as u8 {
+                self.link_target_len = buf.read_u16::<LittleEndian>()?;
+                if buf.len() < self.link_target_len as usize {
+                    return Err(TeleportError::InvalidFileName);
+                }
+                self.link_target = buf[..self.link_target_len as usize].to_vec();
+                buf = &buf[self.link_target_len as usize..];
+            }
+        }
+
+        // Extract the owner, older clients do not send it
+        if TeleportFeatures::PreserveOwner.check_u32(self.features) {
+            self.uid = buf.read_u32::<LittleEndian>()?;
+            self.gid = buf.read_u32::<LittleEndian>()?;
+        }
+
+        // Extract the extended attributes, refusing more than a client may send
+        if TeleportFeatures::PreserveXattr.check_u32(self.features) {
+            let count = buf.read_u16::<LittleEndian>()?;
+            let mut size = 2;
+            self.xattrs.clear();
+            for _ in 0..count {
+                let mut field = || -> Result<Vec<u8>, TeleportError> {
+                    let len = buf.read_u16::<LittleEndian>()? as usize;
+                    size += 2 + len;
+                    if buf.len() < len || size > MAX_XATTR_SIZE {
+                        return Err(TeleportError::InvalidLength);
+                    }
+                    let (data, rest) = buf.split_at(len);
+                    buf = rest;
+                    Ok(data.to_vec())
+                };
+                let name = field()?;
+                let value = field()?;
+                self.xattrs.push((name, value));
+            }
+        }
 
-        // added end
         Ok(())
     }
 }
@@ -348,10 +799,16 @@ pub struct TeleportInitAck {
     pub status: u8,
     pub version: TeleportVersion,
     pub features: Option<u32>,
+    pub resume_offset: Option<u64>,
+    pub resume_hash: Option<u64>,
+    pub stream_id: Option<[u8; STREAM_ID_LEN]>,
     pub delta: Option<TeleportDelta>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// Length of the id extra connections send to join a transfer
+pub const STREAM_ID_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum TeleportStatus {
     Proceed = 0x00,
@@ -364,9 +821,20 @@ pub enum TeleportStatus {
     BadFileName = 0x07,
     Pong = 0x08,
     UnknownUser = 0x09,
+    HashMismatch = 0x0a,
+    ServerBusy = 0x0b,
+    TooLarge = 0x0c,
+    UnknownTransfer = 0x0d,
+    Incomplete = 0x0e,
     UnknownAction = 0xff,
 }
 
+impl fmt::Display for TeleportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({:#04x})", self, *self as u8)
+    }
+}
+
 impl TryFrom<u8> for TeleportStatus {
     type Error = TeleportError;
 
@@ -383,6 +851,12 @@ impl TryFrom<u8> for TeleportStatus {
             x if x == TeleportStatus::EncryptionError as u8 => Ok(TeleportStatus::EncryptionError),
             x if x == TeleportStatus::BadFileName as u8 => Ok(TeleportStatus::BadFileName),
             x if x == TeleportStatus::Pong as u8 => Ok(TeleportStatus::Pong),
+            x if x == TeleportStatus::UnknownUser as u8 => Ok(TeleportStatus::UnknownUser),
+            x if x == TeleportStatus::HashMismatch as u8 => Ok(TeleportStatus::HashMismatch),
+            x if x == TeleportStatus::ServerBusy as u8 => Ok(TeleportStatus::ServerBusy),
+            x if x == TeleportStatus::TooLarge as u8 => Ok(TeleportStatus::TooLarge),
+            x if x == TeleportStatus::UnknownTransfer as u8 => Ok(TeleportStatus::UnknownTransfer),
+            x if x == TeleportStatus::Incomplete as u8 => Ok(TeleportStatus::Incomplete),
             x if x == TeleportStatus::UnknownAction as u8 => Ok(TeleportStatus::UnknownAction),
             _ => Err(TeleportError::InvalidStatusCode),
         }
@@ -401,38 +875,60 @@ impl TeleportInitAck {
                 patch: v.patch as u16,
             },
             features: None,
+            resume_offset: None,
+            resume_hash: None,
+            stream_id: None,
             delta: None,
         }
     }
 
-    pub fn serialize(self) -> Result<Vec<u8>, TeleportError> {
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
         let mut out = Vec::<u8>::new();
+        self.serialize_into(&mut out)?;
 
+        Ok(out)
+    }
+
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
         // Add status
         let status = self.status;
-        out.append(&mut vec![status]);
+        w.write_u8(status)?;
 
         // Add version
-        out.append(&mut self.version.serialize());
+        self.version.serialize_into(w)?;
 
         // If no features, return early
         if status != TeleportStatus::Proceed as u8 || self.features.is_none() {
-            return Ok(out);
+            return Ok(());
         }
 
         // Add optional features
         if let Some(feat) = self.features {
-            out.append(&mut feat.to_le_bytes().to_vec());
+            w.write_u32::<LittleEndian>(feat)?;
+
+            if TeleportFeatures::Resume.check_u32(feat) {
+                // Add resume offset and the hash of the partial file
+                w.write_u64::<LittleEndian>(self.resume_offset.unwrap_or(0))?;
+                w.write_u64::<LittleEndian>(self.resume_hash.unwrap_or(0))?;
+            }
+
+            if TeleportFeatures::Streams.check_u32(feat) {
+                // Add the id extra connections join the transfer with
+                w.write_all(&self.stream_id.unwrap_or_default())?;
+            }
 
             if TeleportFeatures::Delta.check_u32(feat) {
                 // Add optional TeleportDelta data
-                if let Some(delta) = self.delta {
-                    out.append(&mut delta.serialize()?);
+                if let Some(delta) = &self.delta {
+                    delta.serialize_into(w)?;
+                    if TeleportFeatures::RollingDelta.check_u32(feat) {
+                        delta.serialize_weak_into(w)?;
+                    }
                 }
             }
         }
 
-        Ok(out)
+        Ok(())
     }
 
     pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
@@ -447,7 +943,7 @@ impl TeleportInitAck {
         let mut buf: &[u8] = &input[7..];
 
         // If no features, return early
-        if self.status != TeleportStatus::Proceed as u8 {
+        if self.status != TeleportStatus::Proceed as u8 || buf.is_empty() {
             return Ok(());
         }
 
@@ -455,6 +951,19 @@ impl TeleportInitAck {
         let features = buf.read_u32::<LittleEndian>()?;
         self.features = Some(features);
 
+        // Extract optional resume offset and partial file hash
+        if TeleportFeatures::Resume.check_u32(features) {
+            self.resume_offset = Some(buf.read_u64::<LittleEndian>()?);
+            self.resume_hash = Some(buf.read_u64::<LittleEndian>()?);
+        }
+
+        // Extract optional id for joining the transfer
+        if TeleportFeatures::Streams.check_u32(features) {
+            let mut id = [0u8; STREAM_ID_LEN];
+            buf.read_exact(&mut id)?;
+            self.stream_id = Some(id);
+        }
+
         // If no delta, return early
         if !TeleportFeatures::Delta.check_u32(features) {
             return Ok(());
@@ -462,20 +971,28 @@ impl TeleportInitAck {
 
         // Extract optional TeleportDelta data
         let mut delta = TeleportDelta::new();
-        delta.deserialize(&input[11..])?;
+        match TeleportFeatures::RollingDelta.check_u32(features) {
+            true => delta.deserialize_rolling(buf)?,
+            false => delta.deserialize(buf)?,
+        }
         self.delta = Some(delta);
 
         Ok(())
     }
 }
 
+/// Number of chunks a delta aims for by default, keeping TeleportDelta small whatever the file size
+pub const DELTA_CHUNKS: u16 = 2048;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TeleportDelta {
     pub filesize: u64,
     pub hash: u64,
     pub chunk_size: u32,
     chunk_hash_len: u16,
-    pub chunk_hash: Vec<u64>,
+    pub chunk_hash: Vec<u128>,
+    /// Rolling checksum of each chunk, only sent with RollingDelta
+    pub weak_hash: Vec<u32>,
 }
 
 impl TeleportDelta {
@@ -485,52 +1002,62 @@ impl TeleportDelta {
             hash: 0,
             chunk_size: 0,
             chunk_hash_len: 0,
-            chunk_hash: Vec::<u64>::new(),
+            chunk_hash: Vec::<u128>::new(),
+            weak_hash: Vec::<u32>::new(),
         }
     }
 
-    fn delta_serial(input: &[u64]) -> Vec<u8> {
+    #[allow(dead_code)]
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
         let mut out = Vec::<u8>::new();
+        self.serialize_into(&mut out)?;
 
-        for i in input {
-            out.append(&mut i.to_le_bytes().to_vec());
-        }
-
-        out
+        Ok(out)
     }
 
-    pub fn serialize(self) -> Result<Vec<u8>, TeleportError> {
-        let mut out = Vec::<u8>::new();
-
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
         // Add file size
-        out.append(&mut self.filesize.to_le_bytes().to_vec());
+        w.write_u64::<LittleEndian>(self.filesize)?;
 
         // Add file hash
-        out.append(&mut self.hash.to_le_bytes().to_vec());
+        w.write_u64::<LittleEndian>(self.hash)?;
 
         // Add chunk size
-        out.append(&mut self.chunk_size.to_le_bytes().to_vec());
+        w.write_u32::<LittleEndian>(self.chunk_size)?;
 
         // Add delta vector length
-        let dlen = u16::try_from(self.chunk_hash.len())?;
-        out.append(&mut dlen.to_le_bytes().to_vec());
+        w.write_u16::<LittleEndian>(u16::try_from(self.chunk_hash.len())?)?;
 
         // Add delta vector
-        out.append(&mut TeleportDelta::delta_serial(&self.chunk_hash));
+        for i in &self.chunk_hash {
+            w.write_u128::<LittleEndian>(*i)?;
+        }
 
-        Ok(out)
+        Ok(())
+    }
+
+    /// Add the rolling checksum of each chunk, sent after the TeleportDelta with RollingDelta
+    pub fn serialize_weak_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
+        if self.weak_hash.len() != self.chunk_hash.len() {
+            return Err(TeleportError::InvalidDelta);
+        }
+        for i in &self.weak_hash {
+            w.write_u32::<LittleEndian>(*i)?;
+        }
+
+        Ok(())
     }
 
-    fn delta_deserial(input: &[u8], len: u16) -> Result<Vec<u64>, TeleportError> {
-        if input.len() % 8 != 0 || len as usize != input.len() / 8 {
+    fn delta_deserial(input: &[u8], len: u16) -> Result<Vec<u128>, TeleportError> {
+        if input.len() % 16 != 0 || len as usize != input.len() / 16 {
             return Err(TeleportError::InvalidDelta);
         }
 
-        let mut out = Vec::<u64>::new();
+        let mut out = Vec::<u128>::new();
         let mut buf = input;
         let mut count: u16 = len;
         while count > 0 {
-            let a: u64 = buf.read_u64::<LittleEndian>()?;
+            let a: u128 = buf.read_u128::<LittleEndian>()?;
             out.push(a);
             count -= 1;
         }
@@ -562,31 +1089,91 @@ impl TeleportDelta {
         Ok(())
     }
 
-    pub fn delta_hash(mut file: &File) -> Result<Self, TeleportError> {
+    /// Deserialize a TeleportDelta followed by the rolling checksum of each chunk
+    pub fn deserialize_rolling(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let count = match input.get(20..22) {
+            Some(b) => u16::from_le_bytes([b[0], b[1]]) as usize,
+            None => return Err(TeleportError::InvalidLength),
+        };
+        let split = 22 + count * 16;
+        if input.len() != split + count * 4 {
+            return Err(TeleportError::InvalidDelta);
+        }
+        self.deserialize(&input[..split])?;
+
+        let mut buf = &input[split..];
+        self.weak_hash = (0..count)
+            .map(|_| buf.read_u32::<LittleEndian>())
+            .collect::<Result<_, _>>()?;
+
+        Ok(())
+    }
+
+    pub fn delta_hash(file: &File, algo: HashAlgorithm) -> Result<Self, TeleportError> {
         let meta = file.metadata()?;
-        let file_size = meta.len();
+        Self::delta_hash_len(file, meta.len(), algo)
+    }
+
+    /// Hash only the first `file_size` bytes of the file, as used to validate a resumed transfer
+    pub fn delta_hash_len(
+        file: &File,
+        file_size: u64,
+        algo: HashAlgorithm,
+    ) -> Result<Self, TeleportError> {
+        Self::delta_hash_chunks(file, file_size, Self::chunk_size(file_size), algo)
+    }
 
+    /// Hash the first `file_size` bytes of the file in blocks of `chunk_size`, as used by the
+    /// client to compare against a TeleportDelta received from the server
+    pub fn delta_hash_chunks(
+        mut file: &File,
+        file_size: u64,
+        chunk_size: usize,
+        algo: HashAlgorithm,
+    ) -> Result<Self, TeleportError> {
         file.rewind()?;
-        let mut buf = Vec::<u8>::new();
-        buf.resize(Self::chunk_size(meta.len()), 0);
-        let mut whole_hasher = xxh3::Xxh3::new();
-        let mut chunk_hash = Vec::<u64>::new();
+        let out = Self::hash_chunks(file.take(file_size), file_size, chunk_size, algo)?;
+        file.rewind()?;
+
+        Ok(out)
+    }
+
+    /// Hash `reader` in blocks of `chunk_size`, filling each block unless the data ends first
+    fn hash_chunks<R: Read>(
+        mut reader: R,
+        file_size: u64,
+        chunk_size: usize,
+        algo: HashAlgorithm,
+    ) -> Result<Self, TeleportError> {
+        let mut buf = vec![0; chunk_size];
+        // The whole file hash does not depend on the chunk size of the delta
+        let mut whole_hasher = algo.hasher();
+        let mut chunk_hash = Vec::<u128>::new();
 
         loop {
-            let mut hasher = xxh3::Xxh3::new();
-            // Read a chunk of the file
-            let len = match file.read(&mut buf) {
-                Ok(l) => l,
-                Err(s) => return Err(TeleportError::Io(s)),
-            };
+            let mut hasher = algo.hasher();
+            // Read a chunk of the file, short reads keep filling it so chunks match the server's
+            let mut len = 0;
+            while len < buf.len() {
+                match reader.read(&mut buf[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(TeleportError::Io(e)),
+                }
+            }
             if len == 0 {
                 break;
             }
 
-            hasher.write(&buf);
-            chunk_hash.push(hasher.finish());
+            // Only the last chunk can be short, hashed without the stale bytes behind it in the buffer
+            hasher.write(&buf[..len]);
+            chunk_hash.push(hasher.finish128());
 
-            whole_hasher.write(&buf);
+            whole_hasher.write(&buf[..len]);
+            if len < buf.len() {
+                break;
+            }
         }
 
         let mut out = Self::new();
@@ -595,100 +1182,561 @@ impl TeleportDelta {
         out.hash = whole_hasher.finish();
         out.chunk_hash = chunk_hash;
 
+        Ok(out)
+    }
+
+    /// Hash exactly the bytes of the file, the same value as `delta_hash` without the chunk hashes
+    pub fn stream_hash(file: &File, algo: HashAlgorithm) -> Result<u64, TeleportError> {
+        Ok(TeleportDelta::hash_file(file, algo)?.finish())
+    }
+
+    /// Hex digest of the whole file for checksum manifests, the full BLAKE3 digest rather than 64 bits
+    pub fn digest(file: &File, algo: HashAlgorithm) -> Result<String, TeleportError> {
+        Ok(TeleportDelta::hash_file(file, algo)?.finish_hex())
+    }
+
+    fn hash_file(mut file: &File, algo: HashAlgorithm) -> Result<ChunkHasher, TeleportError> {
+        file.rewind()?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut hasher = algo.hasher();
+        loop {
+            let len = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(l) => l,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(TeleportError::Io(e)),
+            };
+            hasher.write(&buf[..len]);
+        }
         file.rewind()?;
 
-        Ok(out)
+        Ok(hasher)
     }
 
-    fn chunk_size(file_size: u64) -> usize {
-        let mut chunk = 1024;
+    /// Add the rolling checksum of each chunk, so a client can find the chunks at any offset
+    pub fn with_weak_hashes(mut self, mut file: &File) -> Result<Self, TeleportError> {
+        file.rewind()?;
+        let mut buf = vec![0; self.chunk_size as usize];
+        let mut reader = file.take(self.filesize);
+        self.weak_hash.clear();
         loop {
-            if file_size / chunk > 2048 {
-                chunk *= 2;
-            } else {
+            // Chunks must line up with chunk_hash, so fill each one completely
+            let mut len = 0;
+            while len < buf.len() {
+                match reader.read(&mut buf[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == 0 {
                 break;
             }
+            self.weak_hash.push(RollingSum::new(&buf[..len]).value());
         }
+        file.rewind()?;
 
-        if chunk > u32::MAX as u64 {
-            u32::MAX as usize
-        } else {
-            chunk as usize
-        }
+        Ok(self)
+    }
+
+    /// Rebuild `file` from the server's chunks found at any offset in it and literal data
+    /// in between, as rsync does. Only full chunks of the server's file are matched
+    pub fn rolling_ops(
+        &self,
+        mut file: &File,
+        algo: HashAlgorithm,
+    ) -> Result<Vec<DeltaOp>, TeleportError> {
+        let block = self.chunk_size as usize;
+        if block == 0 || self.weak_hash.len() != self.chunk_hash.len() {
+            return Err(TeleportError::InvalidDelta);
+        }
+
+        let full = (self.filesize / self.chunk_size as u64) as usize;
+        let mut chunks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, weak) in self.weak_hash.iter().enumerate().take(full) {
+            chunks.entry(*weak).or_default().push(i);
+        }
+
+        file.rewind()?;
+        let mut reader = BufReader::new(file);
+        let mut ops = Vec::<DeltaOp>::new();
+        // Pending literal data followed by the window, starting at `base` in the file
+        let mut data = Vec::<u8>::new();
+        let mut base: u64 = 0;
+        let mut start: usize = 0;
+        let mut sum: Option<RollingSum> = None;
+        let mut eof = false;
+        loop {
+            // Keep the window and the byte after it in memory
+            if !eof && data.len() <= start + block {
+                let need = (start + block + 1 - data.len()).max(64 * 1024);
+                eof = reader.by_ref().take(need as u64).read_to_end(&mut data)? == 0;
+                continue;
+            }
+            if data.len() < start + block {
+                break;
+            }
+
+            let window = &data[start..start + block];
+            let weak = sum.get_or_insert_with(|| RollingSum::new(window));
+            let found = chunks.get(&weak.value()).and_then(|c| {
+                let mut hasher = algo.hasher();
+                hasher.write(window);
+                let strong = hasher.finish128();
+                c.iter().find(|i| self.chunk_hash[**i] == strong).copied()
+            });
+            if let Some(i) = found {
+                push_literal(&mut ops, base, start, block);
+                ops.push(DeltaOp::Copy {
+                    offset: base + start as u64,
+                    source: i as u64 * block as u64,
+                    len: self.chunk_size,
+                });
+                data.drain(..start + block);
+                base += (start + block) as u64;
+                start = 0;
+                sum = None;
+                continue;
+            }
+
+            // No chunk starts here, this byte becomes literal data
+            match data.get(start + block) {
+                Some(next) => weak.roll(data[start], *next),
+                None => break,
+            }
+            start += 1;
+
+            // Bound the literal data held in memory
+            if start == block {
+                push_literal(&mut ops, base, start, block);
+                data.drain(..start);
+                base += start as u64;
+                start = 0;
+            }
+        }
+        push_literal(&mut ops, base, data.len(), block);
+        file.rewind()?;
+
+        Ok(ops)
+    }
+
+    /// Return the indices of the chunks in `local` that differ from this delta
+    pub fn changed_chunks(&self, local: &TeleportDelta) -> Vec<usize> {
+        // Chunks of different sizes cannot be compared, send everything
+        if self.chunk_size != local.chunk_size {
+            return (0..local.chunk_hash.len()).collect();
+        }
+
+        local
+            .chunk_hash
+            .iter()
+            .enumerate()
+            .filter(|(i, h)| self.chunk_hash.get(*i) != Some(h))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Default chunk size of a delta, aiming for DELTA_CHUNKS chunks
+    pub(crate) fn chunk_size(file_size: u64) -> usize {
+        Self::chunk_size_for(file_size, DELTA_CHUNKS)
+    }
+
+    /// The smallest power of two of at least 1024 bytes that splits `file_size` into at most
+    /// `chunks` full chunks plus a short last one, capped at u32::MAX
+    pub fn chunk_size_for(file_size: u64, chunks: u16) -> usize {
+        let chunks = u64::from(chunks.max(1));
+        let mut chunk: u64 = 1024;
+        while file_size / chunk > chunks {
+            chunk *= 2;
+        }
+
+        if chunk > u32::MAX as u64 {
+            u32::MAX as usize
+        } else {
+            chunk as usize
+        }
+    }
+}
+
+/// Add `len` bytes of literal data at `offset`, in pieces of at most `max` bytes
+fn push_literal(ops: &mut Vec<DeltaOp>, mut offset: u64, mut len: usize, max: usize) {
+    while len > 0 {
+        let n = len.min(max);
+        ops.push(DeltaOp::Literal {
+            offset,
+            len: n as u32,
+        });
+        offset += n as u64;
+        len -= n;
+    }
+}
+
+/// One step of rebuilding a file with a rolling delta
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeltaOp {
+    /// Send `len` bytes of the file at `offset`
+    Literal { offset: u64, len: u32 },
+    /// The server copies `len` bytes of its own file at `source` to `offset`
+    Copy { offset: u64, source: u64, len: u32 },
+}
+
+/// Adler-style checksum of a window that slides one byte at a time, as used by rsync
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RollingSum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingSum {
+    pub(crate) fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut sum = RollingSum { a: 0, b: 0, len };
+        for (i, x) in data.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(*x as u32);
+            sum.b = sum.b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+
+        sum
+    }
+
+    /// Slide the window by dropping `out` from its start and adding `new` at its end
+    pub(crate) fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub(crate) fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct TeleportData {
     pub offset: u64,
+    pub seq: u64,
+    pub flags: u8,
     pub data_len: u32,
+    pub checksum: u64,
     pub data: Vec<u8>,
 }
 
 impl TeleportData {
+    /// Flag marking the end of a transfer, its `seq` is the number of chunks sent before it
+    pub const FINAL: u8 = 0x01;
+
+    /// Flag for a chunk the server copies from its own file, `data` holds the source offset and length
+    pub const COPY: u8 = 0x02;
+
+    /// Flag for a chunk carrying the xxh3 checksum of its data after `data_len`
+    pub const CHECKSUM: u8 = 0x04;
+
+    /// Length of the fields before the data
+    const HEADER_LEN: usize = 21;
+
     pub fn new() -> TeleportData {
         TeleportData {
             offset: 0,
+            seq: 0,
+            flags: 0,
             data_len: 0,
+            checksum: 0,
             data: Vec::<u8>::new(),
         }
     }
 
-    pub fn serialize(&mut self) -> Result<Vec<u8>, TeleportError> {
-        let mut out = Vec::<u8>::new();
+    pub fn is_final(&self) -> bool {
+        self.flags & Self::FINAL == Self::FINAL
+    }
 
+    /// A chunk asking the server to copy `len` bytes of its own file at `source` to `offset`
+    pub fn copy(offset: u64, seq: u64, source: u64, len: u32) -> TeleportData {
+        let mut data = source.to_le_bytes().to_vec();
+        data.extend(len.to_le_bytes());
+        TeleportData {
+            offset,
+            seq,
+            flags: Self::COPY,
+            data_len: data.len() as u32,
+            checksum: 0,
+            data,
+        }
+    }
+
+    pub fn is_copy(&self) -> bool {
+        self.flags & Self::COPY == Self::COPY
+    }
+
+    /// The same chunk carrying the checksum of its data, for connections without AEAD
+    pub fn with_checksum(mut self) -> TeleportData {
+        self.flags |= Self::CHECKSUM;
+        self.checksum = xxh3::xxh3_64(&self.data);
+        self
+    }
+
+    /// The source offset and length of a COPY chunk
+    pub fn copy_source(&self) -> Result<(u64, u32), TeleportError> {
+        let mut buf: &[u8] = &self.data;
+        if buf.len() != 12 {
+            return Err(TeleportError::InvalidLength);
+        }
+
+        Ok((
+            buf.read_u64::<LittleEndian>()?,
+            buf.read_u32::<LittleEndian>()?,
+        ))
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::with_capacity(Self::HEADER_LEN + self.data.len());
+        self.serialize_into(&mut out)?;
+
+        Ok(out)
+    }
+
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
         // Add offset
-        out.append(&mut self.offset.to_le_bytes().to_vec());
+        w.write_u64::<LittleEndian>(self.offset)?;
+
+        // Add sequence number
+        w.write_u64::<LittleEndian>(self.seq)?;
+
+        // Add flags
+        w.write_u8(self.flags)?;
 
         // Add data length
-        let length = u32::try_from(self.data.len())?;
-        out.append(&mut length.to_le_bytes().to_vec());
+        w.write_u32::<LittleEndian>(u32::try_from(self.data.len())?)?;
+
+        // Add the data checksum if flagged
+        if self.flags & Self::CHECKSUM == Self::CHECKSUM {
+            w.write_u64::<LittleEndian>(self.checksum)?;
+        }
 
         // Add data
-        out.append(&mut self.data);
+        w.write_all(&self.data)?;
 
-        Ok(out)
+        Ok(())
     }
 
     pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
         let mut buf: &[u8] = input;
 
+        // Offset, sequence number, flags and data length must be present
+        if input.len() < Self::HEADER_LEN {
+            return Err(TeleportError::InvalidLength);
+        }
+
         // Extract offset
         self.offset = buf.read_u64::<LittleEndian>()?;
 
+        // Extract sequence number
+        self.seq = buf.read_u64::<LittleEndian>()?;
+
+        // Extract flags
+        self.flags = buf.read_u8()?;
+
         // Extract data length
         self.data_len = buf.read_u32::<LittleEndian>()?;
 
+        // Extract the data checksum if flagged
+        let checksum = self.flags & Self::CHECKSUM == Self::CHECKSUM;
+        if checksum {
+            self.checksum = buf.read_u64::<LittleEndian>()?;
+        }
+
         // Extract data
-        self.data = input[12..].to_vec();
+        self.data = buf.to_vec();
         if self.data.len() != self.data_len as usize {
             return Err(TeleportError::InvalidLength);
         }
 
+        // Verify the data before anything is written
+        if checksum && xxh3::xxh3_64(&self.data) != self.checksum {
+            return Err(TeleportError::ChunkChecksum);
+        }
+
         Ok(())
     }
 }
 
+/// A file on the server, as reported in a TeleportList
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TeleportListEntry {
+    pub filesize: u64,
+    pub filename_len: u16,
+    pub filename: Vec<u8>,
+}
+
+/// Response to a List request with the files found under the requested directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TeleportList {
+    pub status: u8,
+    pub entries: Vec<TeleportListEntry>,
+}
+
+impl TeleportList {
+    pub fn new(status: TeleportStatus) -> TeleportList {
+        TeleportList {
+            status: status as u8,
+            entries: Vec::<TeleportListEntry>::new(),
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+        self.serialize_into(&mut out)?;
+
+        Ok(out)
+    }
+
+    pub fn serialize_into<W: Write>(&self, w: &mut W) -> Result<(), TeleportError> {
+        // Add status
+        w.write_u8(self.status)?;
+
+        // Add number of entries
+        w.write_u32::<LittleEndian>(u32::try_from(self.entries.len())?)?;
+
+        // Add each entry's filesize, filename_len and filename
+        for entry in &self.entries {
+            w.write_u64::<LittleEndian>(entry.filesize)?;
+            w.write_u16::<LittleEndian>(u16::try_from(entry.filename.len())?)?;
+            w.write_all(&entry.filename)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract status
+        self.status = buf.read_u8()?;
+
+        // Extract number of entries
+        let count = buf.read_u32::<LittleEndian>()?;
+
+        // Extract entries
+        self.entries.clear();
+        for _ in 0..count {
+            let filesize = buf.read_u64::<LittleEndian>()?;
+            let filename_len = buf.read_u16::<LittleEndian>()?;
+            if buf.len() < filename_len as usize {
+                return Err(TeleportError::InvalidFileName);
+            }
+            let filename = buf[..filename_len as usize].to_vec();
+            buf = &buf[filename_len as usize..];
+
+            self.entries.push(TeleportListEntry {
+                filesize,
+                filename_len,
+                filename,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Any of the structures a packet or its payload can be decoded into
+#[derive(Debug)]
+pub enum Frame {
+    Header(TeleportHeader),
+    Enc(TeleportEnc),
+    Init(TeleportInit),
+    InitAck(TeleportInitAck),
+    Delta(TeleportDelta),
+    Data(TeleportData),
+    List(TeleportList),
+}
+
+/// Run every deserializer over untrusted bytes and return the first structure that accepts them
+///
+/// Used by the fuzz target: no input may panic, every failure must come back as a TeleportError.
+pub fn parse_any(bytes: &[u8]) -> Result<Frame, TeleportError> {
+    let mut header = TeleportHeader::new(TeleportAction::Init);
+    let mut enc = TeleportEnc::new()?;
+    let mut init = TeleportInit::default();
+    let mut ack = TeleportInitAck::default();
+    let mut delta = TeleportDelta::new();
+    let mut data = TeleportData::new();
+    let mut list = TeleportList::new(TeleportStatus::Proceed);
+
+    // Every deserializer sees the input, even when an earlier one already accepted it
+    let results = [
+        header.deserialize(bytes.to_vec()),
+        enc.deserialize(bytes),
+        init.deserialize(bytes),
+        ack.deserialize(bytes),
+        delta.deserialize(bytes),
+        data.deserialize(bytes),
+        list.deserialize(bytes),
+    ];
+    let frames = [
+        Frame::Header(header),
+        Frame::Enc(enc),
+        Frame::Init(init),
+        Frame::InitAck(ack),
+        Frame::Delta(delta),
+        Frame::Data(data),
+        Frame::List(list),
+    ];
+
+    let mut error = TeleportError::InvalidProtocol;
+    for (result, frame) in results.into_iter().zip(frames) {
+        match result {
+            Ok(()) => return Ok(frame),
+            Err(e) => error = e,
+        }
+    }
+
+    Err(error)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use rand::prelude::*;
 
+    /// Reader that returns at most 100 bytes per call, like a pipe or network filesystem
+    struct ShortReader<'a> {
+        data: &'a [u8],
+    }
+
+    impl Read for ShortReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.data.len()).min(100);
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
     const TESTHEADER: &[u8] = &[
-        84, 69, 76, 69, 80, 79, 82, 84, 17, 0, 0, 0, 129, 5, 48, 46, 50, 46, 51, 0, 246, 9, 10, 11,
-        12, 4, 0, 0, 0, 184, 34, 0, 0, 0, 0, 0, 0, 10, 10, 32, 3, 21,
+        84, 69, 76, 69, 80, 82, 84, 51, 1, 17, 0, 0, 0, 129, 5, 48, 46, 50, 46, 51, 0, 246, 9, 10,
+        11, 12, 4, 0, 0, 0, 184, 34, 0, 0, 0, 0, 0, 0, 10, 10, 32, 3, 21,
     ];
     const TESTHEADERIV: &[u8; 12] = &[5, 48, 46, 50, 46, 51, 0, 246, 9, 10, 11, 12];
     const TESTDATA: &[u8] = &[4, 0, 0, 0, 184, 34, 0, 0, 0, 0, 0, 0, 10, 10, 32, 3, 21];
     const TESTINIT: &[u8] = &[
-        0, 0, 5, 0, 5, 0, 5, 0, 0, 0, 237, 1, 0, 0, 57, 48, 0, 0, 0, 0, 0, 0, 4, 0, 102, 105, 108,
-        101,
+        0, 0, 5, 0, 5, 0, 5, 0, 0, 0, 237, 1, 0, 0, 57, 48, 0, 0, 0, 0, 0, 0, 21, 205, 91, 7, 0, 0,
+        0, 0, 4, 0, 102, 105, 108, 101, 0, 0, 0, 0,
     ];
     const TESTDELTA: &[u8] = &[
         177, 104, 222, 58, 0, 0, 0, 0, 57, 48, 0, 0, 0, 0, 0, 0, 21, 205, 91, 7, 0, 0,
     ];
-    const TESTDATAPKT: &[u8] = &[49, 212, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5];
+    const TESTDATAPKT: &[u8] = &[
+        49, 212, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5,
+    ];
     const TESTINITACK: &[u8] = &[0, 0, 0, 6, 0, 0, 0, 5, 0, 0, 0];
+    const TESTLIST: &[u8] = &[
+        0, 2, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 1, 0, 97, 57, 48, 0, 0, 0, 0, 0, 0, 5, 0, 100, 105,
+        114, 47, 98,
+    ];
+    const TESTINITACKRESUME: &[u8] = &[
+        0, 0, 0, 6, 0, 0, 0, 65, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 57, 48, 0, 0, 0, 0, 0, 0,
+    ];
 
     #[test]
     fn test_teleportheader_serialize() {
@@ -698,6 +1746,89 @@ mod tests {
         t.iv = Some(*TESTHEADERIV);
         let s = t.serialize().expect("Test should never fail");
         assert_eq!(s, TESTHEADER);
+
+        // Serializing a shared header is repeatable
+        let shared = &t;
+        assert_eq!(shared.serialize().expect("Test should never fail"), s);
+    }
+
+    #[test]
+    fn test_teleportaction_try_from() {
+        let action =
+            TeleportAction::try_from(TeleportAction::Data as u8).expect("Test should never fail");
+        assert_eq!(action, TeleportAction::Data);
+
+        let action =
+            TeleportAction::try_from(TeleportAction::Init as u8 | TeleportAction::Encrypted as u8)
+                .expect("Test should never fail");
+        assert_eq!(action, TeleportAction::Init);
+
+        assert!(matches!(
+            TeleportAction::try_from(TeleportAction::Encrypted as u8),
+            Err(TeleportError::InvalidProtocol)
+        ));
+        assert!(matches!(
+            TeleportAction::try_from(0x09),
+            Err(TeleportError::InvalidProtocol)
+        ));
+
+        let action = TeleportAction::try_from(0x03).expect("Test should never fail");
+        assert_eq!(action, TeleportAction::List);
+    }
+
+    #[test]
+    fn test_teleportstatus_try_from() {
+        let status = TeleportStatus::try_from(TeleportStatus::UnknownUser as u8)
+            .expect("Test should never fail");
+        assert_eq!(status, TeleportStatus::UnknownUser);
+
+        // The status survives the trip through a serialized TeleportInitAck
+        let ack = TeleportInitAck::new(TeleportStatus::UnknownUser);
+        let mut recv = TeleportInitAck::default();
+        recv.deserialize(&ack.serialize().expect("Test should never fail"))
+            .expect("Test should never fail");
+        assert_eq!(
+            TeleportStatus::try_from(recv.status).expect("Test should never fail"),
+            TeleportStatus::UnknownUser
+        );
+
+        let status = TeleportStatus::try_from(0x0e).expect("Test should never fail");
+        assert_eq!(status, TeleportStatus::Incomplete);
+        assert!(matches!(
+            TeleportStatus::try_from(0x0f),
+            Err(TeleportError::InvalidStatusCode)
+        ));
+    }
+
+    #[test]
+    fn test_teleportversion_is_compatible() {
+        let v = |major, minor, patch| TeleportVersion {
+            major,
+            minor,
+            patch,
+        };
+
+        assert!(v(0, 5, 1).is_compatible(v(0, 5, 9)));
+        assert!(!v(0, 5, 1).is_compatible(v(0, 6, 0)));
+        assert!(v(1, 2, 0).is_compatible(v(1, 5, 3)));
+        assert!(!v(1, 2, 0).is_compatible(v(2, 2, 0)));
+
+        let semver = Version::parse("0.6.2").expect("Test should never fail");
+        assert!(v(0, 6, 0).is_compatible(&semver));
+        assert!(!v(0, 5, 0).is_compatible(&semver));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            TeleportStatus::RequiresEncryption.to_string(),
+            "RequiresEncryption (0x05)"
+        );
+        assert_eq!(
+            TeleportStatus::UnknownAction.to_string(),
+            "UnknownAction (0xff)"
+        );
+        assert_eq!(TeleportAction::Data.to_string(), "Data (0x40)");
     }
 
     #[test]
@@ -713,54 +1844,337 @@ mod tests {
         assert_eq!(t, test);
     }
 
+    #[test]
+    fn test_teleportheader_version() {
+        // A newer peer keeps the fixed header, so its packets still parse
+        let mut data = TESTHEADER.to_vec();
+        data[8] = PROTOCOL_VERSION + 1;
+        let mut t = TeleportHeader::new(TeleportAction::Init);
+        t.deserialize(data.clone()).expect("Test should never fail");
+        assert_eq!(t.version, PROTOCOL_VERSION + 1);
+        assert_eq!(t.data, TESTDATA);
+
+        // One older than anything this build can parse is refused by number
+        data[8] = MIN_PROTOCOL_VERSION - 1;
+        let mut t = TeleportHeader::new(TeleportAction::Init);
+        let err = t.deserialize(data).expect_err("Test should never fail");
+        assert!(
+            matches!(err, TeleportError::UnsupportedProtocol(v) if v == MIN_PROTOCOL_VERSION - 1)
+        );
+        assert!(err
+            .to_string()
+            .contains(&format!("version {}", MIN_PROTOCOL_VERSION - 1)));
+    }
+
+    #[test]
+    fn test_teleportheader_deserialize_short() {
+        for len in [0, 5, 13] {
+            let mut t = TeleportHeader::new(TeleportAction::Init);
+            assert!(matches!(
+                t.deserialize(TESTHEADER[..len].to_vec()),
+                Err(TeleportError::InvalidLength)
+            ));
+        }
+
+        let mut t = TeleportHeader::new(TeleportAction::Init);
+        assert!(matches!(
+            t.deserialize(TESTHEADER[..25].to_vec()),
+            Err(TeleportError::InvalidIV)
+        ));
+
+        let mut t = TeleportHeader::new(TeleportAction::Init);
+        assert!(matches!(
+            t.deserialize(TESTHEADER[..31].to_vec()),
+            Err(TeleportError::InvalidLength)
+        ));
+    }
+
+    /// An RNG that cannot produce any bytes, like an OS without entropy
+    struct FailingRng;
+
+    impl rand::RngCore for FailingRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("infallible RNG call")
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            panic!("infallible RNG call")
+        }
+
+        fn fill_bytes(&mut self, _: &mut [u8]) {
+            panic!("infallible RNG call")
+        }
+
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+            Err(rand::Error::new(io::Error::new(
+                io::ErrorKind::Other,
+                "no entropy",
+            )))
+        }
+    }
+
+    impl rand::CryptoRng for FailingRng {}
+
+    #[test]
+    fn test_genkey_rng_failure() {
+        let mut ctx = TeleportEnc::new().expect("Test should never fail");
+        let res = crypto::genkey_from(&mut ctx, &mut FailingRng);
+        assert!(matches!(res, Err(TeleportError::EncryptionFailure)));
+        assert_eq!(ctx.public, [0; 32]);
+        assert!(matches!(
+            TeleportEnc::new_from(&mut FailingRng),
+            Err(TeleportError::EncryptionFailure)
+        ));
+        assert!(matches!(
+            crypto::gensecret_from(&mut FailingRng),
+            Err(TeleportError::EncryptionFailure)
+        ));
+    }
+
     #[test]
     fn test_teleportenc_key_exchange() {
-        let mut a = TeleportEnc::new();
-        let mut b = TeleportEnc::new();
+        let mut a = TeleportEnc::new().expect("Test should never fail");
+        let mut b = TeleportEnc::new().expect("Test should never fail");
 
-        let priva = crypto::genkey(&mut a);
-        let privb = crypto::genkey(&mut b);
+        let priva = crypto::genkey(&mut a).expect("Test should never fail");
+        let privb = crypto::genkey(&mut b).expect("Test should never fail");
 
         a.deserialize(&b.serialize())
             .expect("Test should never fail");
         b.deserialize(&a.serialize())
             .expect("Test should never fail");
 
-        a.calc_secret(priva);
-        b.calc_secret(privb);
+        a.calc_secret(&priva);
+        b.calc_secret(&privb);
 
         assert_eq!(a.secret, b.secret);
     }
 
+    #[test]
+    fn test_teleportenc_identity() {
+        let identity = StaticSecret::new(OsRng);
+        let mut client = TeleportEnc::new().expect("Test should never fail");
+        let mut server = TeleportEnc::new().expect("Test should never fail");
+
+        let privc = crypto::genkey(&mut client).expect("Test should never fail");
+        let privs = crypto::genkey(&mut server).expect("Test should never fail");
+
+        server
+            .deserialize(&client.serialize())
+            .expect("Test should never fail");
+        server.calc_secret(&privs);
+        server.bind_identity(&identity);
+
+        // The client learns the identity from the EcdhAck
+        let ack = server.serialize();
+        assert_eq!(ack.len(), 64);
+        client.deserialize(&ack).expect("Test should never fail");
+        client.calc_secret(&privc);
+        client
+            .bind_remote_identity(&privc)
+            .expect("Test should never fail");
+
+        assert_eq!(client.secret, server.secret);
+        assert_eq!(client.fingerprint(), server.fingerprint());
+
+        // A peer claiming the identity without its secret key derives a different key
+        let mut imposter = TeleportEnc::new().expect("Test should never fail");
+        let privi = crypto::genkey(&mut imposter).expect("Test should never fail");
+        imposter
+            .deserialize(&client.serialize())
+            .expect("Test should never fail");
+        imposter.calc_secret(&privi);
+        imposter.bind_identity(&StaticSecret::new(OsRng));
+        imposter.identity = server.identity;
+        assert_ne!(imposter.secret, server.secret);
+
+        // Servers that send no identity are refused
+        let mut plain = TeleportEnc::new().expect("Test should never fail");
+        let privp = crypto::genkey(&mut plain).expect("Test should never fail");
+        plain
+            .deserialize(&PublicKey::from(&privs).to_bytes())
+            .expect("Test should never fail");
+        assert!(matches!(
+            plain.bind_remote_identity(&privp),
+            Err(TeleportError::InvalidPubKey)
+        ));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let key = PublicKey::from(&StaticSecret::new(OsRng)).to_bytes();
+        let fp = crypto::fingerprint(&key);
+        assert_eq!(fp.len(), 29);
+        assert_eq!(fp.split('-').count(), 6);
+        assert_eq!(fp, crypto::fingerprint(&key));
+
+        assert!(crypto::fingerprint_matches(&fp, &fp.to_lowercase()));
+        assert!(crypto::fingerprint_matches(&fp, &fp.replace('-', "")));
+        assert!(!crypto::fingerprint_matches(&fp, &fp[..24]));
+        let other = PublicKey::from(&StaticSecret::new(OsRng)).to_bytes();
+        assert!(!crypto::fingerprint_matches(
+            &fp,
+            &crypto::fingerprint(&other)
+        ));
+    }
+
+    #[test]
+    fn test_teleportenc_zeroize() {
+        let mut a = TeleportEnc::new().expect("Test should never fail");
+        let mut b = TeleportEnc::new().expect("Test should never fail");
+
+        let priva = crypto::genkey(&mut a).expect("Test should never fail");
+        crypto::genkey(&mut b).expect("Test should never fail");
+
+        a.deserialize(&b.serialize())
+            .expect("Test should never fail");
+        a.calc_secret(&priva);
+        assert_ne!(a.secret, [0; 32]);
+
+        // Drop wipes the key material through the same Zeroize impl
+        assert!(std::mem::needs_drop::<TeleportEnc>());
+        a.zeroize();
+        assert_eq!(a.secret, [0; 32]);
+        assert_eq!(a.remote, [0; 32]);
+    }
+
+    #[test]
+    fn test_teleportenc_deserialize_low_order() {
+        for point in crypto::LOW_ORDER {
+            // Every point really does force an all-zero shared secret
+            let secret = StaticSecret::new(OsRng);
+            let shared = secret.diffie_hellman(&PublicKey::from(point));
+            assert_eq!(shared.to_bytes(), [0; 32]);
+
+            let mut a = TeleportEnc::new().expect("Test should never fail");
+            assert!(matches!(
+                a.deserialize(&point),
+                Err(TeleportError::InvalidPubKey)
+            ));
+
+            let mut high = point;
+            high[31] |= 0x80;
+            assert!(matches!(
+                a.deserialize(&high),
+                Err(TeleportError::InvalidPubKey)
+            ));
+        }
+
+        let mut a = TeleportEnc::new().expect("Test should never fail");
+        let mut b = TeleportEnc::new().expect("Test should never fail");
+        crypto::genkey(&mut b).expect("Test should never fail");
+        a.deserialize(&b.serialize())
+            .expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_teleportenc_next_nonce() {
+        let mut a = TeleportEnc::new().expect("Test should never fail");
+        let mut b = TeleportEnc::new().expect("Test should never fail");
+
+        let priva = crypto::genkey(&mut a).expect("Test should never fail");
+        let privb = crypto::genkey(&mut b).expect("Test should never fail");
+
+        a.deserialize(&b.serialize())
+            .expect("Test should never fail");
+        b.deserialize(&a.serialize())
+            .expect("Test should never fail");
+
+        a.calc_secret(&priva);
+        b.calc_secret(&privb);
+
+        let first = a.next_nonce().expect("Test should never fail");
+        let second = a.next_nonce().expect("Test should never fail");
+        assert_ne!(first, second);
+        assert_eq!(first[..4], second[..4]);
+        assert_eq!(second[4..], 1u64.to_le_bytes());
+
+        // The two peers never share a nonce prefix
+        let other = b.next_nonce().expect("Test should never fail");
+        assert_ne!(first[0] & 0x80, other[0] & 0x80);
+
+        a.counter.set(u64::MAX);
+        assert!(matches!(a.next_nonce(), Err(TeleportError::NonceExhausted)));
+    }
+
     #[test]
     fn test_teleportenc_encrypt_decrypt() {
         let mut rng = StdRng::from_entropy();
         let mut nonce: [u8; 12] = [0; 12];
 
-        let mut a = TeleportEnc::new();
-        let mut b = TeleportEnc::new();
+        let mut a = TeleportEnc::new().expect("Test should never fail");
+        let mut b = TeleportEnc::new().expect("Test should never fail");
 
-        let priva = crypto::genkey(&mut a);
-        let privb = crypto::genkey(&mut b);
+        let priva = crypto::genkey(&mut a).expect("Test should never fail");
+        let privb = crypto::genkey(&mut b).expect("Test should never fail");
 
         a.deserialize(&b.serialize())
             .expect("Test should never fail");
         b.deserialize(&a.serialize())
             .expect("Test should never fail");
 
-        a.calc_secret(priva);
-        b.calc_secret(privb);
+        a.calc_secret(&priva);
+        b.calc_secret(&privb);
 
         assert_eq!(a.secret, b.secret);
 
         let data = TESTHEADER.to_vec();
         rng.fill(&mut nonce);
-        let ciphertext = a.encrypt(&nonce, &data).expect("Test should never fail");
+        let ciphertext = a
+            .encrypt(&nonce, &[], &data)
+            .expect("Test should never fail");
         let plaintext = b
-            .decrypt(&nonce, &ciphertext)
+            .decrypt(&nonce, &[], &ciphertext)
+            .expect("Test should never fail");
+
+        assert_eq!(plaintext, data);
+    }
+
+    #[test]
+    fn test_teleportenc_header_aad() {
+        let mut a = TeleportEnc::new().expect("Test should never fail");
+        let mut b = TeleportEnc::new().expect("Test should never fail");
+
+        let priva = crypto::genkey(&mut a).expect("Test should never fail");
+        let privb = crypto::genkey(&mut b).expect("Test should never fail");
+
+        a.deserialize(&b.serialize())
             .expect("Test should never fail");
+        b.deserialize(&a.serialize())
+            .expect("Test should never fail");
+
+        a.calc_secret(&priva);
+        b.calc_secret(&privb);
+
+        let data = TESTDATA.to_vec();
+        let iv = a.next_nonce().expect("Test should never fail");
+        let mut header = TeleportHeader::new(TeleportAction::Data);
+        header.iv = Some(iv);
+        let aad = header
+            .aad(data.len() + crypto::TAG_LEN)
+            .expect("Test should never fail");
+        header.data = a.encrypt(&iv, &aad, &data).expect("Test should never fail");
+        let mut packet = header.serialize().expect("Test should never fail");
 
+        // Untouched packet authenticates
+        let mut recv = TeleportHeader::new(TeleportAction::Init);
+        recv.deserialize(packet.clone())
+            .expect("Test should never fail");
+        let aad = recv.aad(recv.data.len()).expect("Test should never fail");
+        let plaintext = b
+            .decrypt(&iv, &aad, &recv.data)
+            .expect("Test should never fail");
         assert_eq!(plaintext, data);
+
+        // Flipping the action byte breaks authentication
+        packet[13] = TeleportAction::InitAck as u8 | TeleportAction::Encrypted as u8;
+        let mut recv = TeleportHeader::new(TeleportAction::Init);
+        recv.deserialize(packet).expect("Test should never fail");
+        let aad = recv.aad(recv.data.len()).expect("Test should never fail");
+        assert!(matches!(
+            b.decrypt(&iv, &aad, &recv.data),
+            Err(TeleportError::DecryptionFailure)
+        ));
     }
 
     #[test]
@@ -773,6 +2187,7 @@ mod tests {
         };
         test.filename = vec![b'f', b'i', b'l', b'e'];
         test.filesize = 12345;
+        test.whole_hash = 123456789;
         test.chmod = 0o755;
         TeleportFeatures::Overwrite.add_u32(&mut test.features);
 
@@ -791,6 +2206,7 @@ mod tests {
         test.filename = vec![b'f', b'i', b'l', b'e'];
         test.filename_len = test.filename.len() as u16;
         test.filesize = 12345;
+        test.whole_hash = 123456789;
         test.chmod = 0o755;
         TeleportFeatures::Overwrite.add_u32(&mut test.features);
 
@@ -805,13 +2221,173 @@ mod tests {
         assert_eq!(test, t);
     }
 
+    #[test]
+    fn test_teleportinit_mtime() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = vec![b'f', b'i', b'l', b'e'];
+        test.filename_len = test.filename.len() as u16;
+        test.mtime = 1_600_000_000;
+        TeleportFeatures::PreserveTimes.add_u32(&mut test.features);
+
+        let out = test.serialize().expect("Test should never fail");
+        assert_eq!(out.len(), TESTINIT.len() + 8);
+        assert_eq!(out[TESTINIT.len()..], 1_600_000_000u64.to_le_bytes());
+
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // Without the flag the field is neither sent nor expected
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(TESTINIT).expect("Test should never fail");
+        assert_eq!(t.mtime, 0);
+    }
+
+    #[test]
+    fn test_teleportinit_owner() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = vec![b'f', b'i', b'l', b'e'];
+        test.filename_len = test.filename.len() as u16;
+        test.uid = 1000;
+        test.gid = 100;
+        TeleportFeatures::PreserveOwner.add_u32(&mut test.features);
+
+        let out = test.serialize().expect("Test should never fail");
+        assert_eq!(out.len(), TESTINIT.len() + 8);
+        assert_eq!(out[TESTINIT.len()..], [232, 3, 0, 0, 100, 0, 0, 0]);
+
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // The owner follows the symlink target when both are sent
+        TeleportFeatures::FileType.add_u32(&mut test.features);
+        test.file_type = FileType::Symlink as u8;
+        test.link_target = b"b.txt".to_vec();
+        test.link_target_len = 5;
+        let out = test.serialize().expect("Test should never fail");
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportinit_xattrs() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = vec![b'f', b'i', b'l', b'e'];
+        test.filename_len = test.filename.len() as u16;
+        test.xattrs = vec![
+            (b"user.a".to_vec(), b"1".to_vec()),
+            (b"user.empty".to_vec(), vec![]),
+        ];
+        TeleportFeatures::PreserveXattr.add_u32(&mut test.features);
+
+        let out = test.serialize().expect("Test should never fail");
+        assert_eq!(
+            out.len(),
+            TESTINIT.len() + xattr::serialized_size(&test.xattrs)
+        );
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // A value cut short by the end of the packet is refused
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        assert!(t.deserialize(&out[..out.len() - 12]).is_err());
+
+        // Attributes over the size limit are neither sent nor accepted
+        test.xattrs = vec![(b"user.big".to_vec(), vec![0; MAX_XATTR_SIZE])];
+        assert!(matches!(
+            test.serialize(),
+            Err(TeleportError::InvalidLength)
+        ));
+        let mut out = TESTINIT.to_vec();
+        out[6..10].copy_from_slice(&(TeleportFeatures::PreserveXattr as u32).to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        for _ in 0..2 {
+            out.extend(1u16.to_le_bytes());
+            out.push(b'a');
+            out.extend(u16::MAX.to_le_bytes());
+            out.extend(vec![0; u16::MAX as usize]);
+        }
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        assert!(matches!(
+            t.deserialize(&out),
+            Err(TeleportError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn test_teleportinit_file_type() {
+        for (file_type, target) in [
+            (FileType::Regular, &b""[..]),
+            (FileType::Dir, &b""[..]),
+            (FileType::Symlink, &b"../b.txt"[..]),
+        ] {
+            let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+            test.filename = vec![b'f', b'i', b'l', b'e'];
+            test.filename_len = test.filename.len() as u16;
+            TeleportFeatures::FileType.add_u32(&mut test.features);
+            test.file_type = file_type as u8;
+            test.link_target = target.to_vec();
+            test.link_target_len = target.len() as u16;
+
+            let out = test.serialize().expect("Test should never fail");
+            let extra = match file_type {
+                FileType::Symlink => 1 + 2 + target.len(),
+                _ => 1,
+            };
+            assert_eq!(out.len(), TESTINIT.len() + extra);
+            assert_eq!(out[TESTINIT.len()], file_type as u8);
+
+            let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+            t.deserialize(&out).expect("Test should never fail");
+            assert_eq!(test, t);
+
+            // A symlink target longer than the packet is refused
+            if file_type == FileType::Symlink {
+                let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+                assert!(t.deserialize(&out[..out.len() - 1]).is_err());
+            }
+        }
+
+        // Unknown entry types are refused
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        TeleportFeatures::FileType.add_u32(&mut test.features);
+        test.file_type = 0x7f;
+        let out = test.serialize().expect("Test should never fail");
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        assert!(t.deserialize(&out).is_err());
+
+        // Without the flag every entry is a regular file
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(TESTINIT).expect("Test should never fail");
+        assert_eq!(t.file_type, FileType::Regular as u8);
+    }
+
+    #[test]
+    fn test_teleportinit_deserialize_bad_filename() {
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        assert!(matches!(
+            t.deserialize(&TESTINIT[..34]),
+            Err(TeleportError::InvalidFileName)
+        ));
+
+        // Filenames that are not valid UTF-8 are kept as raw bytes
+        let mut input = TESTINIT.to_vec();
+        input[32] = 0xff;
+        let mut t = TeleportInit::new(TeleportFeatures::NewFile);
+        t.deserialize(&input).expect("Test should never fail");
+        assert_eq!(t.filename, vec![0xff, b'i', b'l', b'e']);
+    }
+
     #[test]
     fn test_teleportdelta_serialize() {
         let mut test = TeleportDelta::new();
         test.filesize = 987654321;
         test.hash = 12345;
         test.chunk_size = 123456789;
-        test.chunk_hash = Vec::<u64>::new();
+        test.chunk_hash = Vec::<u128>::new();
 
         let out = test.serialize().expect("Test should never fail");
 
@@ -824,7 +2400,7 @@ mod tests {
         test.filesize = 987654321;
         test.hash = 12345;
         test.chunk_size = 123456789;
-        test.chunk_hash = Vec::<u64>::new();
+        test.chunk_hash = Vec::<u128>::new();
 
         let mut t = TeleportDelta::new();
         t.deserialize(TESTDELTA).expect("Test should never fail");
@@ -836,6 +2412,7 @@ mod tests {
     fn test_teleportdata_serialize() {
         let mut test = TeleportData::new();
         test.offset = 54321;
+        test.seq = 7;
         test.data_len = 5;
         test.data = vec![1, 2, 3, 4, 5];
 
@@ -844,10 +2421,26 @@ mod tests {
         assert_eq!(out, TESTDATAPKT);
     }
 
+    #[test]
+    fn test_teleportdata_serialize_into() {
+        let mut test = TeleportData::new();
+        test.offset = 54321;
+        test.seq = 7;
+        test.data = vec![1, 2, 3, 4, 5];
+
+        let mut out = Vec::<u8>::new();
+        test.serialize_into(&mut out)
+            .expect("Test should never fail");
+
+        assert_eq!(out, TESTDATAPKT);
+        assert_eq!(test.data, vec![1, 2, 3, 4, 5]);
+    }
+
     #[test]
     fn test_teleportdata_deserialize() {
         let mut test = TeleportData::new();
         test.offset = 54321;
+        test.seq = 7;
         test.data_len = 5;
         test.data = vec![1, 2, 3, 4, 5];
 
@@ -857,6 +2450,79 @@ mod tests {
         assert_eq!(test, t);
     }
 
+    #[test]
+    fn test_teleportdata_deserialize_short() {
+        for len in [0, 8, 16, 20, 24] {
+            let mut t = TeleportData::new();
+            assert!(matches!(
+                t.deserialize(&TESTDATAPKT[..len]),
+                Err(TeleportError::InvalidLength)
+            ));
+        }
+
+        let mut long = TESTDATAPKT.to_vec();
+        long.push(6);
+        let mut t = TeleportData::new();
+        assert!(matches!(
+            t.deserialize(&long),
+            Err(TeleportError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn test_teleportdata_final() {
+        let mut test = TeleportData::new();
+        test.offset = 5;
+        test.seq = 1;
+        test.flags = TeleportData::FINAL;
+
+        let mut t = TeleportData::new();
+        t.deserialize(&test.serialize().expect("Test should never fail"))
+            .expect("Test should never fail");
+
+        assert!(t.is_final() && !TeleportData::new().is_final());
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportdata_copy() {
+        let test = TeleportData::copy(8192, 3, 4096, 4096);
+        let mut t = TeleportData::new();
+        t.deserialize(&test.serialize().expect("Test should never fail"))
+            .expect("Test should never fail");
+
+        assert!(t.is_copy() && !t.is_final() && !TeleportData::new().is_copy());
+        assert_eq!(
+            t.copy_source().expect("Test should never fail"),
+            (4096, 4096)
+        );
+        assert!(TeleportData::new().copy_source().is_err());
+    }
+
+    #[test]
+    fn test_teleportdata_checksum() {
+        let mut test = TeleportData::new();
+        test.data = TESTDATA.to_vec();
+        test.data_len = test.data.len() as u32;
+        let plain = test.serialize().expect("Test should never fail");
+        let test = test.with_checksum();
+        let out = test.serialize().expect("Test should never fail");
+        assert_eq!(out.len(), plain.len() + 8);
+
+        let mut t = TeleportData::new();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(t, test);
+
+        // Corrupted data is caught before anyone writes it
+        let mut bad = out.clone();
+        *bad.last_mut().expect("Test should never fail") ^= 0xff;
+        let mut t = TeleportData::new();
+        assert!(matches!(
+            t.deserialize(&bad),
+            Err(TeleportError::ChunkChecksum)
+        ));
+    }
+
     #[test]
     fn test_teleportinitack_serialize() {
         let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
@@ -888,4 +2554,368 @@ mod tests {
 
         assert_eq!(test, t);
     }
+
+    #[test]
+    fn test_teleportinitack_resume_serialize() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        let feat = TeleportFeatures::NewFile as u32 | TeleportFeatures::Resume as u32;
+        test.features = Some(feat);
+        test.resume_offset = Some(4096);
+        test.resume_hash = Some(12345);
+        test.version = TeleportVersion {
+            major: 0,
+            minor: 6,
+            patch: 0,
+        };
+        let out = test.serialize().expect("Test should never fail");
+
+        assert_eq!(out, TESTINITACKRESUME);
+    }
+
+    #[test]
+    fn test_teleportinitack_resume_deserialize() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        let feat = TeleportFeatures::NewFile as u32 | TeleportFeatures::Resume as u32;
+        test.features = Some(feat);
+        test.resume_offset = Some(4096);
+        test.resume_hash = Some(12345);
+        test.version = TeleportVersion {
+            major: 0,
+            minor: 6,
+            patch: 0,
+        };
+
+        let mut t = TeleportInitAck::new(TeleportStatus::Proceed);
+        t.deserialize(TESTINITACKRESUME)
+            .expect("Test should never fail");
+
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportinitack_stream_id() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        let feat = TeleportFeatures::NewFile as u32 | TeleportFeatures::Streams as u32;
+        test.features = Some(feat);
+        test.stream_id = Some([7; STREAM_ID_LEN]);
+        let out = test.serialize().expect("Test should never fail");
+
+        let mut t = TeleportInitAck::new(TeleportStatus::Proceed);
+        t.deserialize(&out).expect("Test should never fail");
+
+        assert_eq!(test, t);
+        assert!(t.deserialize(&out[..out.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_teleportinitack_rolling_delta() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        let feat = TeleportFeatures::NewFile as u32
+            | TeleportFeatures::Delta as u32
+            | TeleportFeatures::RollingDelta as u32;
+        test.features = Some(feat);
+        let mut delta = TeleportDelta::new();
+        delta.filesize = 8192;
+        delta.chunk_size = 4096;
+        delta.chunk_hash = vec![1, 2];
+        delta.chunk_hash_len = 2;
+        delta.weak_hash = vec![3, 4];
+        test.delta = Some(delta);
+        let out = test.serialize().expect("Test should never fail");
+
+        let mut t = TeleportInitAck::new(TeleportStatus::Proceed);
+        t.deserialize(&out).expect("Test should never fail");
+
+        assert_eq!(test, t);
+        assert!(t.deserialize(&out[..out.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_rolling_sum() {
+        let mut rng = StdRng::from_entropy();
+        let mut data = vec![0u8; 10_000];
+        rng.fill(&mut data[..]);
+
+        let mut sum = RollingSum::new(&data[..4096]);
+        for start in 1..data.len() - 4096 {
+            sum.roll(data[start - 1], data[start + 4095]);
+            assert_eq!(
+                sum.value(),
+                RollingSum::new(&data[start..start + 4096]).value()
+            );
+        }
+        assert_ne!(
+            RollingSum::new(b"ab").value(),
+            RollingSum::new(b"ba").value()
+        );
+    }
+
+    #[test]
+    fn test_teleportdelta_rolling_ops() {
+        for algo in [HashAlgorithm::Xxh3, HashAlgorithm::Blake3] {
+            let mut rng = StdRng::from_entropy();
+            let mut server = vec![0u8; 4 * 1024 * 1024];
+            rng.fill(&mut server[..]);
+
+            let path = std::env::temp_dir().join(format!(
+                "teleporter-rolling-{}-{:?}",
+                std::process::id(),
+                algo
+            ));
+            std::fs::write(&path, &server).expect("Test should never fail");
+            let file = File::open(&path).expect("Test should never fail");
+            let remote = TeleportDelta::delta_hash(&file, algo)
+                .and_then(|d| d.with_weak_hashes(&file))
+                .expect("Test should never fail");
+            assert_eq!(remote.weak_hash.len(), remote.chunk_hash.len());
+
+            // Insert a few bytes near the start and cut some out further on, shifting every chunk
+            let chunk = remote.chunk_size as usize;
+            let mut local = b"inserted".to_vec();
+            local.extend(&server[..chunk / 3]);
+            local.extend(&server[10 * chunk + 5..]);
+            std::fs::write(&path, &local).expect("Test should never fail");
+            let file = File::open(&path).expect("Test should never fail");
+            let ops = remote
+                .rolling_ops(&file, algo)
+                .expect("Test should never fail");
+            std::fs::remove_file(&path).expect("Test should never fail");
+
+            // Rebuild the local file from the ops
+            let mut rebuilt = Vec::<u8>::new();
+            let mut literal = 0;
+            for op in ops {
+                match op {
+                    DeltaOp::Literal { offset, len } => {
+                        assert_eq!(offset as usize, rebuilt.len());
+                        let range = offset as usize..offset as usize + len as usize;
+                        rebuilt.extend(&local[range]);
+                        literal += len as usize;
+                    }
+                    DeltaOp::Copy {
+                        offset,
+                        source,
+                        len,
+                    } => {
+                        assert_eq!(offset as usize, rebuilt.len());
+                        let range = source as usize..source as usize + len as usize;
+                        rebuilt.extend(&server[range]);
+                    }
+                }
+            }
+            assert_eq!(rebuilt, local);
+            assert!(literal < 3 * chunk, "{literal} literal bytes");
+        }
+    }
+
+    #[test]
+    fn test_teleportdelta_changed_chunks() {
+        for algo in [HashAlgorithm::Xxh3, HashAlgorithm::Blake3] {
+            let mut rng = StdRng::from_entropy();
+            let mut data = vec![0u8; 4 * 1024 * 1024];
+            rng.fill(&mut data[..]);
+
+            let path = std::env::temp_dir().join(format!(
+                "teleporter-delta-{}-{:?}",
+                std::process::id(),
+                algo
+            ));
+            std::fs::write(&path, &data).expect("Test should never fail");
+            let remote = TeleportDelta::delta_hash(
+                &File::open(&path).expect("Test should never fail"),
+                algo,
+            )
+            .expect("Test should never fail");
+
+            // Modify a single byte in the middle of one chunk
+            let chunk = remote.chunk_size as usize;
+            data[5 * chunk + chunk / 2] ^= 0xff;
+            std::fs::write(&path, &data).expect("Test should never fail");
+            let file = File::open(&path).expect("Test should never fail");
+            let local = TeleportDelta::delta_hash_chunks(&file, data.len() as u64, chunk, algo)
+                .expect("Test should never fail");
+            std::fs::remove_file(&path).expect("Test should never fail");
+
+            assert_eq!(remote.changed_chunks(&local), vec![5]);
+        }
+    }
+
+    #[test]
+    fn test_hash_algorithm() {
+        assert_eq!(HashAlgorithm::from_features(0), HashAlgorithm::Xxh3);
+        let strong = TeleportFeatures::StrongHash as u32 | TeleportFeatures::NewFile as u32;
+        assert_eq!(HashAlgorithm::from_features(strong), HashAlgorithm::Blake3);
+
+        // BLAKE3 hashes are the digest truncated to the first 8 bytes
+        let mut hasher = HashAlgorithm::Blake3.hasher();
+        hasher.write(b"teleporter");
+        let digest = blake3::hash(b"teleporter");
+        let mut expected = [0; 8];
+        expected.copy_from_slice(&digest.as_bytes()[..8]);
+        assert_eq!(hasher.finish(), u64::from_le_bytes(expected));
+
+        let mut xxh = HashAlgorithm::Xxh3.hasher();
+        xxh.write(b"teleporter");
+        assert_ne!(xxh.finish(), hasher.finish());
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        // Small files use the minimum, then the size doubles to stay within the chunk count
+        assert_eq!(TeleportDelta::chunk_size(0), 1024);
+        assert_eq!(TeleportDelta::chunk_size(1000), 1024);
+        assert_eq!(TeleportDelta::chunk_size(2048 * 1024), 1024);
+        assert_eq!(TeleportDelta::chunk_size(2048 * 1024 + 1024), 2048);
+        assert_eq!(TeleportDelta::chunk_size(100_000_000), 65536);
+        assert_eq!(TeleportDelta::chunk_size(1 << 40), 1 << 29);
+        assert_eq!(TeleportDelta::chunk_size(1 << 53), u32::MAX as usize);
+
+        assert_eq!(TeleportDelta::chunk_size_for(100_000_000, 16), 8 << 20);
+        assert_eq!(TeleportDelta::chunk_size_for(100_000_000, 0), 64 << 20);
+        for size in [1u64, 5000, 100_000_000, 1 << 40, 1 << 43] {
+            let chunk = TeleportDelta::chunk_size_for(size, u16::MAX - 1) as u64;
+            assert!((size + chunk - 1) / chunk <= u16::MAX as u64);
+        }
+    }
+
+    #[test]
+    fn test_delta_hash_chunk_sizes() {
+        let path = std::env::temp_dir().join(format!("teleporter-chunks-{}", std::process::id()));
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).expect("Test should never fail");
+        let file = File::open(&path).expect("Test should never fail");
+
+        // Only the chunk hashes change with the chunk size, never the whole file hash
+        let default =
+            TeleportDelta::delta_hash(&file, HashAlgorithm::Xxh3).expect("Test should never fail");
+        for chunk in [1024, 3000, 4096, 65536] {
+            let delta = TeleportDelta::delta_hash_chunks(&file, 10_000, chunk, HashAlgorithm::Xxh3)
+                .expect("Test should never fail");
+            assert_eq!(delta.hash, default.hash);
+            assert_eq!(delta.chunk_size as usize, chunk);
+            assert_eq!(delta.chunk_hash.len(), (10_000 + chunk - 1) / chunk);
+        }
+        std::fs::remove_file(&path).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_delta_hash_unaligned() {
+        let path =
+            std::env::temp_dir().join(format!("teleporter-unaligned-{}", std::process::id()));
+        for size in [0usize, 1000, 1024, 5000, 3_000_000] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            std::fs::write(&path, &data).expect("Test should never fail");
+            let file = File::open(&path).expect("Test should never fail");
+
+            let mut hasher = HashAlgorithm::Xxh3.hasher();
+            hasher.write(&data);
+            let delta =
+                TeleportDelta::delta_hash_chunks(&file, size as u64, 1024, HashAlgorithm::Xxh3)
+                    .expect("Test should never fail");
+            assert_eq!(delta.hash, hasher.finish());
+            let stream = TeleportDelta::stream_hash(&file, HashAlgorithm::Xxh3)
+                .expect("Test should never fail");
+            assert_eq!(delta.hash, stream);
+
+            // The short last chunk only covers its own bytes
+            if let Some(last) = data.chunks(1024).last() {
+                let mut hasher = HashAlgorithm::Xxh3.hasher();
+                hasher.write(last);
+                assert_eq!(delta.chunk_hash.last(), Some(&hasher.finish128()));
+            }
+
+            // A reader that returns less than asked still hashes full chunks
+            let reader = ShortReader { data: &data };
+            let short = TeleportDelta::hash_chunks(reader, size as u64, 1024, HashAlgorithm::Xxh3)
+                .expect("Test should never fail");
+            assert_eq!(short.hash, delta.hash);
+            assert_eq!(short.chunk_hash, delta.chunk_hash);
+        }
+        std::fs::remove_file(&path).expect("Test should never fail");
+    }
+
+    #[test]
+    fn test_teleportlist_serialize() {
+        let mut t = TeleportList::new(TeleportStatus::Proceed);
+        for (filesize, name) in [(5, "a"), (12345, "dir/b")] {
+            t.entries.push(TeleportListEntry {
+                filesize,
+                filename_len: name.len() as u16,
+                filename: name.as_bytes().to_vec(),
+            });
+        }
+
+        let s = t.serialize().expect("Test should never fail");
+        assert_eq!(s, TESTLIST);
+
+        let mut d = TeleportList::new(TeleportStatus::NoPermission);
+        d.deserialize(&s).expect("Test should never fail");
+        assert_eq!(d, t);
+    }
+
+    #[test]
+    fn test_teleportlist_deserialize_truncated() {
+        let mut t = TeleportList::new(TeleportStatus::Proceed);
+        assert!(t.deserialize(&TESTLIST[..TESTLIST.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_any_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0x7e1e);
+
+        // Every prefix of valid payloads, then random bytes with a plausible length field
+        let mut init = TeleportInit::new(TeleportFeatures::NewFile);
+        init.filename = b"file.bin".to_vec();
+        init.username = b"user".to_vec();
+        let mut samples = vec![
+            init.serialize().expect("Test should never fail"),
+            TeleportInitAck::new(TeleportStatus::Proceed)
+                .serialize()
+                .expect("Test should never fail"),
+            TeleportData::new()
+                .serialize()
+                .expect("Test should never fail"),
+        ];
+        for _ in 0..2000 {
+            let len = rng.gen_range(0, 128);
+            samples.push((0..len).map(|_| rng.gen()).collect());
+        }
+
+        for sample in samples {
+            for end in 0..=sample.len() {
+                let _ = parse_any(&sample[..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_teleportdelta_chunk_hash_width() {
+        for algo in [HashAlgorithm::Xxh3, HashAlgorithm::Blake3] {
+            // Two chunks that differ in a single bit
+            let mut data = vec![0u8; 2 * 4096];
+            data[4096] = 1;
+            let path = std::env::temp_dir().join(format!(
+                "teleporter-width-{}-{:?}",
+                std::process::id(),
+                algo
+            ));
+            std::fs::write(&path, &data).expect("Test should never fail");
+            let file = File::open(&path).expect("Test should never fail");
+            let delta = TeleportDelta::delta_hash_chunks(&file, data.len() as u64, 4096, algo)
+                .expect("Test should never fail");
+            std::fs::remove_file(&path).expect("Test should never fail");
+
+            assert_eq!(delta.chunk_hash.len(), 2);
+            assert_ne!(delta.chunk_hash[0], delta.chunk_hash[1]);
+            // Both halves of the 128-bit hash are populated and differ
+            assert_ne!(delta.chunk_hash[0] >> 64, delta.chunk_hash[1] >> 64);
+            assert_ne!(delta.chunk_hash[0] as u64, delta.chunk_hash[1] as u64);
+
+            // The full width survives serialization
+            let mut d = TeleportDelta::new();
+            d.deserialize(&delta.serialize().expect("Test should never fail"))
+                .expect("Test should never fail");
+            assert_eq!(d.chunk_hash, delta.chunk_hash);
+        }
+    }
 }
@@ -1,19 +1,48 @@
+use crate::crypto;
 use crate::errors::TeleportError;
-use crate::teleport::{TeleportAction, TeleportEnc, TeleportHeader, TeleportInit};
-use crate::PROTOCOL;
+use crate::teleport::{TeleportAction, TeleportEnc, TeleportHeader, HEADER_LEN};
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL, PROTOCOL_VERSION};
 use byteorder::{LittleEndian, ReadBytesExt};
-use rand::prelude::*;
+use log::debug;
+use std::fs;
 use std::io;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn print_updates(received: f64, header: &TeleportInit) {
-    let units = UpdateUnit::update(received, header.filesize as f64);
-    print!(
-        "\r => {:>8.03}{} of {:>8.03}{} ({:02.02}%)",
-        units.partial.value, units.partial.unit, units.total.value, units.total.unit, units.percent
-    );
-    io::stdout().flush().expect("Fatal IO error");
+/// Progress line showing bytes done, percent, current speed and time remaining
+pub fn format_updates(received: f64, total: f64, rate: f64, eta: Option<Duration>) -> String {
+    let units = UpdateUnit::update(received, total);
+    let speed = SizeUnit::identify(rate);
+    let eta = match eta {
+        Some(e) => format_eta(e),
+        None => "--:--".to_string(),
+    };
+    format!(
+        " => {:>8.03}{} of {:>8.03}{} ({:>6.02}%) {:>8.03}{}/s ETA {}",
+        units.partial.value,
+        units.partial.unit,
+        units.total.value,
+        units.total.unit,
+        units.percent,
+        speed.value,
+        speed.unit,
+        eta
+    )
+}
+
+/// Remaining time as MM:SS, or H:MM:SS once it reaches an hour, rounded up to whole seconds
+fn format_eta(eta: Duration) -> String {
+    format_clock(eta.as_secs() + u64::from(eta.subsec_nanos() > 0))
+}
+
+/// Seconds as MM:SS, or H:MM:SS once they reach an hour
+pub fn format_clock(secs: u64) -> String {
+    match secs / 3600 {
+        0 => format!("{:02}:{:02}", secs / 60, secs % 60),
+        h => format!("{}:{:02}:{:02}", h, secs / 60 % 60, secs % 60),
+    }
 }
 
 struct UpdateUnit {
@@ -24,7 +53,11 @@ struct UpdateUnit {
 
 impl UpdateUnit {
     pub fn update(partial: f64, total: f64) -> Self {
-        let percent: f64 = (partial / total) * 100f64;
+        // An empty file is complete as soon as it starts
+        let percent: f64 = match total > 0.0 {
+            true => (partial / total) * 100f64,
+            false => 100f64,
+        };
         let p = SizeUnit::identify(partial);
         let t = SizeUnit::identify(total);
 
@@ -65,54 +98,229 @@ impl SizeUnit {
     }
 }
 
-pub fn send_packet(
-    sock: &mut TcpStream,
+/// Human-readable size, in the same units as the progress output
+pub fn format_size(bytes: u64) -> String {
+    let size = SizeUnit::identify(bytes as f64);
+    format!("{:.3}{}", size.value, size.unit)
+}
+
+/// Bytes buffered when reading or writing file data
+pub const FILE_BUFFER: usize = 128 * 1024;
+
+/// Speed in megabits per second of moving `bytes` in `duration`
+pub fn mbps(bytes: u64, duration: Duration) -> f64 {
+    // A transfer too fast to measure has no meaningful speed
+    if duration.is_zero() {
+        return 0.0;
+    }
+
+    (bytes as f64 * 8.0) / duration.as_secs_f64() / 1024.0 / 1024.0
+}
+
+/// Unix permission bits of a file, built from the read-only flag where there are none
+#[cfg(unix)]
+pub fn file_mode(perms: &fs::Permissions) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    perms.mode()
+}
+
+/// Unix permission bits of a file, built from the read-only flag where there are none
+#[cfg(not(unix))]
+pub fn file_mode(perms: &fs::Permissions) -> u32 {
+    if perms.readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Apply unix permission bits received from the client
+#[cfg(unix)]
+pub fn set_file_mode(perms: &mut fs::Permissions, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    perms.set_mode(mode);
+}
+
+/// Apply unix permission bits received from the client, only the owner write bit has an equivalent
+#[cfg(not(unix))]
+pub fn set_file_mode(perms: &mut fs::Permissions, mode: u32) {
+    let readonly = mode & 0o200 == 0;
+    debug!("Mapping file mode {mode:o} to read-only: {readonly}");
+    perms.set_readonly(readonly);
+}
+
+/// Give a file the uid and gid received from the client, which needs the privilege to chown
+#[cfg(unix)]
+pub fn set_owner(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: path is a valid NUL-terminated string that outlives the call
+    match unsafe { libc::chown(path.as_ptr(), uid, gid) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Give a file the uid and gid received from the client, there are no owners to set here
+#[cfg(not(unix))]
+pub fn set_owner(_path: &Path, _uid: u32, _gid: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Read at offset until `buf` is full or the file ends, without moving the file cursor other threads share
+pub fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(file, &mut buf[len..], offset + len as u64);
+        #[cfg(windows)]
+        let read =
+            std::os::windows::fs::FileExt::seek_read(file, &mut buf[len..], offset + len as u64);
+        match read {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+/// Write all of `buf` at offset, without moving the file cursor other threads share
+pub fn write_at(file: &fs::File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        #[cfg(unix)]
+        let written = std::os::unix::fs::FileExt::write_at(file, buf, offset);
+        #[cfg(windows)]
+        let written = std::os::windows::fs::FileExt::seek_write(file, buf, offset);
+        match written {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a human-readable size such as "512K" or "10M" into bytes, using the same units as SizeUnit
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let units = ['B', 'K', 'M', 'G', 'T'];
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some(c) if units.contains(&c) => {
+            let exp = units.iter().position(|u| *u == c).unwrap_or(0) as u32;
+            (&s[..s.len() - 1], 1024u64.pow(exp))
+        }
+        _ => (s, 1),
+    };
+
+    let value: f64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: {s}"))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(format!("size must be positive: {s}"));
+    }
+
+    Ok((value * mult as f64) as u64)
+}
+
+/// Token bucket that throttles a stream of writes to an average rate in bytes/sec
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        // Allow bursts of up to 100ms worth of data
+        let rate = rate as f64;
+        let capacity = rate / 10.0;
+
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take tokens for `len` bytes, sleeping until the bucket has paid them back
+    pub fn take(&mut self, len: usize) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last = now;
+
+        self.tokens -= len as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+}
+
+/// The wire format version both peers use once the server saw the `peer`'s first packet
+pub fn negotiate(peer: u8) -> u8 {
+    peer.min(PROTOCOL_VERSION)
+}
+
+/// Send a packet in the wire format `version`
+pub fn send_packet<W: Write>(
+    sock: &mut W,
     action: TeleportAction,
+    version: u8,
     enc: &Option<TeleportEnc>,
     data: Vec<u8>,
 ) -> Result<(), TeleportError> {
     let mut header = TeleportHeader::new(action);
+    header.version = version;
 
     // If encryption is enabled
     if let Some(ctx) = enc {
-        // Use random IV
-        let mut rng = StdRng::from_entropy();
-        let mut iv: [u8; 12] = [0; 12];
-        rng.fill(&mut iv);
+        // Use the next counter-based IV
+        let iv = ctx.next_nonce()?;
 
         header.action |= TeleportAction::Encrypted as u8;
 
-        // Encrypt the data array
-        header.data = ctx.encrypt(&iv, &data)?;
-
         // Set the IV in the header
         header.iv = Some(iv);
+
+        // Encrypt the data array, authenticating the header alongside it
+        let aad = header.aad(data.len() + crypto::TAG_LEN)?;
+        header.data = ctx.encrypt(&iv, &aad, &data)?;
     } else {
         header.data = data;
     }
 
-    // Serialize the message
-    let message = header.serialize()?;
+    // Serialize the message straight into a buffered socket writer
+    let mut w = BufWriter::new(sock);
+    header.serialize_into(&mut w)?;
 
-    // Send the packet
-    sock.write_all(&message)?;
-    sock.flush()?;
-    println!("[send] package: {:?}", message);
+    // Send the packet, looping over short writes until it is all out
+    w.flush()?;
+    debug!("[send] package: {:?}", header);
     Ok(())
 }
 
-pub fn recv_packet(
-    sock: &mut TcpStream,
+/// Receive a packet in any wire format version up to `version`, the negotiated one
+pub fn recv_packet<R: Read>(
+    sock: &mut R,
+    version: u8,
     dec: &Option<TeleportEnc>,
 ) -> Result<TeleportHeader, TeleportError> {
-    let mut initbuf: [u8; 13] = [0; 13];
-    loop {
-        let len = sock.peek(&mut initbuf)?;
-        if len == 13 {
-            break;
-        }
-    }
-
+    // Read the fixed header first to learn the packet length
+    let mut initbuf: [u8; HEADER_LEN] = [0; HEADER_LEN];
+    sock.read_exact(&mut initbuf)?;
 
     let mut init: &[u8] = &initbuf;
     let protocol = init.read_u64::<LittleEndian>()?;
@@ -120,34 +328,208 @@ pub fn recv_packet(
         return Err(TeleportError::InvalidProtocol);
     }
 
+    let packet_version = init.read_u8()?;
+    if !(MIN_PROTOCOL_VERSION..=version).contains(&packet_version) {
+        return Err(TeleportError::UnsupportedProtocol(packet_version));
+    }
+
     let packet_len = init.read_u32::<LittleEndian>()?;
     let action = init.read_u8()?;
 
-    println!("protocol: {:X}", protocol);
-    println!("package_len: {}", packet_len);
-    println!("action: {}", action);
+    debug!("protocol: {:X} v{}", protocol, packet_version);
+    debug!("package_len: {}", packet_len);
+    debug!("action: {}", action);
 
-    // Include IV size in length
-    let mut total_len = 13 + packet_len as usize;
+    // Include IV size in length, a length that does not fit in memory on this target is bogus
     let encrypted = action & TeleportAction::Encrypted as u8 == TeleportAction::Encrypted as u8;
-    if encrypted {
-        total_len += 12;
-    }
-
-    let mut buf = Vec::<u8>::new();
-    buf.resize(total_len, 0);
+    let iv_len = if encrypted { 12 } else { 0 };
+    let total_len = usize::try_from(packet_len)
+        .ok()
+        .and_then(|l| l.checked_add(HEADER_LEN + iv_len))
+        .ok_or(TeleportError::InvalidProtocol)?;
 
-    sock.read_exact(&mut buf)?;
+    // Then collect exactly the rest of the declared packet, across partial reads
+    let mut buf = vec![0; total_len];
+    buf[..HEADER_LEN].copy_from_slice(&initbuf);
+    sock.read_exact(&mut buf[HEADER_LEN..])?;
 
     let mut out = TeleportHeader::new(TeleportAction::Init);
     out.deserialize(buf)?;
 
+    // Once a session key is agreed, a packet without encryption cannot be authenticated
     if encrypted {
         out.action ^= TeleportAction::Encrypted as u8;
         if let Some(ctx) = dec {
-            out.data = ctx.decrypt(&out.iv.expect("Fatal decrypt error"), &out.data)?;
+            let aad = out.aad(out.data.len())?;
+            out.data = ctx.decrypt(&out.iv.expect("Fatal decrypt error"), &aad, &out.data)?;
         }
+    } else if dec.is_some() {
+        return Err(TeleportError::DecryptionFailure);
     }
 
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1.5g"), Ok(1536 * 1024 * 1024));
+        assert!(parse_size("fast").is_err());
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("-5M").is_err());
+    }
+
+    #[test]
+    fn test_format_updates() {
+        let line = format_updates(512.0, 2048.0, 1024.0, Some(Duration::from_secs(2)));
+        assert_eq!(
+            line,
+            " =>  512.000B of    2.000K ( 25.00%) 1024.000B/s ETA 00:02"
+        );
+        assert!(format_updates(0.0, 0.0, 0.0, None).ends_with("(100.00%)    0.000B/s ETA --:--"));
+        assert_eq!(format_eta(Duration::from_millis(3_724_200)), "1:02:05");
+    }
+
+    #[test]
+    fn test_mbps_sub_second() {
+        // 1MiB in half a second is 16Mbps, not a divide by zero
+        let speed = mbps(1024 * 1024, Duration::from_millis(500));
+        assert!((speed - 16.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_mbps_fast_transfer() {
+        let speed = mbps(10 * 1024 * 1024, Duration::from_millis(100));
+        assert!(speed.is_finite());
+        assert!((speed - 800.0).abs() < 1e-9);
+
+        assert_eq!(mbps(10 * 1024 * 1024, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_token_bucket_rate() {
+        let rate = 1024 * 1024;
+        let mut bucket = TokenBucket::new(rate);
+        let start = Instant::now();
+
+        // 400K at 1M/s, less the 100ms burst allowance, takes at least ~290ms
+        for _ in 0..40 {
+            bucket.take(10 * 1024);
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(elapsed >= 0.28, "elapsed: {elapsed}");
+        assert!(elapsed < 1.0, "elapsed: {elapsed}");
+    }
+
+    /// Reader that hands out a single byte per call, like a badly fragmented link
+    struct TrickleReader {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos >= self.data.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    /// Writer that accepts at most three bytes per call
+    struct ShortWriter {
+        data: Vec<u8>,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_packet_partial_writes() {
+        let data = vec![9; 5000];
+        let mut writer = ShortWriter { data: Vec::new() };
+        send_packet(
+            &mut writer,
+            TeleportAction::Data,
+            PROTOCOL_VERSION,
+            &None,
+            data.clone(),
+        )
+        .expect("Test should never fail");
+
+        let mut header = TeleportHeader::new(TeleportAction::Data);
+        header.data = data;
+        assert_eq!(
+            writer.data,
+            header.serialize().expect("Test should never fail")
+        );
+    }
+
+    #[test]
+    fn test_recv_packet_partial_reads() {
+        let mut header = TeleportHeader::new(TeleportAction::Data);
+        header.data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let data = header.serialize().expect("Test should never fail");
+
+        let mut reader = TrickleReader { data, pos: 0 };
+        let packet =
+            recv_packet(&mut reader, PROTOCOL_VERSION, &None).expect("Test should never fail");
+
+        assert_eq!(packet.action, TeleportAction::Data as u8);
+        assert_eq!(packet.data, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_recv_packet_closed() {
+        let mut header = TeleportHeader::new(TeleportAction::Data);
+        header.data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut data = header.serialize().expect("Test should never fail");
+        data.truncate(16);
+
+        // A peer that hangs up mid-packet is an error, not a busy loop
+        let mut reader = TrickleReader { data, pos: 0 };
+        assert!(matches!(
+            recv_packet(&mut reader, PROTOCOL_VERSION, &None),
+            Err(TeleportError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_recv_packet_negotiated() {
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1), PROTOCOL_VERSION);
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION), MIN_PROTOCOL_VERSION);
+
+        // Packets newer than the negotiated version are refused, older supported ones are not
+        let mut header = TeleportHeader::new(TeleportAction::Data);
+        header.version = PROTOCOL_VERSION + 1;
+        let data = header.serialize().expect("Test should never fail");
+        let mut reader = TrickleReader {
+            data: data.clone(),
+            pos: 0,
+        };
+        assert!(matches!(
+            recv_packet(&mut reader, PROTOCOL_VERSION, &None),
+            Err(TeleportError::UnsupportedProtocol(v)) if v == PROTOCOL_VERSION + 1
+        ));
+        let mut reader = TrickleReader { data, pos: 0 };
+        let packet = recv_packet(&mut reader, u8::MAX, &None).expect("Test should never fail");
+        assert_eq!(packet.version, PROTOCOL_VERSION + 1);
+    }
+}
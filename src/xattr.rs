@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Name and value of one extended attribute
+pub type Xattr = (Vec<u8>, Vec<u8>);
+
+/// Largest total size of the extended attributes sent with a file, as serialized in TeleportInit
+pub const MAX_XATTR_SIZE: usize = 64 * 1024;
+
+/// Attributes a server never applies, file capabilities grant privileges like setuid does
+const REFUSED: &[&[u8]] = &[b"security.capability"];
+
+/// Bytes the attributes take in a TeleportInit
+pub fn serialized_size(xattrs: &[Xattr]) -> usize {
+    2 + xattrs
+        .iter()
+        .map(|(name, value)| 2 + name.len() + 2 + value.len())
+        .sum::<usize>()
+}
+
+/// True if the server must not apply this attribute
+pub fn refused(name: &[u8]) -> bool {
+    REFUSED.contains(&name)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    /// Run an xattr call twice, first asking for the size of the buffer it needs
+    fn sized<F: Fn(*mut u8, usize) -> isize>(call: F) -> io::Result<Vec<u8>> {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0; len as usize];
+        let len = call(buf.as_mut_ptr(), buf.len());
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+
+        Ok(buf)
+    }
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    // The macOS calls take extra position and options arguments, always 0 here
+    #[cfg(target_os = "linux")]
+    unsafe fn flistxattr(fd: libc::c_int, buf: *mut u8, size: usize) -> isize {
+        libc::flistxattr(fd, buf.cast(), size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn flistxattr(fd: libc::c_int, buf: *mut u8, size: usize) -> isize {
+        libc::flistxattr(fd, buf.cast(), size, 0)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn fgetxattr(
+        fd: libc::c_int,
+        name: *const libc::c_char,
+        buf: *mut u8,
+        size: usize,
+    ) -> isize {
+        libc::fgetxattr(fd, name, buf.cast(), size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn fgetxattr(
+        fd: libc::c_int,
+        name: *const libc::c_char,
+        buf: *mut u8,
+        size: usize,
+    ) -> isize {
+        libc::fgetxattr(fd, name, buf.cast(), size, 0, 0)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn setxattr(
+        path: *const libc::c_char,
+        name: *const libc::c_char,
+        value: *const u8,
+        size: usize,
+    ) -> libc::c_int {
+        libc::setxattr(path, name, value.cast(), size, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn setxattr(
+        path: *const libc::c_char,
+        name: *const libc::c_char,
+        value: *const u8,
+        size: usize,
+    ) -> libc::c_int {
+        libc::setxattr(path, name, value.cast(), size, 0, 0)
+    }
+
+    pub fn list(file: &std::fs::File) -> io::Result<Vec<u8>> {
+        let fd = file.as_raw_fd();
+        // SAFETY: the buffer pointer is valid for the given size, or null with a size of 0
+        sized(|buf, size| unsafe { flistxattr(fd, buf, size) })
+    }
+
+    pub fn get(file: &std::fs::File, name: &[u8]) -> io::Result<Vec<u8>> {
+        let fd = file.as_raw_fd();
+        let name = c_string(name)?;
+        // SAFETY: name is NUL-terminated and the buffer pointer is valid for the given size
+        sized(|buf, size| unsafe { fgetxattr(fd, name.as_ptr(), buf, size) })
+    }
+
+    pub fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name)?;
+        // SAFETY: path and name are NUL-terminated and value is valid for its length
+        match unsafe { setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr(), value.len()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Read all extended attributes of a file
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn read(file: &File) -> io::Result<Vec<Xattr>> {
+    let mut out = Vec::new();
+    for name in sys::list(file)?.split(|b| *b == 0) {
+        if name.is_empty() {
+            continue;
+        }
+        out.push((name.to_vec(), sys::get(file, name)?));
+    }
+
+    Ok(out)
+}
+
+/// Read all extended attributes of a file, there are none on this platform
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn read(_file: &File) -> io::Result<Vec<Xattr>> {
+    Ok(Vec::new())
+}
+
+/// Set one extended attribute on a file
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn write(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    sys::set(path, name, value)
+}
+
+/// Set one extended attribute on a file, silently dropped on this platform
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn write(_path: &Path, _name: &[u8], _value: &[u8]) -> io::Result<()> {
+    Ok(())
+}
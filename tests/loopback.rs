@@ -0,0 +1,261 @@
+use clap::Parser;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use teleporter::{listen, ping, send};
+use teleporter::{ListenOpt, PingOpt, SendOpt};
+
+/// Start `listen::run` on a free loopback port and wait until it answers pings
+fn start_server(out: &Path, extra: &[&str]) -> u16 {
+    // The port is released again before the server binds it, SO_REUSEADDR makes that safe
+    let port = TcpListener::bind("127.0.0.1:0")
+        .expect("Test should never fail")
+        .local_addr()
+        .expect("Test should never fail")
+        .port();
+
+    let mut args = vec![
+        "listen".to_string(),
+        "-b".to_string(),
+        "127.0.0.1".to_string(),
+        "-p".to_string(),
+        port.to_string(),
+        "-o".to_string(),
+        out.to_string_lossy().to_string(),
+    ];
+    args.extend(extra.iter().map(|a| a.to_string()));
+    let opt = ListenOpt::parse_from(args);
+
+    // The server runs until the test process exits
+    thread::spawn(move || listen::run(opt));
+
+    let start = Instant::now();
+    let ping = PingOpt::parse_from(["ping", "-d", "127.0.0.1", "-p", &port.to_string()]);
+    while ping::run(ping.clone()).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "Server did not start"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    port
+}
+
+/// Send `src` with the real client and return the path the server wrote it to
+fn send_file(src: &Path, out: &Path, port: u16, extra: &[&str]) -> PathBuf {
+    let mut args = vec![
+        "send".to_string(),
+        "-i".to_string(),
+        src.to_string_lossy().to_string(),
+        "-d".to_string(),
+        "127.0.0.1".to_string(),
+        "-p".to_string(),
+        port.to_string(),
+        "-u".to_string(),
+        "test".to_string(),
+    ];
+    args.extend(extra.iter().map(|a| a.to_string()));
+    send::run(SendOpt::parse_from(args)).expect("Test should never fail");
+
+    out.join(src.file_name().expect("Test should never fail"))
+}
+
+fn loopback(name: &str, server: &[&str], client: &[&str]) {
+    let dir = std::env::temp_dir().join(format!("teleporter-{name}-{}", std::process::id()));
+    let out = dir.join("out");
+    fs::create_dir_all(&out).expect("Test should never fail");
+    let src = dir.join(format!("{name}.bin"));
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&src, &data).expect("Test should never fail");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o640))
+            .expect("Test should never fail");
+    }
+
+    let port = start_server(&out, server);
+    let dest = send_file(&src, &out, port, client);
+
+    assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |p: &Path| {
+            fs::metadata(p)
+                .expect("Test should never fail")
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(mode(&dest), mode(&src));
+    }
+
+    fs::remove_dir_all(&dir).expect("Test should never fail");
+}
+
+#[test]
+fn test_loopback_encrypted() {
+    loopback("encrypted", &[], &[]);
+}
+
+#[test]
+fn test_loopback_plaintext() {
+    loopback("plaintext", &["--allow-plaintext"], &["--plaintext"]);
+}
+
+#[test]
+fn test_loopback_empty() {
+    let dir = std::env::temp_dir().join(format!("teleporter-empty-{}", std::process::id()));
+    let out = dir.join("out");
+    fs::create_dir_all(&out).expect("Test should never fail");
+    let src = dir.join("empty.bin");
+    fs::write(&src, b"").expect("Test should never fail");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o640))
+            .expect("Test should never fail");
+    }
+
+    let port = start_server(&out, &[]);
+
+    // A new empty file, then one replacing a file that has data
+    let dest = send_file(&src, &out, port, &[]);
+    assert_eq!(fs::read(&dest).expect("Test should never fail"), b"");
+    fs::write(&dest, b"not empty").expect("Test should never fail");
+    let dest = send_file(&src, &out, port, &["-o"]);
+    assert_eq!(fs::read(&dest).expect("Test should never fail"), b"");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&dest)
+            .expect("Test should never fail")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    fs::remove_dir_all(&dir).expect("Test should never fail");
+}
+
+#[test]
+fn test_loopback_rolling_delta() {
+    let dir = std::env::temp_dir().join(format!("teleporter-rolling-{}", std::process::id()));
+    let out = dir.join("out");
+    fs::create_dir_all(&out).expect("Test should never fail");
+
+    // The server has the original, the client the same data with bytes inserted at the start
+    let old: Vec<u8> = (0..2_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(out.join("edited.bin"), &old).expect("Test should never fail");
+    let src = dir.join("edited.bin");
+    let mut data = b"a new first line\n".to_vec();
+    data.extend(&old);
+    fs::write(&src, &data).expect("Test should never fail");
+
+    let port = start_server(&out, &[]);
+    let dest = send_file(&src, &out, port, &["-o"]);
+    assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+    // The same with chunks the client would not pick itself
+    fs::write(&dest, &old).expect("Test should never fail");
+    let port = start_server(&out, &["--delta-chunk-size", "3000"]);
+    let dest = send_file(&src, &out, port, &["-o"]);
+    assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+    fs::remove_dir_all(&dir).expect("Test should never fail");
+}
+
+#[test]
+fn test_loopback_checksum_manifest() {
+    let dir = std::env::temp_dir().join(format!("teleporter-manifest-{}", std::process::id()));
+    let out = dir.join("out");
+    fs::create_dir_all(&out).expect("Test should never fail");
+    let src = dir.join("listed.bin");
+    let data = b"check me later".to_vec();
+    fs::write(&src, &data).expect("Test should never fail");
+
+    // The client lists each file it sent under the name the server got
+    let port = start_server(&out, &[]);
+    let manifest = dir.join("manifest.txt");
+    let path = manifest.to_string_lossy().to_string();
+    send_file(&src, &out, port, &["--checksum-manifest", &path]);
+    let hash = xxhash_rust::xxh3::xxh3_64(&data);
+    assert_eq!(
+        fs::read_to_string(&manifest).expect("Test should never fail"),
+        format!("{hash:016x}  listed.bin\n")
+    );
+    send_file(
+        &src,
+        &out,
+        port,
+        &["-o", "--strong-hash", "--checksum-manifest", &path],
+    );
+    assert_eq!(
+        fs::read_to_string(&manifest).expect("Test should never fail"),
+        format!("{}  listed.bin\n", blake3::hash(&data).to_hex())
+    );
+
+    // A server checking against it receives the file as usual
+    let port = start_server(&out, &["--verify-manifest", &path]);
+    let dest = send_file(&src, &out, port, &["-o"]);
+    assert_eq!(fs::read(&dest).expect("Test should never fail"), data);
+
+    // A manifest it cannot parse is refused at startup
+    fs::write(&manifest, "not a manifest").expect("Test should never fail");
+    assert!(ListenOpt::try_parse_from(["listen", "--verify-manifest", &path]).is_err());
+
+    fs::remove_dir_all(&dir).expect("Test should never fail");
+}
+
+#[test]
+#[ignore = "writes a 5 GiB file, run with --ignored"]
+fn test_loopback_over_4gib() {
+    const SIZE: u64 = 5 << 30;
+
+    let dir = std::env::temp_dir().join(format!("teleporter-4gib-{}", std::process::id()));
+    let out = dir.join("out");
+    fs::create_dir_all(&out).expect("Test should never fail");
+
+    // A sparse file with data on both sides of the 4 GiB mark and at the very end
+    let src = dir.join("big.bin");
+    let mut file = fs::File::create(&src).expect("Test should never fail");
+    file.set_len(SIZE).expect("Test should never fail");
+    for offset in [0, (4 << 30) - 3, SIZE - 8] {
+        file.seek(SeekFrom::Start(offset))
+            .expect("Test should never fail");
+        file.write_all(b"teleport").expect("Test should never fail");
+    }
+    drop(file);
+
+    // The server only moves the file into place once its hash matched
+    let port = start_server(&out, &["--allow-plaintext"]);
+    let dest = send_file(
+        &src,
+        &out,
+        port,
+        &["--plaintext", "--chunk-size", "4194304"],
+    );
+    let size = fs::metadata(&dest).expect("Test should never fail").len();
+    assert_eq!(size, SIZE);
+
+    let mut a = fs::File::open(&src).expect("Test should never fail");
+    let mut b = fs::File::open(&dest).expect("Test should never fail");
+    let (mut buf_a, mut buf_b) = (vec![0; 1 << 20], vec![0; 1 << 20]);
+    loop {
+        let len = a.read(&mut buf_a).expect("Test should never fail");
+        b.read_exact(&mut buf_b[..len])
+            .expect("Test should never fail");
+        assert_eq!(buf_a[..len], buf_b[..len]);
+        if len == 0 {
+            break;
+        }
+    }
+
+    fs::remove_dir_all(&dir).expect("Test should never fail");
+}